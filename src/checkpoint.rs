@@ -0,0 +1,140 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::{
+    collections::{HashMap, HashSet},
+    ffi::OsStr,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::CopyConfig;
+
+/// The `checkpoint` file: source directories whose whole subtree was copied without a failure, one per line.
+/// A later run skips them without listing them again. Directories are completed bottom-up as their last
+/// child finishes, so the walk order doesn't matter and an interrupted run loses at most the directories
+/// it was in the middle of.
+pub(crate) struct Checkpoint {
+    file: Option<PathBuf>,
+    completed: HashSet<PathBuf>,
+    /// Listed directories and how many of their children haven't finished yet
+    pending: Mutex<HashMap<PathBuf, usize>>,
+}
+
+impl Checkpoint {
+    /// A dry run skips what an earlier run completed, but records nothing itself
+    pub(crate) fn load(config: &CopyConfig) -> Result<Checkpoint> {
+        let mut checkpoint = Checkpoint {
+            file: None,
+            completed: HashSet::new(),
+            pending: Mutex::new(HashMap::new()),
+        };
+        let Some(file) = &config.checkpoint else {
+            return Ok(checkpoint);
+        };
+        match fs::read(file) {
+            Ok(content) => {
+                checkpoint.completed.extend(
+                    content
+                        .split(|b| *b == b'\n')
+                        .filter(|l| !l.is_empty())
+                        .map(|l| PathBuf::from(OsStr::from_bytes(l))),
+                );
+                info!("loaded {} completed directories from '{}'", checkpoint.completed.len(), file.display());
+            }
+            Err(e) if e.kind() == ErrorKind::NotFound => (),
+            Err(e) => return Err(e).with_context(|| format!("can't read checkpoint '{}'", file.display())),
+        }
+        if !config.dry_run {
+            checkpoint.file = Some(file.clone());
+        }
+        Ok(checkpoint)
+    }
+
+    pub(crate) fn is_completed(&self, path: &Path) -> bool {
+        self.completed.contains(path)
+    }
+
+    /// `dir` was listed and `children` of its entries will each be reported `done` or fail
+    pub(crate) fn listed(&self, dir: &Path, children: usize) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        let mut pending = self.pending.lock().unwrap();
+        if children > 0 {
+            pending.insert(dir.to_path_buf(), children);
+            return Ok(());
+        }
+        record(file, dir)?;
+        child_done(file, &mut pending, dir)
+    }
+
+    /// `path` needs nothing more from later runs, which completes its parent if it was the last child left
+    pub(crate) fn done(&self, path: &Path) -> Result<()> {
+        let Some(file) = &self.file else {
+            return Ok(());
+        };
+        child_done(file, &mut self.pending.lock().unwrap(), path)
+    }
+}
+
+fn child_done(file: &Path, pending: &mut HashMap<PathBuf, usize>, path: &Path) -> Result<()> {
+    let mut path = path;
+    while let Some(parent) = path.parent() {
+        let Some(left) = pending.get_mut(parent) else {
+            break;
+        };
+        *left -= 1;
+        if *left > 0 {
+            break;
+        }
+        pending.remove(parent);
+        record(file, parent)?;
+        path = parent;
+    }
+    Ok(())
+}
+
+/// Appended as soon as it's known, callers hold the pending lock so lines from workers stay apart
+fn record(file: &Path, dir: &Path) -> Result<()> {
+    let mut line = dir.as_os_str().as_bytes().to_vec();
+    line.push(b'\n');
+    OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(file)
+        .and_then(|mut f| f.write_all(&line))
+        .with_context(|| format!("can't write checkpoint '{}'", file.display()))
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Checkpoint;
+    use crate::CopyConfig;
+    use std::path::Path;
+
+    #[test]
+    fn it_completes_directories_bottom_up() {
+        let file = std::env::temp_dir().join(format!("apfs-copier-checkpoint-{}", std::process::id()));
+        let _ = std::fs::remove_file(&file);
+        let config = CopyConfig {
+            checkpoint: Some(file.clone()),
+            ..Default::default()
+        };
+        let checkpoint = Checkpoint::load(&config).unwrap();
+        checkpoint.listed(Path::new("/src"), 2).unwrap();
+        checkpoint.listed(Path::new("/src/a"), 2).unwrap();
+        checkpoint.listed(Path::new("/src/b"), 0).unwrap();
+        checkpoint.done(Path::new("/src/a/1.txt")).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "/src/b\n");
+        checkpoint.done(Path::new("/src/a/2.txt")).unwrap();
+        assert_eq!(std::fs::read_to_string(&file).unwrap(), "/src/b\n/src/a\n/src\n");
+
+        let resumed = Checkpoint::load(&config).unwrap();
+        std::fs::remove_file(&file).unwrap();
+        assert!(resumed.is_completed(Path::new("/src/a")));
+        assert!(!resumed.is_completed(Path::new("/src/c")));
+    }
+}
@@ -0,0 +1,435 @@
+use anyhow::{bail, Context, Result};
+use std::{
+    collections::{HashMap, HashSet},
+    env, iter,
+    path::{Component, Path, PathBuf},
+    sync::{
+        atomic::{AtomicBool, AtomicU64, Ordering},
+        Arc,
+    },
+    time::{Duration, SystemTime},
+};
+
+use crate::{names::sanitize_name, Mounter, SystemMounter};
+
+/// Everything a copy run needs to know, the binary fills it in from the command line
+#[derive(Clone, Debug)]
+pub struct CopyConfig {
+    /// Device holding the APFS container, passed to `apfs-fuse`
+    pub device: String,
+    /// Where `apfs-fuse` mounts the volume
+    pub mount_point: String,
+    /// Directory on the mounted volume to copy the contents of, or a single file to copy into `dest`
+    pub source: PathBuf,
+    /// More directories or files copied in the same run, every source then goes into `dest` under its own name
+    pub extra_sources: Vec<PathBuf>,
+    /// Directory on the ExFAT volume to copy into
+    pub dest: PathBuf,
+    /// Walk the source and report what would be copied without writing anything
+    pub dry_run: bool,
+    /// Stop once this many files were copied, or would be in a dry run, to try a volume out on a sample.
+    /// Skipped files don't count. With several `jobs`, the files other workers are copying by then are finished too.
+    pub max_files: Option<u64>,
+    /// Give up waiting for the mount to become readable after this long
+    pub mount_timeout: Duration,
+    /// How often to check whether the mount has become readable
+    pub mount_poll_interval: Duration,
+    /// Wait this long more once a new mount is readable. None by default, a FUSE mount that reads fine
+    /// at first but aborts right after may need a few seconds.
+    pub mount_settle: Duration,
+    /// Give up mounting the device after this many failed attempts
+    pub max_mount_attempts: u32,
+    /// Delay before the first mount retry, doubled after every further failure
+    pub mount_retry_delay: Duration,
+    /// Remember paths that caused connection aborts in this file so later runs skip them
+    pub state_file: Option<PathBuf>,
+    /// Skip a path for good once it caused this many connection aborts, counted across remounts and
+    /// runs sharing a `state_file`, until then it's retried on a fresh mount
+    pub max_path_aborts: u32,
+    /// Stop the run once the volume needs remounting after this many remounts, rather than grinding on a dying one
+    pub max_total_remounts: Option<u32>,
+    /// Detach the mount point with a lazy umount when it stays busy, so a lingering file handle can't block a remount
+    pub allow_lazy_umount: bool,
+    /// Record directories whose whole subtree was copied in this file so later runs skip them,
+    /// a checkpoint only holds for runs with the same source, filters and destination
+    pub checkpoint: Option<PathBuf>,
+    /// Append a JSON line with the source-relative path, size and modification time of every copied file to this file,
+    /// for auditing later or for `since_manifest`
+    pub manifest: Option<PathBuf>,
+    /// Put the SHA-256 of every copied file in the manifest too, hashed while copying
+    pub manifest_sha256: bool,
+    /// The `manifest` of an earlier run, files it lists with the size and modification time they still have
+    /// are taken for copied without looking at the destination
+    pub since_manifest: Option<PathBuf>,
+    /// Append a JSON line to this file for every file copied or skipped, every remount and every failure as they
+    /// happen, the layout is documented on `events::Event`
+    pub events_jsonl: Option<PathBuf>,
+    /// Delete whatever in a destination directory has no counterpart in its source directory,
+    /// or only report it in a dry run. With several sources only what's inside their own directories.
+    pub mirror: bool,
+    /// Files besides the run's own ones that `mirror` leaves alone in the destination, like the reports written
+    /// once the copy is done
+    pub mirror_keeps: Vec<PathBuf>,
+    /// Copy every file straight into `dest` instead of recreating the tree, a name taken by an earlier file
+    /// gets a numeric suffix. Can't be combined with `mirror`.
+    pub flatten: bool,
+    /// Sort the files into folders under `dest` by their extension, like `images` or `videos`, instead of
+    /// recreating the tree. Names taken in a folder get a numeric suffix like with `flatten`, which this implies.
+    pub route_by_extension: bool,
+    /// Record a directory whose destination is taken by a file as a failure and leave it out, instead of copying it
+    /// under a numbered name next to the file
+    pub skip_dirs_blocked_by_files: bool,
+    /// Lowercase extensions and the folder `route_by_extension` puts them in instead of the one in `DEFAULT_ROUTES`
+    pub extension_routes: HashMap<String, String>,
+    /// Create every directory of the copy before copying any files, which finds the directories that can't be
+    /// created early and lets the destination be browsed while the files come in
+    pub structure_first: bool,
+    /// Give copied files the modification time of their source
+    pub preserve_mtime: bool,
+    /// Give copied files the Unix permissions of their source, as far as the destination keeps them. Plain ExFAT
+    /// has no permissions at all, a FUSE mount of it only has what its `uid`, `gid` and `umask` options allow.
+    pub preserve_permissions: bool,
+    /// Give copied files the extended attributes of their source, like Finder tags and `com.apple.FinderInfo`, as
+    /// far as the destination takes them. Plain ExFAT has none, some FUSE mounts of it keep `user.` ones.
+    pub preserve_xattrs: bool,
+    /// Only copy files whose source-relative path matches one of these globs
+    pub include: Vec<String>,
+    /// Skip files and directories whose source-relative path matches one of these globs
+    pub exclude: Vec<String>,
+    /// Also exclude `APPLE_METADATA_GLOBS`, leave it off to copy the Finder and Spotlight files too
+    pub skip_apple_metadata: bool,
+    /// Skip every file and directory whose name starts with `.`, without going into the directories
+    pub skip_hidden: bool,
+    /// Also skip whatever the `.gitignore`-style patterns in this file match, relative to the source
+    pub exclude_from: Option<PathBuf>,
+    /// Skip files smaller than this many bytes
+    pub min_size: Option<u64>,
+    /// Skip files bigger than this many bytes
+    pub max_size: Option<u64>,
+    /// Skip files last modified before this, for copying only what changed since an earlier migration
+    pub newer_than: Option<SystemTime>,
+    /// Only copy files this many levels below the source, the files right in the source are level 1
+    pub max_depth: Option<usize>,
+    /// What to do with a file whose destination already exists
+    pub overwrite: Overwrite,
+    /// Unicode normalization of destination names
+    pub normalize: Normalize,
+    /// Which filesystem's rules destination names are rewritten for
+    pub target_fs: TargetFs,
+    /// The characters replaced in names instead of those `target_fs` rejects, control characters
+    /// and its other rules still go by `target_fs`
+    pub forbidden_chars: Option<String>,
+    /// Put in place of every character the destination can't store
+    pub replacement_char: char,
+    /// Which of the files of one directory that end up with the same destination name keeps it
+    pub on_collision: OnCollision,
+    /// Put `_` in front of names Windows reserves for devices, like `CON` or `com1.txt`, which ExFAT stores
+    /// but Windows can't open
+    pub rename_reserved_names: bool,
+    /// What to do with symlinks, which ExFAT can't store
+    pub symlinks: SymlinkMode,
+    /// Stop on a file or directory that can't be read for its permissions, instead of recording it and going on
+    pub fail_on_permission_denied: bool,
+    /// Copy a file with several hard links once and list the other links in the report,
+    /// ExFAT can't link them so they would each take up space again
+    pub dedup_hardlinks: bool,
+    /// How every copy is checked against its source, a mismatch is copied once more before it's reported
+    pub verify: Verify,
+    /// Order the entries of every directory are walked in
+    pub order: WalkOrder,
+    /// Whether subdirectories are gone into before or after their siblings
+    pub traversal: Traversal,
+    /// Copy files on this many worker threads, directories are still created in walk order
+    pub jobs: usize,
+    /// How many directories below the source the time spent copying is added up at for
+    /// `CopyReport::directory_throughput`, 0 adds it all up for the source itself
+    pub throughput_depth: usize,
+    /// Count the source first, then show a progress bar with an ETA while copying,
+    /// and warn up front if the destination looks too small
+    pub progress: bool,
+    /// Skip files bigger than the free space left on the destination instead of stopping the copy
+    pub skip_oversize: bool,
+    /// Copy a file again this many times after an I/O error before it's given up on, flaky FUSE reads often recover
+    pub io_retries: u32,
+    /// Delay before the first retry after an I/O error, doubled after every further one
+    pub io_retry_delay: Duration,
+    /// Chunk size big files are streamed in, which is also how often the progress bar and an interrupt get a look in
+    pub buffer_size: usize,
+    /// Copy at most this many bytes a second on average, counted across all `jobs`
+    pub rate_limit: Option<u64>,
+    /// Log a status line this often while copying, for runs nobody watches a progress bar of
+    pub progress_interval: Option<Duration>,
+    /// Refuse to copy to a destination that isn't on ExFAT or FAT instead of only warning about it
+    pub strict_fs: bool,
+    /// Unmount the volume when the copy stops on `interrupt`
+    pub umount_on_interrupt: bool,
+    /// Unmount the volume once the copy has gone through, whoever mounted it.
+    /// A copy that stops early leaves it mounted for the next run.
+    pub umount_when_done: bool,
+    /// Once the copy has gone through, remount and try every path that failed once more,
+    /// aborts often go away on a fresh mount with nothing else holding files open
+    pub retry_failures_at_end: bool,
+    /// Runs the mount and umount commands
+    pub mounter: Arc<dyn Mounter>,
+    /// Set by `interrupt`, shared by every clone of the config
+    pub interrupted: Arc<AtomicBool>,
+    /// Remounts made so far, checked against `max_total_remounts`. Every run starts a count of its own
+    /// that all the clones it makes share, whatever an earlier run left here.
+    pub remounts: Arc<AtomicU64>,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum SymlinkMode {
+    /// Leave every symlink out and list it in the report
+    Skip,
+    /// Copy what file symlinks point to and walk into directory symlinks,
+    /// each directory is still walked only once so links back up can't loop
+    Follow,
+    /// Copy what file symlinks point to, leave directory symlinks out and list them in the report
+    Dereference,
+}
+
+/// The filesystem names are rewritten for. The Windows ones all reject `" * : < > ? \ |` and control characters,
+/// drop trailing dots and spaces, keep at most 255 UTF-16 code units and ignore case.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum TargetFs {
+    /// Rejects DEL (0x7F) along with the other control characters
+    Exfat,
+    Ntfs,
+    /// Long FAT names, which take DEL
+    Fat32,
+    /// A Unix filesystem that takes any name the source has, so names are left alone
+    None,
+}
+
+/// What happens to files of one directory whose names end up the same at the destination, after sanitizing,
+/// truncating or folding case. Files that only meet there with `flatten` or `route_by_extension` are always suffixed.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum OnCollision {
+    /// The first one listed keeps the name, the others get a numeric suffix
+    Suffix,
+    /// The most recently modified one keeps the name, the others get a numeric suffix
+    KeepNewest,
+    /// Only the most recently modified one is copied, the others are skipped
+    NewestOnly,
+}
+
+/// macOS keeps names decomposed (NFD), most Windows and ExFAT tools expect them composed (NFC)
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Normalize {
+    None,
+    Nfc,
+    Nfd,
+}
+
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Verify {
+    None,
+    /// Compare the length of the copy with the source
+    Size,
+    /// Hash the source while copying it and compare with a hash of the copy read back
+    Sha256,
+}
+
+/// Sizes and modification times are compared the way `preserve_mtime` leaves them, within ExFAT's two seconds
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Overwrite {
+    /// Take every destination that exists for a finished copy
+    Skip,
+    /// Copy every file again
+    Always,
+    /// Copy a file again when the source was modified after its destination
+    IfNewer,
+    /// Copy a file again when its destination differs from the source in size or modification time
+    IfDifferent,
+}
+
+/// Entries are sorted by their raw name bytes, so runs over the same tree go the same way
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum WalkOrder {
+    Name,
+    NameDesc,
+}
+
+/// Only directories wait to be walked either way. Depth-first keeps fewer of them waiting on wide trees,
+/// breadth-first copies the top levels before anything deep.
+#[derive(Clone, Copy, Debug, PartialEq, Eq, clap::ValueEnum)]
+pub enum Traversal {
+    DepthFirst,
+    BreadthFirst,
+}
+
+impl CopyConfig {
+    pub fn new(device: impl Into<String>, mount_point: impl Into<String>, source: PathBuf, dest: PathBuf) -> CopyConfig {
+        CopyConfig {
+            device: device.into(),
+            mount_point: mount_point.into(),
+            source,
+            dest,
+            ..Default::default()
+        }
+    }
+
+    /// Asks a running copy to stop, say from a signal handler. The file being copied is finished first.
+    pub fn interrupt(&self) {
+        self.interrupted.store(true, Ordering::Relaxed);
+    }
+
+    pub fn is_interrupted(&self) -> bool {
+        self.interrupted.load(Ordering::Relaxed)
+    }
+
+    /// The config a run goes by: the sources and `dest` made absolute, so every path walked under a source
+    /// starts with it, and no remounts made yet
+    pub(crate) fn for_run(&self) -> Result<CopyConfig> {
+        Ok(CopyConfig {
+            source: absolute_path(&self.source)?,
+            extra_sources: self.extra_sources.iter().map(|source| absolute_path(source)).collect::<Result<_>>()?,
+            dest: absolute_path(&self.dest)?,
+            remounts: Arc::new(AtomicU64::new(0)),
+            ..self.clone()
+        })
+    }
+
+    /// A config for every source on its own, with the directory it's copied into as `dest`
+    pub(crate) fn roots(&self) -> Result<Vec<CopyConfig>> {
+        if self.extra_sources.is_empty() {
+            return Ok(vec![self.clone()]);
+        }
+        let mut names = HashSet::new();
+        let mut roots = vec![];
+        for source in iter::once(&self.source).chain(&self.extra_sources) {
+            let Some(name) = source.file_name() else {
+                bail!("can't name the copy of '{}' in '{}'", source.display(), self.dest.display());
+            };
+            if !names.insert(sanitize_name(self, name)) {
+                bail!("'{}' would be copied over another source with the same name", source.display());
+            }
+            roots.push(CopyConfig {
+                source: source.clone(),
+                extra_sources: vec![],
+                // a file already goes into `dest` under its own name
+                dest: if source.is_file() { self.dest.clone() } else { self.dest.join(name) },
+                ..self.clone()
+            });
+        }
+        Ok(roots)
+    }
+}
+
+/// `path` against the working directory, with `.` and `..` taken out by name and no trailing separator.
+/// Nothing is looked up on disk, the source may not be mounted yet. An empty path stays empty.
+pub(crate) fn absolute_path(path: &Path) -> Result<PathBuf> {
+    if path.as_os_str().is_empty() {
+        return Ok(PathBuf::new());
+    }
+    let mut absolute = if path.is_absolute() {
+        PathBuf::new()
+    } else {
+        env::current_dir().context("can't get the working directory")?
+    };
+    for component in path.components() {
+        match component {
+            Component::CurDir => (),
+            Component::ParentDir => {
+                absolute.pop();
+            }
+            other => absolute.push(other),
+        }
+    }
+    Ok(absolute)
+}
+
+impl Default for CopyConfig {
+    fn default() -> CopyConfig {
+        CopyConfig {
+            device: String::new(),
+            mount_point: String::new(),
+            source: PathBuf::new(),
+            extra_sources: vec![],
+            dest: PathBuf::new(),
+            dry_run: false,
+            mount_timeout: Duration::from_secs(30),
+            mount_poll_interval: Duration::from_millis(500),
+            mount_settle: Duration::ZERO,
+            max_mount_attempts: 5,
+            mount_retry_delay: Duration::from_secs(1),
+            state_file: None,
+            max_path_aborts: 3,
+            max_total_remounts: None,
+            allow_lazy_umount: false,
+            checkpoint: None,
+            manifest: None,
+            manifest_sha256: false,
+            since_manifest: None,
+            events_jsonl: None,
+            mirror: false,
+            mirror_keeps: vec![],
+            flatten: false,
+            route_by_extension: false,
+            skip_dirs_blocked_by_files: false,
+            extension_routes: HashMap::new(),
+            structure_first: false,
+            exclude_from: None,
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            max_depth: None,
+            preserve_mtime: true,
+            preserve_permissions: false,
+            preserve_xattrs: false,
+            include: vec![],
+            exclude: vec![],
+            skip_apple_metadata: false,
+            skip_hidden: false,
+            overwrite: Overwrite::Skip,
+            jobs: 1,
+            throughput_depth: 1,
+            normalize: Normalize::None,
+            target_fs: TargetFs::Exfat,
+            forbidden_chars: None,
+            replacement_char: '_',
+            on_collision: OnCollision::Suffix,
+            rename_reserved_names: false,
+            symlinks: SymlinkMode::Dereference,
+            fail_on_permission_denied: false,
+            dedup_hardlinks: false,
+            verify: Verify::None,
+            order: WalkOrder::Name,
+            traversal: Traversal::DepthFirst,
+            progress: false,
+            skip_oversize: false,
+            max_files: None,
+            io_retries: 2,
+            io_retry_delay: Duration::from_millis(500),
+            buffer_size: 1024 * 1024,
+            rate_limit: None,
+            progress_interval: None,
+            strict_fs: false,
+            umount_on_interrupt: false,
+            umount_when_done: false,
+            retry_failures_at_end: false,
+            mounter: Arc::new(SystemMounter::default()),
+            interrupted: Arc::new(AtomicBool::new(false)),
+            remounts: Arc::new(AtomicU64::new(0)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn it_makes_paths_absolute_by_name() {
+        let cwd = std::env::current_dir().unwrap();
+        let absolute = |path: &str| super::absolute_path(Path::new(path)).unwrap();
+        assert_eq!(absolute("/mnt/apfs/Users/"), PathBuf::from("/mnt/apfs/Users"));
+        assert_eq!(absolute("/mnt/apfs/./Users/me/../"), PathBuf::from("/mnt/apfs/Users"));
+        assert_eq!(absolute("src/"), cwd.join("src"));
+        assert_eq!(absolute("./a/../b"), cwd.join("b"));
+        assert_eq!(absolute("../x"), cwd.parent().unwrap().join("x"));
+        assert_eq!(absolute("/.."), PathBuf::from("/"));
+        assert_eq!(absolute(""), PathBuf::new());
+    }
+}
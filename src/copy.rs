@@ -0,0 +1,1811 @@
+use anyhow::{anyhow, bail, Context, Result};
+use filetime::FileTime;
+use log::{debug, error, info, warn};
+use sha2::{Digest, Sha256};
+use std::{
+    cell::Cell,
+    collections::{hash_map::Entry, HashMap, HashSet, VecDeque},
+    fmt, fs,
+    io::{self, ErrorKind, Read, Write},
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{mpsc, Mutex},
+    thread,
+    time::Instant,
+};
+
+use crate::{
+    checkpoint::Checkpoint,
+    events::{self, Event, Skip},
+    failures::{is_failure, remember_failure},
+    filters::Filters,
+    manifest::{Manifest, SinceManifest},
+    mirror::remove_stale,
+    mount::{remount_since, RemountsExhausted, MOUNT_GENERATION},
+    names::{
+        claim_destination, claim_directory_destination, is_file_in_the_way, newer_collision, newest_first, part_path,
+        replace_forbidden_characters, sanitize_name, PART_SUFFIX,
+    },
+    progress::Progress,
+    routes::routed,
+    throttle::throttle,
+    CopyConfig, CopyReport, OnCollision, Overwrite, SymlinkMode, Throughput, Traversal, Verify, WalkOrder,
+};
+
+pub(crate) fn walk_and_copy(
+    config: &CopyConfig,
+    manifest: Option<&Manifest>,
+    since: Option<&SinceManifest>,
+    progress: Option<&Progress>,
+) -> Result<CopyReport> {
+    // a dry run creates nothing the copy pass would then find
+    let mut created = CreatedDirs::default();
+    if !config.structure_first || config.dry_run {
+        return copy_files(config, &mut created, manifest, since, progress);
+    }
+    let mut report = create_structure(config, &mut created)?;
+    if !report.interrupted && !report.remounts_exhausted {
+        report.merge(copy_files(config, &mut created, manifest, since, progress)?);
+    }
+    Ok(report)
+}
+
+/// How many destination directories `CreatedDirs` remembers before it starts over, a few MB of paths at most
+const MAX_CREATED_DIRS: usize = 10_000;
+
+/// Destination directories created or found already during this copy, so walking into one again, like every
+/// directory of a flattened copy or the copy pass after `structure_first`, doesn't stat and create it again.
+/// Those are metadata round trips each on a slow FUSE destination.
+#[derive(Default)]
+struct CreatedDirs {
+    dirs: HashSet<PathBuf>,
+    /// `create_dir_all` calls left out, logged at the end
+    skipped: u64,
+}
+
+impl CreatedDirs {
+    fn contains(&mut self, dest_dir: &Path) -> bool {
+        let known = self.dirs.contains(dest_dir);
+        self.skipped += u64::from(known);
+        known
+    }
+
+    fn insert(&mut self, dest_dir: &Path) {
+        if self.dirs.len() >= MAX_CREATED_DIRS {
+            self.dirs.clear();
+        }
+        self.dirs.insert(dest_dir.to_path_buf());
+    }
+}
+
+impl Drop for CreatedDirs {
+    fn drop(&mut self) {
+        if self.skipped > 0 {
+            debug!("left out {} calls to create destination directories that were there already", self.skipped);
+        }
+    }
+}
+
+/// The `structure_first` pass, which walks the source the way the copy will and creates every directory it goes
+/// into without copying any files. What else it comes across is left for the copy pass to report.
+fn create_structure(config: &CopyConfig, created: &mut CreatedDirs) -> Result<CopyReport> {
+    // completed directories are still skipped, but nothing is done before its files are copied
+    let checkpoint = Checkpoint::load(&CopyConfig {
+        dry_run: true,
+        ..config.clone()
+    })?;
+    // the copy pass comes across the same skips and failures and has the events for them
+    let config = &CopyConfig {
+        mirror: false,
+        events_jsonl: None,
+        ..config.clone()
+    };
+    let walked = walk_tree(
+        config,
+        &checkpoint,
+        None,
+        &mut |report, dir, dest_dir| create_dest_dir(config, created, report, dir, dest_dir).map(|_| true),
+        &mut |_, _, _| Ok(()),
+    )?;
+    info!("created {} directories, copying the files", walked.directories_created);
+    Ok(CopyReport {
+        directories_created: walked.directories_created,
+        interrupted: walked.interrupted,
+        remounts_exhausted: walked.remounts_exhausted,
+        ..Default::default()
+    })
+}
+
+fn copy_files(
+    config: &CopyConfig,
+    created: &mut CreatedDirs,
+    manifest: Option<&Manifest>,
+    since: Option<&SinceManifest>,
+    progress: Option<&Progress>,
+) -> Result<CopyReport> {
+    let checkpoint = Checkpoint::load(config)?;
+    let checkpoint = &checkpoint;
+    let mut create_dir = |report: &mut CopyReport, dir: &Path, dest_dir: &Path| {
+        create_dest_dir(config, created, report, dir, dest_dir).map(|_| true)
+    };
+    if config.jobs == 1 {
+        return walk_tree(config, checkpoint, since, &mut create_dir, &mut |report, from, to| {
+            copy_file_checkpointed(config, checkpoint, manifest, progress, report, &from, &to)
+        });
+    }
+    let (sender, receiver) = mpsc::sync_channel::<(PathBuf, PathBuf)>(config.jobs * 4);
+    let receiver = Mutex::new(receiver);
+    let copied = Mutex::new(CopyReport::default());
+    let failed = Mutex::new(None);
+    let walked = thread::scope(|scope| {
+        for _ in 0..config.jobs {
+            scope.spawn(|| copy_worker(config, checkpoint, manifest, progress, &receiver, &copied, &failed));
+        }
+        let walked = walk_tree(config, checkpoint, since, &mut create_dir, &mut |_, from, to| {
+            match failed.lock().unwrap().as_ref() {
+                Some(e) if e.is::<DestinationFull>() => return Err(DestinationFull.into()),
+                Some(e) if e.is::<RemountsExhausted>() => return Err(RemountsExhausted.into()),
+                Some(e) if e.is::<MaxFilesReached>() => return Err(MaxFilesReached.into()),
+                Some(_) => bail!("stopping after a copy worker failed"),
+                None => (),
+            }
+            if reached_max_files(config, &copied.lock().unwrap()) {
+                return Err(MaxFilesReached.into());
+            }
+            sender.send((from, to)).map_err(|_| anyhow!("all copy workers have stopped"))
+        });
+        drop(sender);
+        walked
+    });
+    let failed = failed.into_inner().unwrap();
+    let (destination_full, remounts_exhausted, max_files_reached) = match &failed {
+        Some(e) if e.is::<DestinationFull>() => (true, false, false),
+        Some(e) if e.is::<RemountsExhausted>() => (false, true, false),
+        Some(e) if e.is::<MaxFilesReached>() => (false, false, true),
+        Some(_) => return Err(failed.unwrap()),
+        None => (false, false, false),
+    };
+    let mut report = walked?;
+    report.merge(copied.into_inner().unwrap());
+    report.destination_full |= destination_full;
+    report.remounts_exhausted |= remounts_exhausted;
+    report.max_files_reached |= max_files_reached;
+    Ok(report)
+}
+
+/// `max_files` were copied, the walk stops there like on a full destination
+#[derive(Debug)]
+struct MaxFilesReached;
+
+impl fmt::Display for MaxFilesReached {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "copied the maximum number of files")
+    }
+}
+
+impl std::error::Error for MaxFilesReached {}
+
+fn reached_max_files(config: &CopyConfig, report: &CopyReport) -> bool {
+    config.max_files.is_some_and(|max| report.files_copied >= max)
+}
+
+/// The `retry_failures_at_end` pass over one root: walks down to every failed path in `report` again and copies
+/// what's at or under it the way the first pass would have, so it ends up under the same name. Everything else
+/// the walk lists was done by the first pass.
+pub(crate) fn retry_failures(
+    config: &CopyConfig,
+    manifest: Option<&Manifest>,
+    since: Option<&SinceManifest>,
+    report: &mut CopyReport,
+) -> Result<()> {
+    let failed: HashSet<PathBuf> = report
+        .failures
+        .iter()
+        .map(|f| PathBuf::from(&f.path))
+        .filter(|path| path.starts_with(&config.source))
+        .collect();
+    if failed.is_empty() {
+        return Ok(());
+    }
+    let config = &CopyConfig {
+        mirror: false,
+        ..config.clone()
+    };
+    let checkpoint = Checkpoint::load(config)?;
+    let checkpoint = &checkpoint;
+    let is_retried = |path: &Path| path.ancestors().any(|p| failed.contains(p));
+    let mut created = CreatedDirs::default();
+    // the failed paths the walk got to and copied, the rest keeps its failure,
+    // say one that's gone since or one the walk stopped before
+    let (mut entered, mut copied) = (vec![], vec![]);
+    let walked = walk_tree(
+        config,
+        checkpoint,
+        since,
+        &mut |report, dir, dest_dir| {
+            if !is_retried(dir) && !failed.iter().any(|f| f.starts_with(dir)) {
+                return Ok(false);
+            }
+            if failed.contains(dir) {
+                entered.push(dir.to_path_buf());
+            }
+            create_dest_dir(config, &mut created, report, dir, dest_dir).map(|_| true)
+        },
+        &mut |report, from, to| {
+            if !is_retried(&from) {
+                return checkpoint.done(&from);
+            }
+            let failures = report.failures.len();
+            copy_file_checkpointed(config, checkpoint, manifest, None, report, &from, &to)?;
+            // a big file stopped half-way on an interrupt isn't copied either
+            if failed.contains(&from) && report.failures.len() == failures && !report.interrupted {
+                copied.push(from);
+            }
+            Ok(())
+        },
+    )?;
+    // a directory is only copied once the walk went through all of it
+    let entered = entered.into_iter().filter(|_| !walked.stopped_early());
+    let retried = copied.into_iter().chain(entered).collect();
+    report.merge_retry(walked, &retried);
+    Ok(())
+}
+
+/// A copy ran out of space on the destination, the walk stops there and keeps what it has done so far
+#[derive(Debug)]
+struct DestinationFull;
+
+impl fmt::Display for DestinationFull {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "no space left on the destination")
+    }
+}
+
+impl std::error::Error for DestinationFull {}
+
+fn copy_worker(
+    config: &CopyConfig,
+    checkpoint: &Checkpoint,
+    manifest: Option<&Manifest>,
+    progress: Option<&Progress>,
+    receiver: &Mutex<mpsc::Receiver<(PathBuf, PathBuf)>>,
+    copied: &Mutex<CopyReport>,
+    failed: &Mutex<Option<anyhow::Error>>,
+) {
+    loop {
+        let job = receiver.lock().unwrap().recv();
+        let Ok((from, to)) = job else {
+            return;
+        };
+        // whatever is still queued is left for the next run
+        if config.is_interrupted() {
+            return;
+        }
+        if reached_max_files(config, &copied.lock().unwrap()) {
+            failed.lock().unwrap().get_or_insert(MaxFilesReached.into());
+            return;
+        }
+        let mut report = CopyReport::default();
+        let result = copy_file_checkpointed(config, checkpoint, manifest, progress, &mut report, &from, &to);
+        copied.lock().unwrap().merge(report);
+        if let Err(e) = result {
+            failed.lock().unwrap().get_or_insert(e);
+            return;
+        }
+    }
+}
+
+/// Hands every directory to `enter` before listing it, which says whether to go in, and every file to `copy`,
+/// which reports it to `checkpoint` once it's done. Only directories wait in the queue, the files of a directory
+/// are handled as soon as it's listed.
+pub(crate) fn walk_tree(
+    config: &CopyConfig,
+    checkpoint: &Checkpoint,
+    since: Option<&SinceManifest>,
+    enter: &mut dyn FnMut(&mut CopyReport, &Path, &Path) -> Result<bool>,
+    copy: &mut dyn FnMut(&mut CopyReport, PathBuf, PathBuf) -> Result<()>,
+) -> Result<CopyReport> {
+    let filters = Filters::from_config(config)?;
+    let mut report = CopyReport::default();
+    let mut claimed = HashMap::new();
+    // (device, inode) of every directory walked, so a symlink back to one can't loop forever
+    let mut walked = HashMap::new();
+    // (device, inode) of files with more than one link, and where the first of them went
+    let mut hardlinks = HashMap::new();
+    let source = PathBuf::from(&config.source);
+    if is_failure(config, &source) {
+        return Ok(report);
+    }
+    if checkpoint.is_completed(&source) {
+        report.paths_skipped_by_checkpoint += 1;
+        events::emit(config, Event::skipped(&source, Skip::Checkpoint));
+        return Ok(report);
+    }
+    if source.is_file() {
+        // named on its own, so it's copied whatever the filters say
+        let dest_dir = replace_forbidden_characters(config, &config.dest);
+        let dest_path = dest_dir.join(sanitize_name(config, source.file_name().unwrap_or_default()));
+        if enter(&mut report, source.parent().unwrap_or(&source), &dest_dir)? {
+            copy_or_count(config, checkpoint, since, &mut report, copy, source, dest_path)?;
+        }
+        return Ok(report);
+    }
+    // every component of dest path must be escaped properly because it's created underscored at the destination,
+    // children are queued with their parent's final destination so a disambiguated directory keeps its contents
+    let mut dirs = VecDeque::from([(source, replace_forbidden_characters(config, &config.dest))]);
+    'walk: while let Some((dir, dest_dir)) = match config.traversal {
+        Traversal::DepthFirst => dirs.pop_back(),
+        Traversal::BreadthFirst => dirs.pop_front(),
+    } {
+        if config.is_interrupted() {
+            info!("interrupted, stopping before '{}'", dir.display());
+            report.interrupted = true;
+            break;
+        }
+        if config.symlinks == SymlinkMode::Follow {
+            if let Some(first) = fs::metadata(&dir)
+                .ok()
+                .and_then(|m| walked.insert((m.dev(), m.ino()), dir.clone()))
+            {
+                warn!("'{}' leads back to '{}', not walking it again", dir.display(), first.display());
+                report.record_failure(config, &dir, None, format!("symlink loop back to '{}'", first.display()));
+                continue;
+            }
+        }
+        if !enter(&mut report, &dir, &dest_dir)? {
+            continue;
+        }
+        let failures = report.failures.len();
+        let mut children = match list_dir(config, &mut report, &dir, &dest_dir) {
+            Err(e) if e.is::<RemountsExhausted>() => {
+                report.remounts_exhausted = true;
+                break;
+            }
+            children => children?,
+        };
+        newest_first(config, &mut children);
+        // an aborted listing is missing entries, mirroring it would delete their copies
+        // and it mustn't count as completed once the entries it has are done
+        let listed_fully = report.failures.len() == failures;
+        checkpoint.listed(&dir, children.len() + usize::from(!listed_fully))?;
+        let mut subdirs = vec![];
+        // where everything listed would go, whether it's copied this time or not
+        let mut expected = HashSet::new();
+        for (path, dest_path) in children {
+            if config.is_interrupted() {
+                info!("interrupted, stopping before '{}'", path.display());
+                report.interrupted = true;
+                break 'walk;
+            }
+            if config.mirror {
+                expected.insert(dest_path.clone());
+            }
+            if is_failure(config, &path) {
+                continue;
+            }
+            if filters.skips_hidden(&path) {
+                debug!("skipping hidden '{}'", path.display());
+                report.paths_skipped_hidden += 1;
+                events::emit(config, Event::skipped(&path, Skip::Hidden));
+                checkpoint.done(&path)?;
+                continue;
+            }
+            let relative = path.strip_prefix(config.source.as_path()).unwrap();
+            let is_dir = path.is_dir();
+            if !filters.accepts(relative, is_dir) {
+                events::emit(config, Event::skipped(&path, Skip::Excluded));
+                checkpoint.done(&path)?;
+                continue;
+            }
+            if is_symlink(&path) {
+                if skips_symlink(config, is_dir) {
+                    info!("skipping symlink '{}'", path.display());
+                    report.record_symlink(&path);
+                    events::emit(config, Event::skipped(&path, Skip::Symlink));
+                    checkpoint.done(&path)?;
+                    continue;
+                }
+                if !path.exists() {
+                    warn!("skipping dangling symlink '{}'", path.display());
+                    report.record_failure(config, &path, None, "dangling symlink");
+                    continue;
+                }
+            }
+            if !is_dir && filters.skips_size(&path) {
+                report.files_skipped_by_size += 1;
+                events::emit(config, Event::skipped(&path, Skip::Size));
+                checkpoint.done(&path)?;
+                continue;
+            }
+            if !is_dir && filters.skips_age(&path) {
+                report.files_skipped_by_age += 1;
+                events::emit(config, Event::skipped(&path, Skip::Age));
+                checkpoint.done(&path)?;
+                continue;
+            }
+            // not done, or a run without the limit would take it for copied
+            if is_dir && config.max_depth.is_some_and(|max| relative.components().count() >= max) {
+                debug!("not descending into '{}', it's at the maximum depth", path.display());
+                report.directories_skipped_by_depth += 1;
+                events::emit(config, Event::skipped(&path, Skip::Depth));
+                continue;
+            }
+            // a completed directory still claims its destination, or a colliding sibling could take it over.
+            // A flattened one has no destination of its own, its files go right into the same one.
+            let flattened = is_dir && (config.flatten || config.route_by_extension);
+            let dest_path = if !is_dir && config.route_by_extension { routed(config, &dest_path) } else { dest_path };
+            let newer = if is_dir { None } else { newer_collision(config, &claimed, &path, &dest_path) };
+            if let Some(newer) = newer.as_ref().filter(|_| config.on_collision == OnCollision::NewestOnly) {
+                info!("'{}' is older than '{}', which takes its name, skipping it", path.display(), newer.display());
+                report.record_collision(&path, &dest_path, newer, true);
+                report.files_skipped_by_collision += 1;
+                events::emit(config, Event::skipped(&path, Skip::Collision));
+                checkpoint.done(&path)?;
+                continue;
+            }
+            let dest_path = if flattened {
+                dest_dir.clone()
+            } else if is_dir && !config.skip_dirs_blocked_by_files {
+                claim_directory_destination(config, &mut claimed, &path, dest_path)
+            } else {
+                claim_destination(config, &mut claimed, &path, dest_path)
+            };
+            if config.mirror {
+                expected.insert(dest_path.clone());
+            }
+            if is_dir && !flattened && config.skip_dirs_blocked_by_files && is_file_in_the_way(&dest_path) {
+                warn!("'{}' is a file, leaving out the directory '{}'", dest_path.display(), path.display());
+                // File exists, what creating the directory runs into
+                report.record_failure(config, &path, Some(17), format!("'{}' is a file", dest_path.display()));
+                continue;
+            }
+            if checkpoint.is_completed(&path) {
+                report.paths_skipped_by_checkpoint += 1;
+                events::emit(config, Event::skipped(&path, Skip::Checkpoint));
+                checkpoint.done(&path)?;
+                continue;
+            }
+            if !is_dir && config.dedup_hardlinks {
+                if let Some(first) = copied_before(&mut hardlinks, &path, &dest_path) {
+                    info!("'{}' is a hard link to '{}', not copying it again", path.display(), first.display());
+                    report.record_hardlink(&path, &first);
+                    events::emit(config, Event::skipped(&path, Skip::Hardlink));
+                    checkpoint.done(&path)?;
+                    continue;
+                }
+            }
+            if let Some(newer) = &newer {
+                report.record_collision(&path, &dest_path, newer, false);
+            } else if path.file_name() != dest_path.file_name() && !flattened {
+                report.record_rename(&path, &dest_path);
+            }
+            if is_dir {
+                subdirs.push((path, dest_path));
+            } else if !copy_or_count(config, checkpoint, since, &mut report, copy, path, dest_path)? {
+                break 'walk;
+            }
+        }
+        if config.mirror && listed_fully {
+            remove_stale(config, &mut report, &dest_dir, &expected);
+        }
+        // queued so they come off the queue in `order`
+        match config.traversal {
+            Traversal::DepthFirst => dirs.extend(subdirs.into_iter().rev()),
+            Traversal::BreadthFirst => dirs.extend(subdirs),
+        }
+    }
+    Ok(report)
+}
+
+/// Hands `path` to `copy`, or only counts it in a dry run. False once the destination is full, the remounts are used up
+/// or `max_files` were copied.
+fn copy_or_count(
+    config: &CopyConfig,
+    checkpoint: &Checkpoint,
+    since: Option<&SinceManifest>,
+    report: &mut CopyReport,
+    copy: &mut dyn FnMut(&mut CopyReport, PathBuf, PathBuf) -> Result<()>,
+    path: PathBuf,
+    dest_path: PathBuf,
+) -> Result<bool> {
+    if reached_max_files(config, report) {
+        info!("copied {} files, stopping before '{}'", report.files_copied, path.display());
+        report.max_files_reached = true;
+        return Ok(false);
+    }
+    if since.is_some_and(|since| since.is_unchanged(&path)) {
+        debug!("'{}' is unchanged since the manifest, not copying it again", path.display());
+        report.files_unchanged_since_manifest += 1;
+        events::emit(config, Event::skipped(&path, Skip::Unchanged));
+        checkpoint.done(&path)?;
+        return Ok(true);
+    }
+    if config.dry_run {
+        if is_up_to_date(config, &path, &dest_path) {
+            report.files_already_copied += 1;
+        } else {
+            info!("would copy '{}' to '{}'", path.display(), dest_path.display());
+            report.files_copied += 1;
+            report.bytes_copied += fs::metadata(&path).map(|m| m.len()).unwrap_or(0);
+        }
+        checkpoint.done(&path)?;
+        return Ok(true);
+    }
+    match copy(report, path, dest_path) {
+        Err(e) if e.is::<DestinationFull>() => {
+            report.destination_full = true;
+            Ok(false)
+        }
+        Err(e) if e.is::<RemountsExhausted>() => {
+            report.remounts_exhausted = true;
+            Ok(false)
+        }
+        Err(e) if e.is::<MaxFilesReached>() => {
+            info!("copied {} files, stopping", config.max_files.unwrap_or_default());
+            report.max_files_reached = true;
+            Ok(false)
+        }
+        copied => copied.map(|_| true),
+    }
+}
+
+pub(crate) fn is_symlink(path: &Path) -> bool {
+    fs::symlink_metadata(path).is_ok_and(|m| m.file_type().is_symlink())
+}
+
+/// ExFAT has no symlinks, so one is either left out or replaced by what it points to
+pub(crate) fn skips_symlink(config: &CopyConfig, is_dir: bool) -> bool {
+    match config.symlinks {
+        SymlinkMode::Skip => true,
+        SymlinkMode::Dereference => is_dir,
+        SymlinkMode::Follow => false,
+    }
+}
+
+/// Where an earlier link to the same file as `path` was copied to. The first link gets `None`
+/// and its `dest_path` stands for all of them from then on.
+fn copied_before(hardlinks: &mut HashMap<(u64, u64), PathBuf>, path: &Path, dest_path: &Path) -> Option<PathBuf> {
+    let metadata = fs::metadata(path).ok().filter(|m| m.nlink() > 1)?;
+    match hardlinks.entry((metadata.dev(), metadata.ino())) {
+        Entry::Occupied(first) => Some(first.get().clone()),
+        Entry::Vacant(first) => {
+            first.insert(dest_path.to_path_buf());
+            None
+        }
+    }
+}
+
+fn create_dest_dir(
+    config: &CopyConfig,
+    created: &mut CreatedDirs,
+    report: &mut CopyReport,
+    path: &Path,
+    dest_path: &Path,
+) -> Result<()> {
+    if created.contains(dest_path) {
+        return Ok(());
+    }
+    if config.dry_run {
+        if !dest_path.exists() {
+            info!("would create '{}'", dest_path.display());
+            report.directories_created += 1;
+        }
+        created.insert(dest_path);
+        return Ok(());
+    }
+    if !dest_path.is_dir() {
+        report.directories_created += 1;
+    }
+    match fs::create_dir_all(dest_path) {
+        // a renamed one is created again every time, so the rename is reported by every pass
+        Ok(_) => created.insert(dest_path),
+        Err(e) => match e.raw_os_error() {
+            Some(22) => {
+                let sanitized = replace_forbidden_characters(config, dest_path);
+                if sanitized != dest_path {
+                    report.record_rename(path, &sanitized);
+                }
+                fs::create_dir_all(&sanitized)
+                    .with_context(|| format!("can't create directory '{}'", sanitized.display()))?;
+            }
+            _ => {
+                return Err(e).with_context(|| {
+                    format!("can't create directory '{}' for '{}'", dest_path.display(), path.display())
+                });
+            }
+        },
+    }
+    Ok(())
+}
+
+/// The entries of `path` in `order`, paired with their destinations under `dest_path`. A listing that aborts is
+/// read again from scratch after a remount; if that aborts too, the entries read before the abort are all there is.
+fn list_dir(
+    config: &CopyConfig,
+    report: &mut CopyReport,
+    path: &Path,
+    dest_path: &Path,
+) -> Result<Vec<(PathBuf, PathBuf)>> {
+    let mut retried = false;
+    let mut children = loop {
+        let mount = MOUNT_GENERATION.read().unwrap();
+        let (children, aborted) = read_entries(config, report, path, dest_path)?;
+        let generation = *mount;
+        drop(mount);
+        if !aborted {
+            break children;
+        }
+        // the directory handle is closed by now, so it doesn't keep the volume busy
+        warn!(
+            "Software caused connection abort after {} entries of '{}', remounting",
+            children.len(),
+            path.display()
+        );
+        remount_since(config, generation)?;
+        if retried {
+            // readdir doesn't tell which entry aborts it, so only the rest of this listing is given up on
+            warn!("listing '{}' aborted again, going on with the {} entries read", path.display(), children.len());
+            report.record_failure(config, path, Some(103), format!("listing aborted after {} entries", children.len()));
+            break children;
+        }
+        retried = true;
+    };
+    children.sort_by(|(a, _), (b, _)| a.file_name().cmp(&b.file_name()));
+    if config.order == WalkOrder::NameDesc {
+        children.reverse();
+    }
+    Ok(children)
+}
+
+/// Whatever `path` lists before a connection abort, and whether there was one.
+/// A directory behind too many symlinks, or that can't be read for its permissions, lists nothing and is recorded
+/// as a failure.
+fn read_entries(
+    config: &CopyConfig,
+    report: &mut CopyReport,
+    path: &Path,
+    dest_path: &Path,
+) -> Result<(Vec<(PathBuf, PathBuf)>, bool)> {
+    let mut children = vec![];
+    let entries = match fs::read_dir(path) {
+        Ok(entries) => entries,
+        Err(e) if e.raw_os_error() == Some(103) => return Ok((children, true)),
+        // Too many levels of symbolic links
+        Err(e) if e.raw_os_error() == Some(40) => {
+            warn!("too many levels of symlinks listing '{}', skipping it", path.display());
+            report.record_failure(config, path, e.raw_os_error(), e.to_string());
+            return Ok((children, false));
+        }
+        Err(e) if e.raw_os_error() == Some(13) && !config.fail_on_permission_denied => {
+            warn!("permission denied listing '{}', skipping it", path.display());
+            report.record_failure(config, path, e.raw_os_error(), e.to_string());
+            return Ok((children, false));
+        }
+        Err(e) => return Err(e).with_context(|| format!("can't read directory '{}'", path.display())),
+    };
+    for entry in entries {
+        match entry {
+            Ok(entry) => {
+                let name = sanitize_name(config, &entry.file_name());
+                children.push((entry.path(), dest_path.join(name)));
+            }
+            // Software caused connection abort, the volume has to be remounted
+            Err(e) if e.raw_os_error() == Some(103) => return Ok((children, true)),
+            Err(e) => return Err(e).with_context(|| format!("can't read entry of '{}'", path.display())),
+        }
+    }
+    Ok((children, false))
+}
+
+/// A file that was skipped on a failure isn't done, so a later run still lists its directory to retry it
+fn copy_file_checkpointed(
+    config: &CopyConfig,
+    checkpoint: &Checkpoint,
+    manifest: Option<&Manifest>,
+    progress: Option<&Progress>,
+    report: &mut CopyReport,
+    from: &Path,
+    to: &Path,
+) -> Result<()> {
+    let failures = report.failures.len();
+    let started = Instant::now();
+    let generation = *MOUNT_GENERATION.read().unwrap();
+    let copied = copy_file_with_progress(config, progress, report, from, to)?;
+    if let Some(copied) = &copied {
+        events::emit(config, Event::copied(from, &copied.to, copied.bytes, started.elapsed()));
+    }
+    let throughput = Throughput {
+        files: 1,
+        bytes_copied: copied.as_ref().map_or(0, |c| c.bytes),
+        secs: started.elapsed().as_secs_f64(),
+        remounts: MOUNT_GENERATION.read().unwrap().saturating_sub(generation),
+    };
+    report.record_throughput(config, from, throughput);
+    if let (Some(manifest), Some(copied)) = (manifest, copied) {
+        manifest.record(&config.source, from, &copied)?;
+    }
+    // a big file stopped half-way on an interrupt isn't done either
+    if report.failures.len() == failures && !report.interrupted {
+        checkpoint.done(from)?;
+    }
+    Ok(())
+}
+
+/// A file that was actually copied this time
+pub(crate) struct Copied {
+    pub(crate) to: PathBuf,
+    pub(crate) bytes: u64,
+    /// Hex SHA-256 of the source, when it was hashed on the way
+    pub(crate) sha256: Option<String>,
+}
+
+/// Advances the progress bar by the whole file whatever the outcome, so skipped and failed files count as done
+fn copy_file_with_progress(
+    config: &CopyConfig,
+    progress: Option<&Progress>,
+    report: &mut CopyReport,
+    from: &Path,
+    to: &Path,
+) -> Result<Option<Copied>> {
+    // what a big file already moved the bar by while it was streamed
+    let advanced = Cell::new(0);
+    let progressed = |bytes| {
+        if let Some(progress) = progress {
+            progress.advance(bytes);
+        }
+        advanced.set(advanced.get() + bytes);
+    };
+    let result = copy_file(config, report, from, to, &progressed);
+    if let Some(progress) = progress {
+        progress.file_done(from, fs::metadata(from).map(|m| m.len()).unwrap_or(0).saturating_sub(advanced.get()));
+    }
+    result
+}
+
+fn copy_file(
+    config: &CopyConfig,
+    report: &mut CopyReport,
+    from: &Path,
+    to: &Path,
+    progressed: &dyn Fn(u64),
+) -> Result<Option<Copied>> {
+    if is_up_to_date(config, from, to) {
+        report.files_already_copied += 1;
+        events::emit(config, Event::skipped(from, Skip::AlreadyCopied));
+        return Ok(None);
+    }
+    let len = fs::metadata(from).map(|m| m.len()).unwrap_or(0);
+    let dest_dir = to.parent().unwrap_or(to);
+    // the folders are only made once something goes into them
+    if config.route_by_extension {
+        fs::create_dir_all(dest_dir).with_context(|| format!("can't create directory '{}'", dest_dir.display()))?;
+    }
+    let available = fs2::available_space(dest_dir)
+        .with_context(|| format!("can't get free space of '{}'", dest_dir.display()))?;
+    if !has_room(config, report, from, len, available)? {
+        return Ok(None);
+    }
+    copy_file_attempt(config, report, from, to, progressed, true, 0)
+}
+
+/// A file bigger than the free space is skipped and recorded with `skip_oversize`, and stops the copy without it
+fn has_room(config: &CopyConfig, report: &mut CopyReport, from: &Path, len: u64, available: u64) -> Result<bool> {
+    if len <= available {
+        return Ok(true);
+    }
+    if !config.skip_oversize {
+        bail!(
+            "'{}' is {} bytes but only {} bytes are free on the destination",
+            from.display(),
+            len,
+            available
+        );
+    }
+    warn!("skipping '{}', {} bytes don't fit in {} bytes free", from.display(), len, available);
+    report.files_skipped_no_space += 1;
+    // No space left on device, what copying it would have run into
+    report.record_failure(config, from, Some(28), format!("{} bytes don't fit in {} bytes free", len, available));
+    Ok(false)
+}
+
+// ExFAT keeps modification times with a 10ms granularity, and some drivers round to 2 seconds like FAT
+const MTIME_TOLERANCE_SECS: i64 = 2;
+
+/// Whether the destination that exists passes for a finished copy under `overwrite`
+fn is_up_to_date(config: &CopyConfig, from: &Path, to: &Path) -> bool {
+    if !to.exists() {
+        return false;
+    }
+    match config.overwrite {
+        Overwrite::Skip => return true,
+        Overwrite::Always => return false,
+        Overwrite::IfNewer | Overwrite::IfDifferent => (),
+    }
+    let (Ok(source), Ok(dest)) = (fs::metadata(from), fs::metadata(to)) else {
+        return false;
+    };
+    let source_mtime = clamp_to_exfat_range(FileTime::from_last_modification_time(&source));
+    let dest_mtime = FileTime::from_last_modification_time(&dest);
+    let newer_by = source_mtime.unix_seconds() - dest_mtime.unix_seconds();
+    if config.overwrite == Overwrite::IfNewer {
+        return newer_by <= MTIME_TOLERANCE_SECS;
+    }
+    if source.len() != dest.len() {
+        return false;
+    }
+    !config.preserve_mtime || newer_by.abs() <= MTIME_TOLERANCE_SECS
+}
+
+/// The existence check is left to `copy_file`. `io_retry` counts the retries after I/O errors so far.
+fn copy_file_attempt(
+    config: &CopyConfig,
+    report: &mut CopyReport,
+    from: &Path,
+    to: &Path,
+    progressed: &dyn Fn(u64),
+    recopy_on_mismatch: bool,
+    io_retry: u32,
+) -> Result<Option<Copied>> {
+    let hash = config.verify == Verify::Sha256 || (config.manifest.is_some() && config.manifest_sha256);
+    let mut hasher = hash.then(Sha256::new);
+    let (generation, copied) = {
+        let generation = MOUNT_GENERATION.read().unwrap();
+        (*generation, copy_contents(config, from, to, hasher.as_mut(), progressed))
+    };
+    match copied {
+        Ok(bytes) => {
+            let source_hash = hasher.map(|h| h.finalize());
+            if let Some(mismatch) = verify_copy(config, from, to, source_hash.as_deref()) {
+                if recopy_on_mismatch {
+                    warn!("'{}' {}, copying it again", to.display(), mismatch);
+                    return copy_file_attempt(config, report, from, to, progressed, false, io_retry);
+                }
+                warn!("'{}' {} again, removing it", to.display(), mismatch);
+                if let Err(e) = fs::remove_file(to) {
+                    warn!("can't remove unverified copy '{}': {}", to.display(), e);
+                }
+                report.files_failed_verification += 1;
+                report.record_failure(config, from, None, format!("verification failed, {}", mismatch));
+                return Ok(None);
+            }
+            report.files_copied += 1;
+            report.bytes_copied += bytes;
+            if config.preserve_mtime {
+                if let Err(e) = preserve_mtime(from, to) {
+                    warn!("can't preserve modification time of '{}': {}", to.display(), e);
+                }
+            }
+            if config.preserve_permissions {
+                // plain ExFAT has nowhere to keep them, so that's no reason to warn
+                if let Err(e) = fs::metadata(from).and_then(|m| fs::set_permissions(to, m.permissions())) {
+                    debug!("can't preserve permissions of '{}': {}", to.display(), e);
+                }
+            }
+            if config.preserve_xattrs {
+                if let Err(e) = copy_xattrs(from, to) {
+                    debug!("can't read extended attributes of '{}': {}", from.display(), e);
+                }
+            }
+            Ok(Some(Copied {
+                to: to.to_path_buf(),
+                bytes,
+                sha256: source_hash.map(|h| format!("{:x}", h)),
+            }))
+        }
+        Err(e) if e.kind() == ErrorKind::Interrupted && config.is_interrupted() => {
+            info!("interrupted half-way through '{}', leaving it for the next run", from.display());
+            report.interrupted = true;
+            Ok(None)
+        }
+        Err(e) => match e.raw_os_error() {
+            Some(5) if io_retry < config.io_retries => {
+                // input-output error, often gone again on a flaky FUSE mount
+                let delay = config.io_retry_delay * 2u32.saturating_pow(io_retry);
+                warn!(
+                    "I/O error on '{}', retry {} of {} in {:?}",
+                    from.display(),
+                    io_retry + 1,
+                    config.io_retries,
+                    delay
+                );
+                thread::sleep(delay);
+                copy_file_attempt(config, report, from, to, progressed, recopy_on_mismatch, io_retry + 1)
+            }
+            Some(5) => {
+                //  input-output error, can't get source data, just continue
+                warn!("I/O error on '{}', skipping it", from.display());
+                report.record_unreadable(from);
+                report.record_failure(config, from, e.raw_os_error(), e.to_string());
+                Ok(None)
+            }
+            Some(103) => {
+                // Software caused connection abort -- the file may well be readable on a fresh mount
+                if handle_software_caused_connection_abort(config, report, from, generation)? {
+                    return Ok(None);
+                }
+                copy_file_attempt(config, report, from, to, progressed, recopy_on_mismatch, io_retry)
+            }
+            Some(22) => {
+                let sanitized = replace_forbidden_characters(config, to);
+                // already sanitized, so trying again would only fail the same way for good
+                if sanitized == to {
+                    warn!("'{}' is still invalid on the destination, skipping '{}'", to.display(), from.display());
+                    report.record_failure(config, from, e.raw_os_error(), e.to_string());
+                    return Ok(None);
+                }
+                report.record_rename(from, &sanitized);
+                copy_file(config, report, from, sanitized.as_path(), progressed)
+            }
+            Some(28) => {
+                // No space left on device -- a full disk won't get better by itself, stop here
+                error!("no space left on the destination, stopping at '{}'", from.display());
+                report.record_failure(config, from, e.raw_os_error(), e.to_string());
+                Err(DestinationFull.into())
+            }
+            Some(2) => {
+                // broken link, just continue - TODO: stat to confirm it was a link
+                report.record_failure(config, from, e.raw_os_error(), e.to_string());
+                Ok(None)
+            }
+            Some(13) if !config.fail_on_permission_denied => {
+                // Permission denied, a system volume always has a few of these
+                warn!("permission denied reading '{}', skipping it", from.display());
+                report.record_failure(config, from, e.raw_os_error(), e.to_string());
+                Ok(None)
+            }
+            Some(40) => {
+                // Too many levels of symbolic links, firmlinks on macOS volumes run into this
+                warn!("too many levels of symlinks resolving '{}', skipping it", from.display());
+                report.record_failure(config, from, e.raw_os_error(), e.to_string());
+                Ok(None)
+            }
+            _ => Err(e).with_context(|| format!("can't copy '{}' to '{}'", from.display(), to.display())),
+        },
+    }
+}
+
+/// Sets every extended attribute of `from` on `to` that the destination takes, the ones it rejects are left out
+fn copy_xattrs(from: &Path, to: &Path) -> io::Result<()> {
+    for name in xattr::list(from)? {
+        let Some(value) = xattr::get(from, &name)? else {
+            continue;
+        };
+        if let Err(e) = xattr::set(to, &name, &value) {
+            debug!("can't preserve extended attribute {:?} of '{}': {}", name, to.display(), e);
+        }
+    }
+    Ok(())
+}
+
+/// Files bigger than this are streamed through `buffer_size` chunks rather than copied in one go
+const STREAM_ABOVE: u64 = 16 * 1024 * 1024;
+
+/// `fs::copy` through a `part_path` next to `to` that's only renamed to `to` once it's complete, so a truncated
+/// file never passes for a finished copy, not even after a crash. Whatever was at `to` stays until then.
+/// With a `hasher` the source is hashed on the way through instead of being read again.
+/// Only `stream` keeps to a `rate_limit`, so every file is streamed with one.
+fn copy_contents(
+    config: &CopyConfig,
+    from: &Path,
+    to: &Path,
+    hasher: Option<&mut Sha256>,
+    progressed: &dyn Fn(u64),
+) -> io::Result<u64> {
+    let mut source = fs::File::open(from)?;
+    let metadata = source.metadata()?;
+    if !metadata.is_file() {
+        return Err(io::Error::new(ErrorKind::InvalidInput, "the source path is not a regular file"));
+    }
+    let part = part_path(to);
+    let mut dest = fs::File::create(&part)?;
+    let copied = match (hasher, metadata.len() > STREAM_ABOVE || config.rate_limit.is_some()) {
+        (Some(hasher), true) => stream(config, &mut HashingReader { inner: source, hasher }, &mut dest, progressed),
+        (None, true) => stream(config, &mut source, &mut dest, progressed),
+        (Some(hasher), false) => io::copy(&mut HashingReader { inner: source, hasher }, &mut dest),
+        (None, false) => io::copy(&mut source, &mut dest),
+    };
+    drop(dest);
+    copied.and_then(|bytes| fs::rename(&part, to).map(|_| bytes)).inspect_err(|_| {
+        if let Err(e) = fs::remove_file(&part) {
+            warn!("can't remove partial copy '{}': {}", part.display(), e);
+        }
+    })
+}
+
+/// Partial copies a crashed run left behind anywhere under `dest`, a copy that fails otherwise removes its own
+pub(crate) fn remove_partial_copies(dest: &Path) -> usize {
+    let mut removed = 0;
+    let mut dirs = vec![dest.to_path_buf()];
+    while let Some(dir) = dirs.pop() {
+        let Ok(entries) = fs::read_dir(&dir) else {
+            continue;
+        };
+        for entry in entries.flatten() {
+            let path = entry.path();
+            match entry.file_type() {
+                Ok(t) if t.is_dir() => dirs.push(path),
+                Ok(_) if entry.file_name().to_string_lossy().ends_with(PART_SUFFIX) => match fs::remove_file(&path) {
+                    Ok(()) => removed += 1,
+                    Err(e) => warn!("can't remove partial copy '{}': {}", path.display(), e),
+                },
+                _ => (),
+            }
+        }
+    }
+    removed
+}
+
+/// Copies a chunk at a time, telling `progressed` about every one and keeping to `rate_limit`,
+/// and stops with `ErrorKind::Interrupted` between chunks once the copy is interrupted
+fn stream(config: &CopyConfig, reader: &mut dyn Read, dest: &mut dyn Write, progressed: &dyn Fn(u64)) -> io::Result<u64> {
+    let mut buffer = vec![0; config.buffer_size.max(1)];
+    let mut copied = 0;
+    loop {
+        if config.is_interrupted() {
+            return Err(io::Error::new(ErrorKind::Interrupted, "interrupted half-way through the file"));
+        }
+        let read = match reader.read(&mut buffer) {
+            Ok(0) => return Ok(copied),
+            Ok(read) => read,
+            Err(e) if e.kind() == ErrorKind::Interrupted => continue,
+            Err(e) => return Err(e),
+        };
+        dest.write_all(&buffer[..read])?;
+        copied += read as u64;
+        progressed(read as u64);
+        if let Some(rate) = config.rate_limit {
+            throttle(rate, read as u64);
+        }
+    }
+}
+
+struct HashingReader<'a, R> {
+    inner: R,
+    hasher: &'a mut Sha256,
+}
+
+impl<R: Read> Read for HashingReader<'_, R> {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let read = self.inner.read(buf)?;
+        self.hasher.update(&buf[..read]);
+        Ok(read)
+    }
+}
+
+/// What's wrong with the copy `to` as far as `verify` checks, `source_hash` is what `copy_contents` hashed on the way
+pub(crate) fn verify_copy(config: &CopyConfig, from: &Path, to: &Path, source_hash: Option<&[u8]>) -> Option<String> {
+    match config.verify {
+        Verify::None => None,
+        Verify::Size => {
+            let (source, dest) = (fs::metadata(from).ok()?.len(), fs::metadata(to).map(|m| m.len()).unwrap_or(0));
+            (source != dest).then(|| format!("is {} bytes instead of {}", dest, source))
+        }
+        Verify::Sha256 => {
+            let source = source_hash?;
+            let mut dest = Sha256::new();
+            let hashed = fs::File::open(to).and_then(|mut f| io::copy(&mut f, &mut dest));
+            match hashed {
+                Ok(_) if dest.finalize().as_slice() == source => None,
+                Ok(_) => Some("has a different SHA-256".to_string()),
+                Err(e) => Some(format!("can't be read back: {}", e)),
+            }
+        }
+    }
+}
+
+// ExFAT timestamps run from 1980-01-01 to 2107-12-31
+const EXFAT_MIN_MTIME: i64 = 315_532_800;
+const EXFAT_MAX_MTIME: i64 = 4_354_819_199;
+
+fn preserve_mtime(from: &Path, to: &Path) -> std::io::Result<()> {
+    let mtime = FileTime::from_last_modification_time(&fs::metadata(from)?);
+    let clamped = clamp_to_exfat_range(mtime);
+    if clamped != mtime {
+        warn!("modification time of '{}' is outside of the ExFAT range, clamping it", from.display());
+    }
+    filetime::set_file_mtime(to, clamped)
+}
+
+fn clamp_to_exfat_range(mtime: FileTime) -> FileTime {
+    if mtime.unix_seconds() < EXFAT_MIN_MTIME {
+        FileTime::from_unix_time(EXFAT_MIN_MTIME, 0)
+    } else if mtime.unix_seconds() > EXFAT_MAX_MTIME {
+        FileTime::from_unix_time(EXFAT_MAX_MTIME, 0)
+    } else {
+        mtime
+    }
+}
+
+pub(crate) fn handle_software_caused_connection_abort(
+    config: &CopyConfig,
+    report: &mut CopyReport,
+    path: &Path,
+    generation: u64,
+) -> Result<bool> {
+    let aborts = remember_failure(config, path)?;
+    let given_up = aborts >= config.max_path_aborts;
+    warn!(
+        "Software caused connection abort {} of {}, remounting and {}: {}",
+        aborts,
+        config.max_path_aborts,
+        if given_up { "skipping it from now on" } else { "retrying" },
+        path.display()
+    );
+    if given_up {
+        report.paths_abandoned += 1;
+        report.record_failure(config, path, Some(103), "software caused connection abort");
+    }
+    remount_since(config, generation)?;
+    info!("remounted, continuing");
+    Ok(given_up)
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig, CopyReport, OnCollision, Overwrite, SymlinkMode, Traversal, Verify, WalkOrder};
+    use filetime::FileTime;
+    use sha2::{Digest, Sha256};
+    use std::path::PathBuf;
+
+    /// `walk_tree` the way a copy runs it, creating directories as it goes
+    fn walk_tree(
+        config: &CopyConfig,
+        checkpoint: &super::Checkpoint,
+        copy: &mut dyn FnMut(&mut CopyReport, PathBuf, PathBuf) -> anyhow::Result<()>,
+    ) -> anyhow::Result<CopyReport> {
+        let mut created = super::CreatedDirs::default();
+        let mut create_dir = |report: &mut CopyReport, dir: &std::path::Path, dest_dir: &std::path::Path| {
+            super::create_dest_dir(config, &mut created, report, dir, dest_dir).map(|_| true)
+        };
+        super::walk_tree(config, checkpoint, None, &mut create_dir, copy)
+    }
+
+    #[test]
+    fn it_creates_a_destination_directory_once() {
+        let (source, dest) = test_dirs("created_dirs");
+        let config = CopyConfig::new("", "", source.clone(), dest.clone());
+        let (mut created, mut report) = (super::CreatedDirs::default(), CopyReport::default());
+        for dir in ["a", "b", "a"] {
+            super::create_dest_dir(&config, &mut created, &mut report, &source.join(dir), &dest.join("flat")).unwrap();
+        }
+        assert_eq!((report.directories_created, created.skipped), (1, 2));
+        // only this copy remembers it
+        std::fs::remove_dir(dest.join("flat")).unwrap();
+        super::create_dest_dir(&config, &mut super::CreatedDirs::default(), &mut report, &source, &dest.join("flat")).unwrap();
+        assert!(dest.join("flat").is_dir());
+
+        for i in 0..=super::MAX_CREATED_DIRS {
+            created.insert(&dest.join(i.to_string()));
+        }
+        assert!(created.dirs.len() <= super::MAX_CREATED_DIRS);
+        assert!(!created.contains(&dest.join("flat")));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_copies_files_on_parallel_workers() {
+        let (source, dest) = test_dirs("parallel");
+        for dir in ["a", "b:c"] {
+            std::fs::create_dir(source.join(dir)).unwrap();
+            for i in 0..20 {
+                std::fs::write(source.join(dir).join(format!("{}.txt", i)), i.to_string()).unwrap();
+            }
+        }
+        let config = CopyConfig {
+            jobs: 4,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!(report.files_copied, 40);
+        assert_eq!(report.directories_created, 2);
+        assert_eq!(std::fs::read_to_string(dest.join("b_c").join("19.txt")).unwrap(), "19");
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_or_stops_on_files_bigger_than_free_space() {
+        let from = std::path::Path::new("/src/big.mov");
+        let mut report = CopyReport::default();
+        let stop = CopyConfig::default();
+        assert!(super::has_room(&stop, &mut report, from, 10, 10).unwrap());
+        assert!(super::has_room(&stop, &mut report, from, 11, 10).is_err());
+
+        let skip = CopyConfig {
+            skip_oversize: true,
+            ..Default::default()
+        };
+        assert!(!super::has_room(&skip, &mut report, from, 11, 10).unwrap());
+        assert_eq!(report.files_skipped_no_space, 1);
+        assert_eq!(report.failures[0].path, "/src/big.mov");
+    }
+
+    #[test]
+    fn it_stops_walking_when_destination_is_full() {
+        let (source, dest) = test_dirs("full");
+        std::fs::create_dir(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub").join("a.txt"), "a").unwrap();
+        std::fs::write(source.join("sub").join("b.txt"), "b").unwrap();
+        let config = CopyConfig::new("", "", source.clone(), dest);
+        let mut copies = 0;
+        let checkpoint = super::Checkpoint::load(&config).unwrap();
+        let report = walk_tree(&config, &checkpoint, &mut |_, _, _| {
+            copies += 1;
+            Err(super::DestinationFull.into())
+        })
+        .unwrap();
+        assert_eq!(copies, 1);
+        assert!(report.destination_full);
+        assert_eq!(report.directories_created, 1);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_leaves_destination_alone_when_source_cant_be_read() {
+        let (source, dest) = test_dirs("partial");
+        let to = dest.join("kept.txt");
+        std::fs::write(&to, "kept").unwrap();
+        assert!(super::copy_contents(&CopyConfig::default(), &source.join("missing.txt"), &to, None, &drop).is_err());
+        assert!(super::copy_contents(&CopyConfig::default(), &source, &to, None, &drop).is_err());
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "kept");
+
+        std::fs::write(source.join("new.txt"), "new").unwrap();
+        assert_eq!(super::copy_contents(&CopyConfig::default(), &source.join("new.txt"), &to, None, &drop).unwrap(), 3);
+        assert_eq!(std::fs::read_to_string(&to).unwrap(), "new");
+        assert!(!crate::names::part_path(&to).exists());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_removes_partial_copies_left_by_a_crash() {
+        let (source, dest) = test_dirs("part-files");
+        std::fs::create_dir(dest.join("sub")).unwrap();
+        std::fs::write(dest.join("sub").join("a.mov.apfs-copier-part"), "trunc").unwrap();
+        std::fs::write(dest.join("b.apfs-copier-part"), "trunc").unwrap();
+        std::fs::write(dest.join("download.part"), "kept").unwrap();
+        assert_eq!(super::remove_partial_copies(&dest), 2);
+        assert!(dest.join("download.part").exists());
+        assert!(dest.join("sub").exists());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_creates_every_directory_before_copying_files() {
+        let (source, dest) = test_dirs("structure-first");
+        for dir in ["a", "b/c"] {
+            std::fs::create_dir_all(source.join(dir)).unwrap();
+            std::fs::write(source.join(dir).join("file.txt"), dir).unwrap();
+        }
+        // in the way of the directory b, both passes put it next to it under the same name
+        std::fs::write(dest.join("b"), "not a directory").unwrap();
+        let config = CopyConfig {
+            structure_first: true,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!((report.directories_created, report.files_copied), (3, 2));
+        assert_eq!(std::fs::read_to_string(dest.join("b (1)").join("c").join("file.txt")).unwrap(), "b/c");
+
+        std::fs::remove_file(dest.join("b")).unwrap();
+        std::fs::remove_dir_all(dest.join("a")).unwrap();
+        std::fs::remove_dir_all(dest.join("b (1)")).unwrap();
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!(report.directories_created, 3);
+        assert_eq!(report.files_copied, 2);
+        assert_eq!(std::fs::read_to_string(dest.join("b").join("c").join("file.txt")).unwrap(), "b/c");
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_stops_after_max_files() {
+        let (source, dest) = test_dirs("max-files");
+        for i in 0..10 {
+            std::fs::write(source.join(format!("{}.txt", i)), i.to_string()).unwrap();
+        }
+        let copy = |dry_run, jobs| {
+            let config = CopyConfig {
+                max_files: Some(3),
+                dry_run,
+                jobs,
+                ..CopyConfig::new("", "", source.clone(), dest.clone())
+            };
+            super::walk_and_copy(&config, None, None, None).unwrap()
+        };
+        let counted = copy(true, 1);
+        assert_eq!((counted.files_copied, counted.max_files_reached), (3, true));
+        let copied = copy(false, 1);
+        assert_eq!((copied.files_copied, copied.max_files_reached), (3, true));
+        // the copied ones are skipped and don't count
+        let copied = copy(false, 4);
+        assert!(copied.max_files_reached);
+        assert!((3..=6).contains(&copied.files_copied));
+        assert_eq!(copied.files_already_copied, 3);
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count() as u64, 3 + copied.files_copied);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_stops_walking_when_interrupted() {
+        let (source, dest) = test_dirs("interrupted");
+        std::fs::write(source.join("a.txt"), "a").unwrap();
+        let config = CopyConfig::new("", "", source.clone(), dest);
+        config.interrupt();
+        let checkpoint = super::Checkpoint::load(&config).unwrap();
+        let report = walk_tree(&config, &checkpoint, &mut |_, _, _| panic!("copied after an interrupt")).unwrap();
+        assert!(report.interrupted);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_renames_entries_differing_only_in_case() {
+        let (source, dest) = test_dirs("case-collision");
+        std::fs::write(source.join("File.txt"), "upper").unwrap();
+        std::fs::write(source.join("file.txt"), "lower").unwrap();
+        let config = CopyConfig::new("", "", source.clone(), dest.clone());
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!(report.files_copied, 2);
+        assert_eq!(std::fs::read_to_string(dest.join("File.txt")).unwrap(), "upper");
+        assert_eq!(std::fs::read_to_string(dest.join("file (1).txt")).unwrap(), "lower");
+        assert_eq!(report.renames.len(), 1);
+        assert_eq!(report.renames[0].source, source.join("file.txt").to_string_lossy());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_walks_entries_in_name_order() {
+        let (source, dest) = test_dirs("order");
+        std::fs::create_dir(source.join("b")).unwrap();
+        for name in ["c.txt", "a.txt", "b/z.txt", "b/y.txt"] {
+            std::fs::write(source.join(name), name).unwrap();
+        }
+        let walk = |order| {
+            let config = CopyConfig {
+                order,
+                ..CopyConfig::new("", "", source.clone(), dest.clone())
+            };
+            let mut copied = vec![];
+            let checkpoint = super::Checkpoint::load(&config).unwrap();
+            walk_tree(&config, &checkpoint, &mut |_, from, _| {
+                copied.push(from.strip_prefix(&source).unwrap().to_string_lossy().into_owned());
+                Ok(())
+            })
+            .unwrap();
+            copied
+        };
+        assert_eq!(walk(WalkOrder::Name), ["a.txt", "c.txt", "b/y.txt", "b/z.txt"]);
+        assert_eq!(walk(WalkOrder::NameDesc), ["c.txt", "a.txt", "b/z.txt", "b/y.txt"]);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_flattens_files_into_the_destination() {
+        let (source, dest) = test_dirs("flatten");
+        for dir in ["a", "b"] {
+            std::fs::create_dir_all(source.join(dir).join("c")).unwrap();
+            std::fs::write(source.join(dir).join("photo.jpg"), dir).unwrap();
+        }
+        std::fs::write(source.join("a").join("c").join("d.jpg"), "d").unwrap();
+        let config = CopyConfig {
+            flatten: true,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!((report.files_copied, report.directories_created), (3, 0));
+        let mut copied: Vec<_> = std::fs::read_dir(&dest).unwrap().map(|e| e.unwrap().file_name()).collect();
+        copied.sort();
+        assert_eq!(copied, ["d.jpg", "photo (1).jpg", "photo.jpg"]);
+        assert_eq!(std::fs::read_to_string(dest.join("photo.jpg")).unwrap(), "a");
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_copies_a_directory_next_to_a_file_in_its_way() {
+        let (source, dest) = test_dirs("file-in-the-way");
+        std::fs::create_dir(source.join("photos")).unwrap();
+        std::fs::write(source.join("photos").join("a.jpg"), "a").unwrap();
+        // left by a flattened run
+        std::fs::write(dest.join("photos"), "not a directory").unwrap();
+        let config = CopyConfig {
+            skip_dirs_blocked_by_files: true,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!(report.files_copied, 0);
+        assert_eq!(report.failures[0].path, source.join("photos").to_str().unwrap());
+        assert_eq!(report.failures[0].errno, Some(17));
+
+        let report = super::walk_and_copy(&CopyConfig::new("", "", source.clone(), dest.clone()), None, None, None).unwrap();
+        assert!(report.failures.is_empty());
+        assert_eq!(std::fs::read_to_string(dest.join("photos (1)").join("a.jpg")).unwrap(), "a");
+        assert_eq!(std::fs::read_to_string(dest.join("photos")).unwrap(), "not a directory");
+        assert_eq!(report.renames[0].destination, dest.join("photos (1)").to_str().unwrap());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_keeps_the_name_for_the_newest_of_colliding_files() {
+        let (source, dest) = test_dirs("keep-newest");
+        for (name, mtime) in [("a:b.txt", 1_000), ("a?b.txt", 2_000), ("a|b.txt", 3_000)] {
+            std::fs::write(source.join(name), name).unwrap();
+            filetime::set_file_mtime(source.join(name), FileTime::from_unix_time(mtime, 0)).unwrap();
+        }
+        let config = CopyConfig {
+            on_collision: OnCollision::KeepNewest,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!(report.files_copied, 3);
+        assert_eq!(std::fs::read_to_string(dest.join("a_b.txt")).unwrap(), "a|b.txt");
+        assert_eq!(std::fs::read_to_string(dest.join("a_b (1).txt")).unwrap(), "a?b.txt");
+        assert_eq!(std::fs::read_to_string(dest.join("a_b (2).txt")).unwrap(), "a:b.txt");
+        let collisions: Vec<_> = report.renames.iter().map(|r| (r.source.as_str(), r.collision.clone())).collect();
+        let newest = source.join("a|b.txt").to_str().unwrap().to_string();
+        assert_eq!(
+            collisions,
+            [
+                (newest.as_str(), None),
+                (source.join("a?b.txt").to_str().unwrap(), Some(format!("older than '{}'", newest))),
+                (source.join("a:b.txt").to_str().unwrap(), Some(format!("older than '{}'", newest))),
+            ]
+        );
+
+        std::fs::remove_dir_all(&dest).unwrap();
+        let config = CopyConfig {
+            on_collision: OnCollision::NewestOnly,
+            ..config
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!((report.files_copied, report.files_skipped_by_collision), (1, 2));
+        assert_eq!(std::fs::read_to_string(dest.join("a_b.txt")).unwrap(), "a|b.txt");
+        assert_eq!(report.renames[1].destination, dest.join("a_b.txt").to_str().unwrap());
+        assert_eq!(report.renames[1].collision, Some(format!("skipped, older than '{}'", newest)));
+        assert_eq!(std::fs::read_dir(&dest).unwrap().count(), 1);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_routes_files_into_folders_by_extension() {
+        let (source, dest) = test_dirs("route");
+        for dir in ["a", "b"] {
+            std::fs::create_dir(source.join(dir)).unwrap();
+            std::fs::write(source.join(dir).join("photo.JPG"), dir).unwrap();
+        }
+        std::fs::write(source.join("a").join("clip.mov"), "").unwrap();
+        std::fs::write(source.join("README"), "").unwrap();
+        let config = CopyConfig {
+            route_by_extension: true,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!((report.files_copied, report.directories_created), (4, 0));
+        let list = |dir: &str| {
+            let mut names: Vec<_> = std::fs::read_dir(dest.join(dir)).unwrap().map(|e| e.unwrap().file_name()).collect();
+            names.sort();
+            names
+        };
+        assert_eq!(list(""), ["images", "other", "videos"]);
+        assert_eq!(list("images"), ["photo (1).JPG", "photo.JPG"]);
+        assert_eq!(list("videos"), ["clip.mov"]);
+        assert_eq!(list("other"), ["README"]);
+        assert_eq!(std::fs::read_to_string(dest.join("images").join("photo.JPG")).unwrap(), "a");
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_hidden_files_and_directories() {
+        let (source, dest) = test_dirs("skip-hidden");
+        std::fs::create_dir_all(source.join(".config").join("app")).unwrap();
+        std::fs::create_dir(source.join("docs")).unwrap();
+        std::fs::write(source.join(".config").join("app").join("settings"), "").unwrap();
+        std::fs::write(source.join(".bashrc"), "").unwrap();
+        std::fs::write(source.join("docs").join(".hidden.txt"), "").unwrap();
+        std::fs::write(source.join("docs").join("visible.txt"), "").unwrap();
+        let config = CopyConfig {
+            skip_hidden: true,
+            ..CopyConfig::new("", "", source.clone(), dest)
+        };
+        let checkpoint = super::Checkpoint::load(&config).unwrap();
+        let mut copied = vec![];
+        let report = walk_tree(&config, &checkpoint, &mut |_, from, _| {
+            copied.push(from);
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(copied, [source.join("docs").join("visible.txt")]);
+        assert_eq!(report.paths_skipped_hidden, 3);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_stops_descending_at_max_depth() {
+        let (source, dest) = test_dirs("max-depth");
+        std::fs::create_dir_all(source.join("a").join("b")).unwrap();
+        std::fs::write(source.join("top.txt"), "top").unwrap();
+        std::fs::write(source.join("a").join("middle.txt"), "middle").unwrap();
+        std::fs::write(source.join("a").join("b").join("deep.txt"), "deep").unwrap();
+        let config = CopyConfig {
+            max_depth: Some(2),
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let report = super::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!((report.files_copied, report.directories_skipped_by_depth), (2, 1));
+        assert!(dest.join("a").join("middle.txt").exists());
+        assert!(!dest.join("a").join("b").exists());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_walks_depth_or_breadth_first() {
+        let (source, dest) = test_dirs("traversal");
+        std::fs::create_dir_all(source.join("a").join("x")).unwrap();
+        std::fs::create_dir(source.join("b")).unwrap();
+        for name in ["a/x/1.txt", "a/2.txt", "b/3.txt"] {
+            std::fs::write(source.join(name), name).unwrap();
+        }
+        let walk = |traversal| {
+            let config = CopyConfig {
+                traversal,
+                ..CopyConfig::new("", "", source.clone(), dest.clone())
+            };
+            let mut copied = vec![];
+            let checkpoint = super::Checkpoint::load(&config).unwrap();
+            walk_tree(&config, &checkpoint, &mut |_, from, _| {
+                copied.push(from.strip_prefix(&source).unwrap().to_string_lossy().into_owned());
+                Ok(())
+            })
+            .unwrap();
+            copied
+        };
+        assert_eq!(walk(Traversal::DepthFirst), ["a/2.txt", "a/x/1.txt", "b/3.txt"]);
+        assert_eq!(walk(Traversal::BreadthFirst), ["a/2.txt", "b/3.txt", "a/x/1.txt"]);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_does_not_loop_on_symlinks_to_ancestors() {
+        let (source, dest) = test_dirs("symlinks");
+        std::fs::create_dir(source.join("a")).unwrap();
+        std::fs::write(source.join("a").join("1.txt"), "1").unwrap();
+        std::os::unix::fs::symlink(&source, source.join("a").join("up")).unwrap();
+        let walk = |symlinks| {
+            let config = CopyConfig {
+                symlinks,
+                ..CopyConfig::new("", "", source.clone(), dest.clone())
+            };
+            let checkpoint = super::Checkpoint::load(&config).unwrap();
+            walk_tree(&config, &checkpoint, &mut |_, _, _| Ok(())).unwrap()
+        };
+        let skipped = walk(SymlinkMode::Dereference);
+        assert_eq!(skipped.skipped_symlinks.len(), 1);
+        assert!(skipped.failures.is_empty());
+        let followed = walk(SymlinkMode::Follow);
+        assert!(followed.skipped_symlinks.is_empty());
+        assert_eq!(followed.failures.len(), 1);
+        assert_eq!(followed.failures[0].path, source.join("a").join("up").to_string_lossy());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_or_dereferences_file_symlinks() {
+        let (source, dest) = test_dirs("file-symlinks");
+        std::fs::write(source.join("target.txt"), "target").unwrap();
+        std::os::unix::fs::symlink(source.join("target.txt"), source.join("link.txt")).unwrap();
+        std::os::unix::fs::symlink(source.join("missing.txt"), source.join("dangling.txt")).unwrap();
+        let walk = |symlinks| {
+            let config = CopyConfig {
+                symlinks,
+                ..CopyConfig::new("", "", source.clone(), dest.clone())
+            };
+            let checkpoint = super::Checkpoint::load(&config).unwrap();
+            let mut copied = vec![];
+            let report = walk_tree(&config, &checkpoint, &mut |_, from, _| {
+                copied.push(from.file_name().unwrap().to_string_lossy().into_owned());
+                Ok(())
+            })
+            .unwrap();
+            (report, copied)
+        };
+        let (skipped, copied) = walk(SymlinkMode::Skip);
+        assert_eq!(copied, ["target.txt"]);
+        assert_eq!(skipped.skipped_symlinks.len(), 2);
+        assert_eq!(skipped.skipped_symlinks[1].target, source.join("target.txt").to_string_lossy());
+
+        let (dereferenced, copied) = walk(SymlinkMode::Dereference);
+        assert_eq!(copied, ["link.txt", "target.txt"]);
+        assert_eq!(dereferenced.failures.len(), 1);
+        assert_eq!(dereferenced.failures[0].reason, "dangling symlink");
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_paths_behind_too_many_symlinks() {
+        let (source, dest) = test_dirs("eloop");
+        std::os::unix::fs::symlink(source.join("b"), source.join("a")).unwrap();
+        std::os::unix::fs::symlink(source.join("a"), source.join("b")).unwrap();
+        let config = CopyConfig::new("", "", source.clone(), dest.clone());
+        let mut report = CopyReport::default();
+        assert!(super::copy_file(&config, &mut report, &source.join("a"), &dest.join("a"), &drop).unwrap().is_none());
+        assert_eq!(report.failures[0].errno, Some(40));
+        let listed = super::read_entries(&config, &mut report, &source.join("b"), &dest.join("b")).unwrap();
+        assert!(listed.0.is_empty() && !listed.1);
+        assert_eq!(report.failures.len(), 2);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_or_stops_on_permission_denied() {
+        use std::os::unix::fs::PermissionsExt;
+        let (source, dest) = test_dirs("eacces");
+        std::fs::write(source.join("secret.txt"), "secret").unwrap();
+        std::fs::create_dir(source.join("private")).unwrap();
+        std::fs::set_permissions(source.join("secret.txt"), std::fs::Permissions::from_mode(0o000)).unwrap();
+        std::fs::set_permissions(source.join("private"), std::fs::Permissions::from_mode(0o000)).unwrap();
+        // root reads everything anyway
+        if std::fs::read(source.join("secret.txt")).is_err() {
+            let config = CopyConfig::new("", "", source.clone(), dest.clone());
+            let mut report = CopyReport::default();
+            let (from, to) = (source.join("secret.txt"), dest.join("secret.txt"));
+            assert!(super::copy_file(&config, &mut report, &from, &to, &drop).unwrap().is_none());
+            assert!(super::read_entries(&config, &mut report, &source.join("private"), &dest).unwrap().0.is_empty());
+            assert_eq!(report.failures.iter().map(|f| f.errno).collect::<Vec<_>>(), [Some(13), Some(13)]);
+
+            let config = CopyConfig {
+                fail_on_permission_denied: true,
+                ..config
+            };
+            assert!(super::copy_file(&config, &mut report, &from, &to, &drop).is_err());
+            assert!(super::read_entries(&config, &mut report, &source.join("private"), &dest).is_err());
+        }
+        std::fs::set_permissions(source.join("private"), std::fs::Permissions::from_mode(0o755)).unwrap();
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_copies_hard_linked_files_once() {
+        let (source, dest) = test_dirs("hardlinks");
+        std::fs::write(source.join("a.txt"), "linked").unwrap();
+        std::fs::hard_link(source.join("a.txt"), source.join("b.txt")).unwrap();
+        std::fs::write(source.join("c.txt"), "single").unwrap();
+        let config = CopyConfig {
+            dedup_hardlinks: true,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let checkpoint = super::Checkpoint::load(&config).unwrap();
+        let mut copied = vec![];
+        let report = walk_tree(&config, &checkpoint, &mut |_, from, _| {
+            copied.push(from.file_name().unwrap().to_string_lossy().into_owned());
+            Ok(())
+        })
+        .unwrap();
+        assert_eq!(copied, ["a.txt", "c.txt"]);
+        assert_eq!(report.deduplicated_hardlinks.len(), 1);
+        assert_eq!(report.deduplicated_hardlinks[0].path, source.join("b.txt").to_string_lossy());
+        assert_eq!(report.deduplicated_hardlinks[0].copied_as, dest.join("a.txt").to_string_lossy());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_verifies_copies_by_size_or_sha256() {
+        let (source, dest) = test_dirs("verify");
+        let (from, to) = (source.join("a.txt"), dest.join("a.txt"));
+        std::fs::write(&from, "content").unwrap();
+        let verify = |verify, written: &str| {
+            let config = CopyConfig {
+                verify,
+                ..Default::default()
+            };
+            std::fs::write(&to, written).unwrap();
+            super::verify_copy(&config, &from, &to, Some(&Sha256::digest(b"content")))
+        };
+        assert_eq!(verify(Verify::Size, "content"), None);
+        assert_eq!(verify(Verify::Size, "conte"), Some("is 5 bytes instead of 7".to_string()));
+        assert_eq!(verify(Verify::Size, "CONTENT"), None);
+        assert_eq!(verify(Verify::Sha256, "content"), None);
+        assert_eq!(verify(Verify::Sha256, "CONTENT"), Some("has a different SHA-256".to_string()));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_streams_in_chunks_until_interrupted() {
+        let config = CopyConfig {
+            buffer_size: 4,
+            ..Default::default()
+        };
+        let chunks = std::cell::RefCell::new(vec![]);
+        let progressed = |bytes| chunks.borrow_mut().push(bytes);
+        let mut dest = vec![];
+        let copied = super::stream(&config, &mut &b"0123456789"[..], &mut dest, &progressed).unwrap();
+        assert_eq!((copied, dest.as_slice()), (10, &b"0123456789"[..]));
+        assert_eq!(*chunks.borrow(), [4, 4, 2]);
+
+        config.interrupt();
+        let error = super::stream(&config, &mut &b"0123456789"[..], &mut vec![], &progressed).unwrap_err();
+        assert_eq!(error.kind(), std::io::ErrorKind::Interrupted);
+    }
+
+    #[test]
+    fn it_preserves_permissions_when_asked() {
+        use std::os::unix::fs::PermissionsExt;
+        let (source, dest) = test_dirs("permissions");
+        std::fs::write(source.join("run.sh"), "#!/bin/sh\n").unwrap();
+        std::fs::set_permissions(source.join("run.sh"), std::fs::Permissions::from_mode(0o750)).unwrap();
+        let config = CopyConfig {
+            preserve_permissions: true,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        super::walk_and_copy(&config, None, None, None).unwrap();
+        let mode = std::fs::metadata(dest.join("run.sh")).unwrap().permissions().mode();
+        assert_eq!(mode & 0o777, 0o750);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_preserves_extended_attributes_the_destination_takes() {
+        let (source, dest) = test_dirs("xattrs");
+        std::fs::write(source.join("tagged.txt"), "tagged").unwrap();
+        // not every filesystem the tests run on has user attributes
+        if xattr::set(source.join("tagged.txt"), "user.xdg.tags", b"red").is_ok() {
+            let config = CopyConfig {
+                preserve_xattrs: true,
+                ..CopyConfig::new("", "", source.clone(), dest.clone())
+            };
+            super::walk_and_copy(&config, None, None, None).unwrap();
+            assert_eq!(xattr::get(dest.join("tagged.txt"), "user.xdg.tags").unwrap(), Some(b"red".to_vec()));
+        }
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_clamps_mtime_to_exfat_range() {
+        let before_1980 = FileTime::from_unix_time(0, 0);
+        let in_range = FileTime::from_unix_time(1_673_827_200, 500);
+        let after_2107 = FileTime::from_unix_time(5_000_000_000, 0);
+        assert_eq!(super::clamp_to_exfat_range(before_1980), FileTime::from_unix_time(super::EXFAT_MIN_MTIME, 0));
+        assert_eq!(super::clamp_to_exfat_range(in_range), in_range);
+        assert_eq!(super::clamp_to_exfat_range(after_2107), FileTime::from_unix_time(super::EXFAT_MAX_MTIME, 0));
+    }
+
+    #[test]
+    fn it_overwrites_existing_destinations_as_configured() {
+        let dir = std::env::temp_dir().join(format!("apfs-copier-update-{}", std::process::id()));
+        std::fs::create_dir_all(&dir).unwrap();
+        let from = dir.join("from.txt");
+        let to = dir.join("to.txt");
+        std::fs::write(&from, "complete").unwrap();
+        filetime::set_file_mtime(&from, FileTime::from_unix_time(1_673_827_200, 0)).unwrap();
+
+        let skip_existing = CopyConfig::default();
+        let update = CopyConfig {
+            overwrite: Overwrite::IfDifferent,
+            ..Default::default()
+        };
+        let always = CopyConfig {
+            overwrite: Overwrite::Always,
+            ..Default::default()
+        };
+        let if_newer = CopyConfig {
+            overwrite: Overwrite::IfNewer,
+            ..Default::default()
+        };
+        assert!(!super::is_up_to_date(&update, &from, &to));
+
+        std::fs::write(&to, "compl").unwrap();
+        assert!(super::is_up_to_date(&skip_existing, &from, &to));
+        assert!(!super::is_up_to_date(&update, &from, &to));
+
+        std::fs::write(&to, "complete").unwrap();
+        assert!(!super::is_up_to_date(&update, &from, &to));
+        filetime::set_file_mtime(&to, FileTime::from_unix_time(1_673_827_201, 0)).unwrap();
+        assert!(super::is_up_to_date(&update, &from, &to));
+        assert!(!super::is_up_to_date(&always, &from, &to));
+        assert!(super::is_up_to_date(&if_newer, &from, &to));
+
+        // a destination newer than its source is only copied over when they differ
+        filetime::set_file_mtime(&to, FileTime::from_unix_time(1_673_900_000, 0)).unwrap();
+        assert!(super::is_up_to_date(&if_newer, &from, &to));
+        assert!(!super::is_up_to_date(&update, &from, &to));
+        filetime::set_file_mtime(&to, FileTime::from_unix_time(1_673_000_000, 0)).unwrap();
+        assert!(!super::is_up_to_date(&if_newer, &from, &to));
+
+        std::fs::remove_dir_all(&dir).unwrap();
+    }
+}
@@ -0,0 +1,96 @@
+use anyhow::{bail, Result};
+use log::{debug, info, warn};
+use std::{
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{CopyConfig, TargetFs};
+
+/// The mount types `target_fs` shows up as, and whether a `fuseblk` mount can be it,
+/// `exfat-fuse` and `ntfs-3g` mounts show up as plain `fuseblk`
+fn fs_types(target_fs: TargetFs) -> (&'static str, &'static [&'static str], bool) {
+    match target_fs {
+        TargetFs::Exfat => ("ExFAT", &["exfat", "vfat", "msdos"], true),
+        TargetFs::Ntfs => ("NTFS", &["ntfs", "ntfs3"], true),
+        TargetFs::Fat32 => ("FAT32", &["vfat", "msdos"], false),
+        TargetFs::None => ("any filesystem", &[], false),
+    }
+}
+
+/// Warns, or fails with `strict_fs`, when `dest` isn't on the `target_fs` the names are rewritten for,
+/// where renaming them only gets in the way. With `TargetFs::None` nothing is rewritten, so anything goes.
+pub(crate) fn check_destination_fs(config: &CopyConfig) -> Result<()> {
+    if config.target_fs == TargetFs::None {
+        return Ok(());
+    }
+    let (Ok(mounts), Ok(dest)) = (fs::read_to_string("/proc/mounts"), fs::canonicalize(&config.dest)) else {
+        debug!("can't tell the filesystem of '{}', not checking it", config.dest.display());
+        return Ok(());
+    };
+    let (label, types, on_fuse) = fs_types(config.target_fs);
+    match fs_type_of(&mounts, &dest) {
+        Some(fs_type) if types.contains(&fs_type.as_str()) => (),
+        Some(fs_type) if on_fuse && fs_type == "fuseblk" => info!("'{}' is a FUSE mount, assuming it's {}", dest.display(), label),
+        Some(fs_type) if config.strict_fs => bail!("'{}' is on {}, not {}", dest.display(), fs_type, label),
+        Some(fs_type) => warn!("'{}' is on {}, not {}, names are still rewritten for {}", dest.display(), fs_type, label, label),
+        None => debug!("'{}' isn't under any mount point", dest.display()),
+    }
+    Ok(())
+}
+
+/// The type of the mount in `/proc/mounts`-style `mounts` that `path` is on
+fn fs_type_of(mounts: &str, path: &Path) -> Option<String> {
+    mounts
+        .lines()
+        .filter_map(|line| {
+            let mut fields = line.split(' ');
+            let (_, mount_point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+            Some((unescape(mount_point), fs_type))
+        })
+        // the last of the longest matching mount points is the one on top
+        .filter(|(mount_point, _)| path.starts_with(mount_point))
+        .max_by_key(|(mount_point, _)| mount_point.components().count())
+        .map(|(_, fs_type)| fs_type.to_string())
+}
+
+/// Spaces, tabs, newlines and backslashes in mount points are written as octal escapes like `\040`
+pub(crate) fn unescape(field: &str) -> PathBuf {
+    let mut unescaped = String::new();
+    let mut rest = field;
+    while let Some(i) = rest.find('\\') {
+        unescaped.push_str(&rest[..i]);
+        match rest.get(i + 1..i + 4).and_then(|octal| u8::from_str_radix(octal, 8).ok()) {
+            Some(byte) => {
+                unescaped.push(char::from(byte));
+                rest = &rest[i + 4..];
+            }
+            None => {
+                unescaped.push('\\');
+                rest = &rest[i + 1..];
+            }
+        }
+    }
+    unescaped.push_str(rest);
+    PathBuf::from(unescaped)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::path::Path;
+
+    #[test]
+    fn it_finds_the_filesystem_of_a_path() {
+        let mounts = "/dev/sda1 / ext4 rw,relatime 0 0\n\
+                      /dev/sdb1 /media/My\\040Drive exfat rw 0 0\n\
+                      /dev/sdb1 /media/My\\040Drive/sub vfat rw 0 0\n\
+                      /dev/sdc1 /media/usb fuseblk rw 0 0\n";
+        let fs_type = |path: &str| super::fs_type_of(mounts, Path::new(path));
+        assert_eq!(fs_type("/home/user").as_deref(), Some("ext4"));
+        assert_eq!(fs_type("/media/My Drive/Photos").as_deref(), Some("exfat"));
+        assert_eq!(fs_type("/media/My Drive/sub/x").as_deref(), Some("vfat"));
+        assert_eq!(fs_type("/media/usbstick").as_deref(), Some("ext4"));
+        assert_eq!(fs_type("/media/usb").as_deref(), Some("fuseblk"));
+        assert_eq!(fs_type("relative"), None);
+    }
+}
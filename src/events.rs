@@ -0,0 +1,226 @@
+use anyhow::{Context, Result};
+use log::warn;
+use serde::Serialize;
+use std::{
+    fs::{File, OpenOptions},
+    io::Write,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
+
+use crate::CopyConfig;
+
+/// Bump on any incompatible change to the lines of `events_jsonl`, adding an event or a field isn't one
+const SCHEMA_VERSION: u32 = 1;
+
+/// The `events_jsonl` file of the copy running now, written by every worker and by whoever remounts
+static EVENTS: Mutex<Option<EventLog>> = Mutex::new(None);
+
+struct EventLog {
+    path: PathBuf,
+    file: File,
+    /// Only the first failed write is warned about
+    failed: bool,
+}
+
+/// One line of `events_jsonl`, written as it happens:
+///
+/// ```text
+/// {"schema_version":1,"time":1728912345.25,"event":"copied","path":"/mnt/src/a.jpg","destination":"/dst/a.jpg","size":1024,"duration_secs":0.5}
+/// {"schema_version":1,"time":1728912345.3,"event":"skipped","path":"/mnt/src/.DS_Store","reason":"excluded"}
+/// {"schema_version":1,"time":1728912346.0,"event":"remount","remounts":1}
+/// {"schema_version":1,"time":1728912346.1,"event":"error","path":"/mnt/src/b.jpg","errno":5,"reason":"Input/output error (os error 5)"}
+/// ```
+///
+/// `time` is in seconds since the Unix epoch, paths are the full source and destination paths.
+/// An `error` has no `errno` when it didn't come from the system.
+#[derive(Serialize)]
+#[serde(tag = "event", rename_all = "snake_case")]
+pub(crate) enum Event<'a> {
+    Copied {
+        path: String,
+        destination: String,
+        size: u64,
+        duration_secs: f64,
+    },
+    Skipped {
+        path: String,
+        reason: Skip,
+    },
+    /// The volume was remounted, `remounts` is how many times so far
+    Remount { remounts: u64 },
+    Error {
+        path: String,
+        #[serde(skip_serializing_if = "Option::is_none")]
+        errno: Option<i32>,
+        reason: &'a str,
+    },
+}
+
+/// Why a path was left alone, failures are `error` events instead
+#[derive(Clone, Copy, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum Skip {
+    /// The destination passes for a finished copy under `overwrite`
+    AlreadyCopied,
+    /// Completed by an earlier run according to the checkpoint
+    Checkpoint,
+    /// Copied by an earlier run according to `since_manifest`, and not changed since
+    Unchanged,
+    Hidden,
+    /// Left out by the include and exclude patterns
+    Excluded,
+    Symlink,
+    /// A hard link to a file copied already
+    Hardlink,
+    Size,
+    Age,
+    /// A directory at `max_depth`
+    Depth,
+    /// An older file of the same directory took its destination, with `OnCollision::NewestOnly`
+    Collision,
+}
+
+#[derive(Serialize)]
+struct Line<'a> {
+    schema_version: u32,
+    time: f64,
+    #[serde(flatten)]
+    event: Event<'a>,
+}
+
+impl<'a> Event<'a> {
+    pub(crate) fn copied(path: &Path, destination: &Path, size: u64, duration: Duration) -> Event<'a> {
+        Event::Copied {
+            path: path.to_string_lossy().into_owned(),
+            destination: destination.to_string_lossy().into_owned(),
+            size,
+            duration_secs: duration.as_secs_f64(),
+        }
+    }
+
+    pub(crate) fn skipped(path: &Path, reason: Skip) -> Event<'a> {
+        Event::Skipped {
+            path: path.to_string_lossy().into_owned(),
+            reason,
+        }
+    }
+
+    pub(crate) fn error(path: &Path, errno: Option<i32>, reason: &'a str) -> Event<'a> {
+        Event::Error {
+            path: path.to_string_lossy().into_owned(),
+            errno,
+            reason,
+        }
+    }
+}
+
+/// Writes to `events_jsonl` until dropped, the copy it was opened for is over then
+pub(crate) struct EventsOpen;
+
+impl Drop for EventsOpen {
+    fn drop(&mut self) {
+        *EVENTS.lock().unwrap() = None;
+    }
+}
+
+/// A dry run does nothing worth an event, like with the manifest
+pub(crate) fn open(config: &CopyConfig) -> Result<Option<EventsOpen>> {
+    let Some(path) = config.events_jsonl.as_ref().filter(|_| !config.dry_run) else {
+        return Ok(None);
+    };
+    let file = OpenOptions::new()
+        .create(true)
+        .append(true)
+        .open(path)
+        .with_context(|| format!("can't open events file '{}'", path.display()))?;
+    *EVENTS.lock().unwrap() = Some(EventLog {
+        path: path.clone(),
+        file,
+        failed: false,
+    });
+    Ok(Some(EventsOpen))
+}
+
+/// Appends `event` when `config` asks for events. A failed write doesn't stop the copy, the report still has it all.
+pub(crate) fn emit(config: &CopyConfig, event: Event) {
+    if config.events_jsonl.is_none() {
+        return;
+    }
+    let mut events = EVENTS.lock().unwrap();
+    let Some(log) = events.as_mut() else {
+        return;
+    };
+    let line = Line {
+        schema_version: SCHEMA_VERSION,
+        time: SystemTime::now().duration_since(UNIX_EPOCH).unwrap_or_default().as_secs_f64(),
+        event,
+    };
+    let mut bytes = serde_json::to_vec(&line).expect("events serialize");
+    bytes.push(b'\n');
+    if let Err(e) = log.file.write_all(&bytes) {
+        if !log.failed {
+            warn!("can't write events file '{}', leaving out events: {}", log.path.display(), e);
+            log.failed = true;
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig};
+    use serde_json::Value;
+
+    #[test]
+    fn it_writes_an_event_line_per_copied_skipped_and_failed_file() {
+        let (source, dest) = test_dirs("events");
+        let events = source.parent().unwrap().join("events.jsonl");
+        std::fs::write(source.join("a.txt"), "abc").unwrap();
+        std::fs::write(source.join(".hidden"), "").unwrap();
+        std::fs::write(source.join("b.tmp"), "").unwrap();
+        let config = CopyConfig {
+            events_jsonl: Some(events.clone()),
+            skip_hidden: true,
+            exclude: vec!["*.tmp".to_string()],
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let open = super::open(&config).unwrap();
+        crate::copy::walk_and_copy(&config, None, None, None).unwrap();
+        crate::copy::walk_and_copy(&config, None, None, None).unwrap();
+        let mut report = crate::CopyReport::default();
+        report.record_failure(&config, &source.join("c.txt"), Some(5), "Input/output error");
+        drop(open);
+        // closed, nothing more is written
+        report.record_failure(&config, &source.join("d.txt"), None, "");
+        let lines: Vec<Value> = std::fs::read_to_string(&events)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert!(lines.iter().all(|line| line["schema_version"] == 1 && line["time"].as_f64().unwrap() > 0.0));
+        let summary: Vec<_> = lines
+            .iter()
+            .map(|line| {
+                let path = line["path"].as_str().unwrap();
+                let detail = line.get("reason").or(line.get("size")).unwrap().to_string();
+                (line["event"].as_str().unwrap(), path.strip_prefix(source.to_str().unwrap()).unwrap().to_string(), detail)
+            })
+            .collect();
+        let skipped = |path: &str, reason: &str| ("skipped", path.to_string(), format!("\"{}\"", reason));
+        let mut expected = vec![
+            ("copied", "/a.txt".to_string(), "3".to_string()),
+            skipped("/.hidden", "hidden"),
+            skipped("/b.tmp", "excluded"),
+        ];
+        expected.sort();
+        let (mut first_run, rest) = (summary[..3].to_vec(), &summary[3..]);
+        first_run.sort();
+        assert_eq!(first_run, expected);
+        assert!(rest.contains(&skipped("/a.txt", "already_copied")));
+        assert_eq!(rest.last().unwrap(), &("error", "/c.txt".to_string(), "\"Input/output error\"".to_string()));
+        assert_eq!(lines[..3].iter().find(|line| line["event"] == "copied").unwrap()["destination"], dest.join("a.txt").to_str().unwrap());
+        assert_eq!(lines.last().unwrap()["errno"], 5);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,138 @@
+use anyhow::{Context, Result};
+use log::info;
+use std::{
+    collections::HashMap,
+    ffi::OsStr,
+    fs::{self, OpenOptions},
+    io::{ErrorKind, Write},
+    os::unix::ffi::OsStrExt,
+    path::{Path, PathBuf},
+    sync::{Mutex, OnceLock},
+};
+
+use crate::CopyConfig;
+
+/// How many times every path has aborted the connection, in this run and the ones before it
+static FAILED_CONNECTION_ABORTS: OnceLock<Mutex<HashMap<PathBuf, u32>>> = OnceLock::new();
+
+fn failed_connection_aborts() -> &'static Mutex<HashMap<PathBuf, u32>> {
+    FAILED_CONNECTION_ABORTS.get_or_init(|| Mutex::new(HashMap::new()))
+}
+
+/// The state file has a line for every abort, so a path listed `max_path_aborts` times is skipped for good
+pub(crate) fn load_failures(state_file: &Path) -> Result<()> {
+    let content = match fs::read(state_file) {
+        Ok(content) => content,
+        Err(e) if e.kind() == ErrorKind::NotFound => return Ok(()),
+        Err(e) => return Err(e).with_context(|| format!("can't read state file '{}'", state_file.display())),
+    };
+    let mut aborts = failed_connection_aborts().lock().unwrap();
+    for line in content.split(|b| *b == b'\n').filter(|l| !l.is_empty()) {
+        *aborts.entry(PathBuf::from(OsStr::from_bytes(line))).or_default() += 1;
+    }
+    info!("loaded connection aborts of {} paths from '{}'", aborts.len(), state_file.display());
+    Ok(())
+}
+
+/// Counts another abort of `path` and returns how many it has had
+pub(crate) fn remember_failure(config: &CopyConfig, path: &Path) -> Result<u32> {
+    // holding the map lock also keeps lines from concurrent workers apart in the state file
+    let mut aborts = failed_connection_aborts().lock().unwrap();
+    if let Some(state_file) = &config.state_file {
+        // appending keeps every failure on disk even if the process is killed right after
+        let mut file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(state_file)
+            .with_context(|| format!("can't open state file '{}'", state_file.display()))?;
+        // raw bytes, so names that aren't valid UTF-8 survive the round trip
+        let mut line = path.as_os_str().as_bytes().to_vec();
+        line.push(b'\n');
+        file.write_all(&line)
+            .with_context(|| format!("can't write state file '{}'", state_file.display()))?;
+    }
+    let count = aborts.entry(path.to_path_buf()).or_default();
+    *count += 1;
+    Ok(*count)
+}
+
+/// Lets `path` be tried again in this run, the state file still has its aborts for later runs
+pub(crate) fn forgive_failure(path: &Path) {
+    failed_connection_aborts().lock().unwrap().remove(path);
+}
+
+/// A path that aborted `max_path_aborts` times is skipped along with its whole subtree
+pub(crate) fn is_failure(config: &CopyConfig, path: &Path) -> bool {
+    let aborts = failed_connection_aborts().lock().unwrap();
+    path.ancestors().any(|p| aborts.get(p).is_some_and(|n| *n >= config.max_path_aborts))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CopyConfig;
+    use std::{ffi::OsStr, os::unix::ffi::OsStrExt, path::Path};
+
+    #[test]
+    fn it_persists_failures_in_state_file() {
+        let state_file = std::env::temp_dir().join(format!("apfs-copier-state-{}", std::process::id()));
+        std::fs::write(&state_file, "/state-test/loaded\n").unwrap();
+        super::load_failures(&state_file).unwrap();
+        let config = CopyConfig {
+            state_file: Some(state_file.clone()),
+            max_path_aborts: 1,
+            ..Default::default()
+        };
+        assert!(super::is_failure(&config, Path::new("/state-test/loaded")));
+
+        super::remember_failure(&config, Path::new("/state-test/remembered")).unwrap();
+        assert!(super::is_failure(&config, Path::new("/state-test/remembered")));
+        let content = std::fs::read_to_string(&state_file).unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+        assert_eq!(content, "/state-test/loaded\n/state-test/remembered\n");
+    }
+
+    #[test]
+    fn it_keeps_non_utf8_paths_in_state_file() {
+        let state_file = std::env::temp_dir().join(format!("apfs-copier-state-utf8-{}", std::process::id()));
+        let config = CopyConfig {
+            state_file: Some(state_file.clone()),
+            max_path_aborts: 1,
+            ..Default::default()
+        };
+        let failed = Path::new("/utf8-test").join(OsStr::from_bytes(b"caf\xe9:menu.txt"));
+        super::remember_failure(&config, &failed).unwrap();
+        assert!(super::is_failure(&config, &failed.join("child")));
+        let content = std::fs::read(&state_file).unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+        assert_eq!(content, b"/utf8-test/caf\xe9:menu.txt\n");
+    }
+
+    #[test]
+    fn it_skips_subtree_of_remembered_failure() {
+        let config = CopyConfig {
+            max_path_aborts: 1,
+            ..Default::default()
+        };
+        super::remember_failure(&config, Path::new("/subtree-test/a")).unwrap();
+        assert!(super::is_failure(&config, Path::new("/subtree-test/a")));
+        assert!(super::is_failure(&config, Path::new("/subtree-test/a/b")));
+        assert!(super::is_failure(&config, Path::new("/subtree-test/a/b/c.txt")));
+        assert!(!super::is_failure(&config, Path::new("/subtree-test/ab")));
+        assert!(!super::is_failure(&config, Path::new("/subtree-test")));
+    }
+
+    #[test]
+    fn it_skips_a_path_only_after_max_aborts() {
+        let state_file = std::env::temp_dir().join(format!("apfs-copier-state-count-{}", std::process::id()));
+        std::fs::write(&state_file, "/count-test/a\n/count-test/a\n/count-test/b\n").unwrap();
+        super::load_failures(&state_file).unwrap();
+        std::fs::remove_file(&state_file).unwrap();
+        let config = CopyConfig::default();
+        assert_eq!(config.max_path_aborts, 3);
+        assert!(!super::is_failure(&config, Path::new("/count-test/a")));
+        assert_eq!(super::remember_failure(&config, Path::new("/count-test/a")).unwrap(), 3);
+        assert!(super::is_failure(&config, Path::new("/count-test/a")));
+        assert_eq!(super::remember_failure(&config, Path::new("/count-test/b")).unwrap(), 2);
+        assert!(!super::is_failure(&config, Path::new("/count-test/b")));
+    }
+}
@@ -0,0 +1,211 @@
+use anyhow::{Context, Result};
+use globset::{Glob, GlobSet, GlobSetBuilder};
+use ignore::gitignore::{Gitignore, GitignoreBuilder};
+use std::{fs, path::Path, time::SystemTime};
+
+use crate::CopyConfig;
+
+/// What `skip_apple_metadata` leaves out: Finder and Spotlight state, the trash, and AppleDouble `._` sidecars
+pub const APPLE_METADATA_GLOBS: &[&str] = &["**/.DS_Store", "**/.Spotlight-V100", "**/.Trashes", "**/.fseventsd", "**/._*"];
+
+/// Compiled `--include`/`--exclude` globs, matched against source-relative paths, and the file size range
+pub(crate) struct Filters {
+    include: Option<GlobSet>,
+    exclude: GlobSet,
+    /// The `exclude_from` patterns
+    ignore: Option<Gitignore>,
+    min_size: Option<u64>,
+    max_size: Option<u64>,
+    newer_than: Option<SystemTime>,
+    skip_hidden: bool,
+}
+
+impl Filters {
+    pub(crate) fn from_config(config: &CopyConfig) -> Result<Filters> {
+        let mut exclude = config.exclude.clone();
+        if config.skip_apple_metadata {
+            exclude.extend(APPLE_METADATA_GLOBS.iter().map(|glob| glob.to_string()));
+        }
+        Ok(Filters {
+            ignore: config.exclude_from.as_deref().map(load_ignore_file).transpose()?,
+            min_size: config.min_size,
+            max_size: config.max_size,
+            newer_than: config.newer_than,
+            skip_hidden: config.skip_hidden,
+            ..Filters::new(&config.include, &exclude)?
+        })
+    }
+
+    pub(crate) fn new(include: &[String], exclude: &[String]) -> Result<Filters> {
+        Ok(Filters {
+            include: if include.is_empty() { None } else { Some(build_glob_set(include)?) },
+            exclude: build_glob_set(exclude)?,
+            ignore: None,
+            min_size: None,
+            max_size: None,
+            newer_than: None,
+            skip_hidden: false,
+        })
+    }
+
+    /// Directories are descended into unless excluded, since their children might be included
+    pub(crate) fn accepts(&self, relative: &Path, is_dir: bool) -> bool {
+        if relative.as_os_str().is_empty() {
+            return true;
+        }
+        if self.exclude.is_match(relative) {
+            return false;
+        }
+        if self.ignore.as_ref().is_some_and(|ignore| ignore.matched(relative, is_dir).is_ignore()) {
+            return false;
+        }
+        is_dir || self.include.as_ref().is_none_or(|include| include.is_match(relative))
+    }
+
+    /// The file at `path` is outside of `min_size`..=`max_size`, only looked up when there's a limit.
+    /// A file whose size can't be read is left for the copy to report.
+    pub(crate) fn skips_size(&self, path: &Path) -> bool {
+        if self.min_size.is_none() && self.max_size.is_none() {
+            return false;
+        }
+        fs::metadata(path).is_ok_and(|m| {
+            self.min_size.is_some_and(|min| m.len() < min) || self.max_size.is_some_and(|max| m.len() > max)
+        })
+    }
+
+    /// `path` is a dotfile or dot-directory left out by `skip_hidden`, its ancestors were each checked on the way down
+    pub(crate) fn skips_hidden(&self, path: &Path) -> bool {
+        self.skip_hidden && path.file_name().is_some_and(|name| name.as_encoded_bytes().starts_with(b"."))
+    }
+
+    /// The file at `path` was last modified before `newer_than`, like `skips_size`
+    pub(crate) fn skips_age(&self, path: &Path) -> bool {
+        let Some(newer_than) = self.newer_than else {
+            return false;
+        };
+        fs::metadata(path).and_then(|m| m.modified()).is_ok_and(|modified| modified < newer_than)
+    }
+}
+
+/// Patterns of a `.gitignore`, anchored at the source root
+fn load_ignore_file(path: &Path) -> Result<Gitignore> {
+    let mut builder = GitignoreBuilder::new("");
+    if let Some(e) = builder.add(path) {
+        return Err(e).with_context(|| format!("can't read exclude file '{}'", path.display()));
+    }
+    builder.build().with_context(|| format!("invalid pattern in exclude file '{}'", path.display()))
+}
+
+fn build_glob_set(patterns: &[String]) -> Result<GlobSet> {
+    let mut builder = GlobSetBuilder::new();
+    for pattern in patterns {
+        builder.add(Glob::new(pattern).with_context(|| format!("invalid glob '{}'", pattern))?);
+    }
+    Ok(builder.build()?)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Filters;
+    use crate::CopyConfig;
+    use std::path::Path;
+
+    #[test]
+    fn it_filters_by_include_and_exclude_globs() {
+        let filters = Filters::new(
+            &["*.jpg".to_string(), "*.mov".to_string()],
+            &["*/Caches/*".to_string()],
+        )
+        .unwrap();
+        assert!(filters.accepts(Path::new(""), true));
+        assert!(filters.accepts(Path::new("Photos/2022/a.jpg"), false));
+        assert!(filters.accepts(Path::new("clip.mov"), false));
+        assert!(!filters.accepts(Path::new("notes.txt"), false));
+        assert!(filters.accepts(Path::new("Documents"), true));
+        assert!(!filters.accepts(Path::new("Library/Caches/thumb.jpg"), false));
+        assert!(!filters.accepts(Path::new("Library/Caches/com.apple.Safari"), true));
+
+        let everything = Filters::new(&[], &[]).unwrap();
+        assert!(everything.accepts(Path::new("notes.txt"), false));
+    }
+
+    #[test]
+    fn it_skips_apple_metadata_when_asked() {
+        let config = CopyConfig {
+            skip_apple_metadata: true,
+            ..Default::default()
+        };
+        let filters = Filters::from_config(&config).unwrap();
+        assert!(!filters.accepts(Path::new(".DS_Store"), false));
+        assert!(!filters.accepts(Path::new("Photos/.DS_Store"), false));
+        assert!(!filters.accepts(Path::new("Photos/._IMG_0001.jpg"), false));
+        assert!(!filters.accepts(Path::new(".Spotlight-V100"), true));
+        assert!(!filters.accepts(Path::new(".fseventsd"), true));
+        assert!(filters.accepts(Path::new("Photos/IMG_0001.jpg"), false));
+        assert!(filters.accepts(Path::new("Photos/.hidden"), false));
+        assert!(Filters::from_config(&CopyConfig::default()).unwrap().accepts(Path::new(".DS_Store"), false));
+    }
+
+    #[test]
+    fn it_excludes_gitignore_patterns_from_a_file() {
+        let (source, _) = crate::test_support::test_dirs("exclude-from");
+        let exclude_from = source.parent().unwrap().join("excludes");
+        std::fs::write(&exclude_from, "# caches\n**/Caches/\n*.tmp\n!keep.tmp\n/top.log\n").unwrap();
+        let config = CopyConfig {
+            exclude_from: Some(exclude_from),
+            ..Default::default()
+        };
+        let filters = Filters::from_config(&config).unwrap();
+        assert!(!filters.accepts(Path::new("Library/Caches"), true));
+        assert!(filters.accepts(Path::new("Library/Caches"), false));
+        assert!(!filters.accepts(Path::new("a/b.tmp"), false));
+        assert!(filters.accepts(Path::new("a/keep.tmp"), false));
+        assert!(!filters.accepts(Path::new("top.log"), false));
+        assert!(filters.accepts(Path::new("sub/top.log"), false));
+        assert!(filters.accepts(Path::new("# caches"), false));
+        let missing = CopyConfig {
+            exclude_from: Some(source.join("missing")),
+            ..Default::default()
+        };
+        assert!(Filters::from_config(&missing).is_err());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_files_outside_the_size_range() {
+        let (source, _) = crate::test_support::test_dirs("size-filter");
+        for (name, len) in [("small", 1), ("medium", 10), ("large", 100)] {
+            std::fs::write(source.join(name), vec![0; len]).unwrap();
+        }
+        let config = CopyConfig {
+            min_size: Some(10),
+            max_size: Some(99),
+            ..Default::default()
+        };
+        let filters = Filters::from_config(&config).unwrap();
+        assert!(filters.skips_size(&source.join("small")));
+        assert!(!filters.skips_size(&source.join("medium")));
+        assert!(filters.skips_size(&source.join("large")));
+        assert!(!filters.skips_size(&source.join("missing")));
+        assert!(!Filters::from_config(&CopyConfig::default()).unwrap().skips_size(&source.join("small")));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_skips_files_modified_before_newer_than() {
+        let (source, _) = crate::test_support::test_dirs("age-filter");
+        for (name, mtime) in [("old", 1_000_000_000), ("new", 1_700_000_000)] {
+            std::fs::write(source.join(name), name).unwrap();
+            filetime::set_file_mtime(source.join(name), filetime::FileTime::from_unix_time(mtime, 0)).unwrap();
+        }
+        let config = CopyConfig {
+            newer_than: Some(std::time::UNIX_EPOCH + std::time::Duration::from_secs(1_600_000_000)),
+            ..Default::default()
+        };
+        let filters = Filters::from_config(&config).unwrap();
+        assert!(filters.skips_age(&source.join("old")));
+        assert!(!filters.skips_age(&source.join("new")));
+        assert!(!Filters::from_config(&CopyConfig::default()).unwrap().skips_age(&source.join("old")));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
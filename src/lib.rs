@@ -0,0 +1,218 @@
+//! Copies a directory tree from an `apfs-fuse` mount to an ExFAT volume, remounting the source
+//! when the FUSE connection aborts and renaming whatever ExFAT can't store.
+
+use anyhow::{bail, Context, Result};
+use log::{info, warn};
+use std::{
+    path::{Path, PathBuf},
+    sync::mpsc::{self, RecvTimeoutError},
+    thread,
+    time::Instant,
+};
+
+mod checkpoint;
+mod config;
+mod copy;
+mod dest_fs;
+mod events;
+mod failures;
+mod filters;
+mod list;
+mod manifest;
+mod mirror;
+mod mount;
+mod names;
+mod progress;
+mod report;
+mod routes;
+#[cfg(test)]
+mod test_support;
+mod throttle;
+mod verify;
+mod walker;
+
+pub use config::{CopyConfig, Normalize, OnCollision, Overwrite, SymlinkMode, TargetFs, Traversal, Verify, WalkOrder};
+pub use filters::APPLE_METADATA_GLOBS;
+pub use mount::{Mounter, SourceMissing, SystemMounter, DEFAULT_MOUNT_COMMAND, DEFAULT_UMOUNT_COMMAND};
+pub use report::{CopyReport, Difference, Failure, Hardlink, Missing, Rename, Symlink, Throughput, VerifyReport};
+pub use routes::{DEFAULT_ROUTES, OTHER_ROUTE};
+pub use walker::{SourceWalker, WalkEntry, WalkError};
+
+use manifest::{Manifest, SinceManifest};
+use progress::Progress;
+
+/// Mounts the source if needed and copies it over as `config` says
+pub fn copy_tree(config: &CopyConfig) -> Result<CopyReport> {
+    let config = &config.for_run()?;
+    names::check_replacement_char(config)?;
+    if config.mirror && (config.flatten || config.route_by_extension) {
+        bail!("a flattened copy can't be mirrored, every directory of the source would delete the files of the others");
+    }
+    dest_fs::check_destination_fs(config)?;
+    if let Some(state_file) = &config.state_file {
+        failures::load_failures(state_file)?;
+    }
+    mount::initial_mount_check(config)?;
+    let started = Instant::now();
+    if !config.dry_run {
+        let removed = copy::remove_partial_copies(&config.dest);
+        if removed > 0 {
+            info!("removed {} partial copies left by an earlier run", removed);
+        }
+    }
+    let roots = config.roots()?;
+    let since = SinceManifest::load(config, &roots)?;
+    let progress = if config.progress && !config.dry_run {
+        let (mut files, mut bytes) = (0, 0);
+        for root in &roots {
+            let (root_files, root_bytes) = match progress::prescan(root, since.as_ref()) {
+                Err(e) if e.is::<mount::RemountsExhausted>() => {
+                    return Ok(CopyReport {
+                        remounts_exhausted: true,
+                        elapsed_secs: started.elapsed().as_secs_f64(),
+                        ..Default::default()
+                    });
+                }
+                counted => counted?,
+            };
+            files += root_files;
+            bytes += root_bytes;
+        }
+        info!("found {} files, {} bytes to go through", files, bytes);
+        let available = fs2::available_space(&config.dest)
+            .with_context(|| format!("can't get free space of '{}'", config.dest.display()))?;
+        if bytes > available {
+            // files left over from an earlier run are in the total too, so this may well still fit
+            warn!(
+                "only {} bytes free on '{}', files bigger than what's left will {}",
+                available,
+                config.dest.display(),
+                if config.skip_oversize { "be skipped" } else { "stop the copy" }
+            );
+        }
+        Some(Progress::new(files, bytes))
+    } else if config.progress_interval.is_some() && !config.dry_run {
+        Some(Progress::without_bar())
+    } else {
+        None
+    };
+    let manifest = Manifest::open(config)?;
+    let _events = events::open(config)?;
+    let mut report = CopyReport::default();
+    let (stop_status, status_stopped) = mpsc::channel::<()>();
+    thread::scope(|scope| -> Result<()> {
+        if let (Some(interval), Some(progress)) = (config.progress_interval, &progress) {
+            scope.spawn(move || {
+                while status_stopped.recv_timeout(interval) == Err(RecvTimeoutError::Timeout) {
+                    progress.log_status(config, started.elapsed());
+                }
+            });
+        }
+        // dropped however the copy ends, which stops the status lines
+        let _stop_status = stop_status;
+        for root in &roots {
+            // what the sources before left of the limit
+            let root = &CopyConfig {
+                max_files: config.max_files.map(|max| max.saturating_sub(report.files_copied)),
+                ..root.clone()
+            };
+            report.merge(copy::walk_and_copy(root, manifest.as_ref(), since.as_ref(), progress.as_ref())?);
+            if report.stopped_early() {
+                break;
+            }
+        }
+        Ok(())
+    })?;
+    if let Some(progress) = &progress {
+        progress.finish();
+    }
+    if config.retry_failures_at_end && !config.dry_run && !report.stopped_early() && !report.failures.is_empty() {
+        retry_failures(config, &roots, manifest.as_ref(), since.as_ref(), &mut report)?;
+    }
+    let finished = !report.stopped_early();
+    if report.interrupted && config.umount_on_interrupt {
+        mount::umount(config)?;
+    } else if finished && config.umount_when_done {
+        info!("copy finished, unmounting '{}'", config.mount_point);
+        mount::umount(config)?;
+    }
+    report.dry_run = config.dry_run;
+    report.elapsed_secs = started.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+/// The `retry_failures_at_end` pass, on a fresh mount
+fn retry_failures(
+    config: &CopyConfig,
+    roots: &[CopyConfig],
+    manifest: Option<&Manifest>,
+    since: Option<&SinceManifest>,
+    report: &mut CopyReport,
+) -> Result<()> {
+    info!("retrying {} failed paths on a fresh mount", report.failures.len());
+    let generation = *mount::MOUNT_GENERATION.read().unwrap();
+    match mount::remount_since(config, generation) {
+        Err(e) if e.is::<mount::RemountsExhausted>() => {
+            report.remounts_exhausted = true;
+            return Ok(());
+        }
+        remounted => remounted?,
+    }
+    for failure in &report.failures {
+        failures::forgive_failure(Path::new(&failure.path));
+    }
+    for root in roots {
+        copy::retry_failures(root, manifest, since, report)?;
+        if report.interrupted || report.destination_full || report.remounts_exhausted {
+            break;
+        }
+    }
+    info!("{} failed paths copied fine this time", report.failures_recovered);
+    Ok(())
+}
+
+/// Mounts the source if needed and hands every directory and file a copy with `config` would go through to `listed`,
+/// with where it would go relative to the destination. `config.dest` is ignored, nothing is written.
+pub fn list_tree(config: &CopyConfig, listed: &mut dyn FnMut(&Path, &Path)) -> Result<CopyReport> {
+    let config = &config.for_run()?;
+    names::check_replacement_char(config)?;
+    if let Some(state_file) = &config.state_file {
+        failures::load_failures(state_file)?;
+    }
+    mount::initial_mount_check(config)?;
+    let started = Instant::now();
+    let config = CopyConfig {
+        dest: PathBuf::new(),
+        ..config.clone()
+    };
+    let mut report = CopyReport::default();
+    for root in config.roots()? {
+        report.merge(list::walk_and_list(&root, listed)?);
+        if report.interrupted || report.remounts_exhausted {
+            break;
+        }
+    }
+    report.elapsed_secs = started.elapsed().as_secs_f64();
+    Ok(report)
+}
+
+/// Mounts the source if needed and checks that a copy made with `config` is still all there,
+/// `config.verify` says how each file is compared and `Verify::None` only checks that it exists
+pub fn verify_tree(config: &CopyConfig) -> Result<VerifyReport> {
+    let config = &config.for_run()?;
+    names::check_replacement_char(config)?;
+    if let Some(state_file) = &config.state_file {
+        failures::load_failures(state_file)?;
+    }
+    mount::initial_mount_check(config)?;
+    let started = Instant::now();
+    let mut report = VerifyReport::default();
+    for root in config.roots()? {
+        report.merge(verify::walk_and_verify(&root)?);
+        if report.interrupted || report.remounts_exhausted {
+            break;
+        }
+    }
+    report.elapsed_secs = started.elapsed().as_secs_f64();
+    Ok(report)
+}
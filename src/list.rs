@@ -0,0 +1,64 @@
+use anyhow::Result;
+use std::{cell::RefCell, path::Path};
+
+use crate::{checkpoint::Checkpoint, copy::walk_tree, CopyConfig, CopyReport};
+
+/// Walks the source the way a copy with the same `config` would and hands every directory and file below it to
+/// `listed` with where the copy puts it, relative to `dest`. Nothing is written, a checkpoint is ignored.
+pub(crate) fn walk_and_list(config: &CopyConfig, listed: &mut dyn FnMut(&Path, &Path)) -> Result<CopyReport> {
+    let config = &CopyConfig {
+        dry_run: false,
+        checkpoint: None,
+        mirror: false,
+        ..config.clone()
+    };
+    let checkpoint = Checkpoint::load(config)?;
+    // both get to it, one after the other
+    let listed = RefCell::new(listed);
+    walk_tree(
+        config,
+        &checkpoint,
+        None,
+        &mut |_, dir, dest_dir| {
+            // a single source root goes to `dest` itself, there's nothing to say about it
+            if !dest_dir.as_os_str().is_empty() {
+                (listed.borrow_mut())(dir, dest_dir);
+            }
+            Ok(true)
+        },
+        &mut |_, from, to| {
+            (listed.borrow_mut())(&from, &to);
+            Ok(())
+        },
+    )
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig};
+    use std::path::PathBuf;
+
+    #[test]
+    fn it_lists_paths_with_their_destinations() {
+        let (source, _) = test_dirs("list");
+        std::fs::create_dir(source.join("a:b")).unwrap();
+        std::fs::write(source.join("a:b").join("c.txt"), "").unwrap();
+        std::fs::write(source.join("d.txt"), "").unwrap();
+        let config = CopyConfig::new("", "", source.clone(), PathBuf::new());
+        let mut listed = vec![];
+        let report = super::walk_and_list(&config, &mut |path, dest| {
+            listed.push((path.strip_prefix(&source).unwrap().to_path_buf(), dest.to_path_buf()))
+        })
+        .unwrap();
+        assert_eq!(
+            listed,
+            [
+                (PathBuf::from("d.txt"), PathBuf::from("d.txt")),
+                (PathBuf::from("a:b"), PathBuf::from("a_b")),
+                (PathBuf::from("a:b/c.txt"), PathBuf::from("a_b/c.txt")),
+            ]
+        );
+        assert!(report.failures.is_empty());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
@@ -1,238 +1,1140 @@
-use clap::Parser;
-use std::{collections::HashSet, fs, path::Path, path::PathBuf, sync::Mutex, thread, time};
-
-#[macro_use]
-extern crate lazy_static;
+use anyhow::{bail, Context, Result};
+use anstyle::{AnsiColor, Style};
+use aspf_copier::{CopyConfig, CopyReport, Failure, Normalize, OnCollision, Overwrite, SourceMissing, SymlinkMode, SystemMounter, TargetFs, Traversal, Verify, WalkOrder, DEFAULT_MOUNT_COMMAND, DEFAULT_UMOUNT_COMMAND};
+use clap::{parser::ValueSource, ArgAction, Args, CommandFactory, FromArgMatches, Parser, Subcommand};
+use log::{error, info, warn, LevelFilter};
+use std::{
+    env,
+    ffi::OsString,
+    fs,
+    io::{self, Write},
+    path::{Path, PathBuf},
+    process,
+    sync::Arc,
+    time::{Duration, SystemTime, UNIX_EPOCH},
+};
 
 #[derive(Parser)]
 #[command(name= "APFS Copier")]
 #[command(about = "Copy a directory tree from a mounted APFS volume to a destination directory on ExFAT volume in Linux")]
 #[command(author = "Alexander Pugachev")]
+#[command(after_help = "Exit codes:\n  \
+    0    everything was copied, or verify found the destination matching\n  \
+    1    the run went through, but some paths failed and are listed in the report\n  \
+    2    the run stopped early, on a full destination, --max-total-remounts or a fatal error\n  \
+    3    bad arguments, config file, a missing source or program, nothing was touched\n  \
+    4    the run stopped at --max-files with more left to copy\n  \
+    130  stopped by Ctrl-C, run again to go on")]
 struct Cli {
+    #[command(subcommand)]
+    command: Command,
+    /// Log more, twice for every detail
+    #[arg(short, long, global = true, action = clap::ArgAction::Count, conflicts_with = "quiet")]
+    verbose: u8,
+    /// Only log warnings and errors
+    #[arg(short, long, global = true)]
+    quiet: bool,
+    /// Also append the log to this file, with RFC 3339 timestamps down to the millisecond
+    #[arg(long, global = true, value_name = "PATH")]
+    log_file: Option<PathBuf>,
+    /// Log only to `--log-file`, nothing on the console
+    #[arg(long, global = true, requires = "log_file")]
+    log_file_only: bool,
+    /// Take settings from this TOML file, keyed by their long option names like `mount-command = "..."`,
+    /// `exclude = ["*.tmp"]` or `no-sudo = true`. `device`, `mount-point`, `sources` and `dest` it sets
+    /// are left out on the command line. Whatever the command line gives still wins.
+    #[arg(long, global = true)]
+    config: Option<PathBuf>,
+}
+
+#[derive(Subcommand)]
+enum Command {
+    /// Copy the source to the destination
+    Copy(CopyArgs),
+    /// Check that every file of the source is at the destination a copy would put it at, without copying anything
+    Verify(VerifyArgs),
+    /// Print every path of the source with where a copy would put it relative to the destination,
+    /// without needing one
+    List(ListArgs),
+}
+
+/// How to get at the source volume
+#[derive(Args)]
+struct MountArgs {
     device: String,
     mount_point: String,
-    source: PathBuf,
+    /// Give up waiting for the mount to become readable after this many seconds
+    #[arg(long, default_value_t = 30)]
+    mount_timeout_secs: u64,
+    /// How often to check whether the mount has become readable
+    #[arg(long, default_value_t = 500)]
+    mount_poll_interval_ms: u64,
+    /// Wait this many seconds more once a new mount is readable, 0 doesn't wait. Raise it if copies abort
+    /// right after a mount, too low and the first reads race a FUSE mount that isn't quite ready.
+    #[arg(long, default_value_t = 0)]
+    mount_settle_secs: u64,
+    /// Give up mounting the device after this many failed attempts
+    #[arg(long, default_value_t = 5, value_parser = clap::value_parser!(u32).range(1..))]
+    max_mount_attempts: u32,
+    /// Delay before the first mount retry, doubled after every further failure
+    #[arg(long, default_value_t = 1000)]
+    mount_retry_delay_ms: u64,
+    /// Remember paths that caused connection aborts in this file so later runs skip them
+    #[arg(long)]
+    state_file: Option<PathBuf>,
+    /// Skip a path for good once it caused this many connection aborts, retry it on a fresh mount until then
+    #[arg(long, default_value_t = 3, value_parser = clap::value_parser!(u32).range(1..))]
+    max_path_aborts: u32,
+    /// Stop with exit code 2 once the volume needs remounting after this many remounts, no limit by default
+    #[arg(long)]
+    max_total_remounts: Option<u32>,
+    /// Fall back to `umount -l` when the mount point stays busy, so a lingering file handle can't block a remount
+    #[arg(long)]
+    allow_lazy_umount: bool,
+    /// Command that mounts the device, `{device}` and `{mountpoint}` are filled in
+    #[arg(long, default_value = DEFAULT_MOUNT_COMMAND)]
+    mount_command: String,
+    /// Command that unmounts the volume, `{mountpoint}` is filled in
+    #[arg(long, default_value = DEFAULT_UMOUNT_COMMAND)]
+    umount_command: String,
+    /// Run the mount and umount commands directly instead of through sudo
+    #[arg(long)]
+    no_sudo: bool,
+    /// Password of an encrypted volume, prefer --password-file or --ask-password to keep it out of shell history
+    #[arg(long, conflicts_with_all = ["password_file", "ask_password"])]
+    password: Option<String>,
+    /// Read the password of an encrypted volume from the first line of this file
+    #[arg(long, conflicts_with = "ask_password")]
+    password_file: Option<PathBuf>,
+    /// Prompt for the password of an encrypted volume without echoing it
+    #[arg(long)]
+    ask_password: bool,
+    /// Index of the volume to mount when the container holds several, e.g. the Data volume next to System
+    #[arg(long)]
+    volume: Option<u32>,
+    /// Pass `-o <OPTION>` to the mount command, on every remount too, e.g. ro, allow_other or uid=1000, can be repeated
+    #[arg(long = "mount-option", value_name = "OPTION")]
+    mount_options: Vec<String>,
+    /// Mount the source with `-o ro` so nothing can write to a failing volume, also on every remount. The default.
+    #[arg(long, overrides_with = "no_read_only")]
+    read_only: bool,
+    /// Leave `-o ro` out, for a mount command that doesn't take it
+    #[arg(long, overrides_with = "read_only")]
+    no_read_only: bool,
+}
+
+/// What's copied to where
+#[derive(Args)]
+struct Paths {
+    /// A directory whose contents are copied into the destination, or files and directories
+    /// that each go into it under their own name
+    #[arg(required = true, num_args = 1..)]
+    sources: Vec<PathBuf>,
     dest: PathBuf,
 }
 
-lazy_static! {
-    static ref FAILED_CONNECTION_ABORTS: Mutex<HashSet<String>> = { Mutex::new(HashSet::new()) };
+/// Which source files go where, `verify` has to be given the same as the copy it checks
+#[derive(Args)]
+struct WalkArgs {
+    /// Only copy files whose source-relative path matches one of these globs
+    #[arg(long)]
+    include: Vec<String>,
+    /// Skip files and directories whose source-relative path matches one of these globs
+    #[arg(long)]
+    exclude: Vec<String>,
+    /// Leave out .DS_Store, .Spotlight-V100, .Trashes, .fseventsd and ._ AppleDouble sidecars
+    #[arg(long)]
+    skip_apple_metadata: bool,
+    /// Leave out dotfiles and dot-directories, with everything in them
+    #[arg(long)]
+    skip_hidden: bool,
+    /// Also skip what the patterns in this file match, one per line in .gitignore syntax relative to the source
+    #[arg(long)]
+    exclude_from: Option<PathBuf>,
+    /// Copy every file straight into the destination without its directories, clashing names get a numeric suffix
+    #[arg(long)]
+    flatten: bool,
+    /// Sort files into images, videos, audio, documents and other folders of the destination by their extension
+    /// instead of recreating the tree, clashing names in a folder get a numeric suffix
+    #[arg(long)]
+    route_by_extension: bool,
+    /// Leave out and report a directory whose destination is blocked by a file, say from a --flatten run,
+    /// instead of copying it under a numbered name next to the file
+    #[arg(long)]
+    skip_dirs_blocked_by_files: bool,
+    /// Put files with these extensions into this folder with --route-by-extension, like raw=cr2,nef, can be repeated
+    #[arg(long = "route", value_name = "FOLDER=EXTENSIONS", requires = "route_by_extension", value_parser = parse_route)]
+    routes: Vec<(String, Vec<String>)>,
+    /// Skip files smaller than this, in bytes or with a K, M, G or T suffix
+    #[arg(long, value_parser = parse_size)]
+    min_size: Option<u64>,
+    /// Skip files bigger than this, in bytes or with a K, M, G or T suffix
+    #[arg(long, value_parser = parse_size)]
+    max_size: Option<u64>,
+    /// Skip files last modified before this, a UTC date like 2023-01-01 or an age like 30d, 12h or 2w
+    #[arg(long, value_parser = parse_newer_than)]
+    newer_than: Option<SystemTime>,
+    /// Only copy files this many levels below the source, 1 takes just the files right in it
+    #[arg(long, value_parser = clap::value_parser!(u16).range(1..))]
+    max_depth: Option<u16>,
+    /// Unicode-normalize destination names, macOS keeps them as NFD while most other systems expect NFC
+    #[arg(long, value_enum, default_value_t = Normalize::None)]
+    normalize: Normalize,
+    /// Rewrite names for this filesystem, none leaves them as they are
+    #[arg(long, value_enum, default_value_t = TargetFs::Exfat)]
+    target_fs: TargetFs,
+    /// Replace these characters in names instead of the ones --target-fs rejects,
+    /// control characters still go by --target-fs
+    #[arg(long, value_name = "CHARS")]
+    forbidden_chars: Option<String>,
+    /// Put this in place of every character the destination can't store, colliding names still get numbered
+    #[arg(long, default_value_t = '_')]
+    replacement_char: char,
+    /// Which of the files of a directory whose names end up the same at the destination keeps the name,
+    /// the others are numbered or with newest-only skipped, and listed in --rename-log
+    #[arg(long, value_enum, default_value_t = OnCollision::Suffix)]
+    on_collision: OnCollision,
+    /// Put `_` in front of names Windows reserves for devices, like CON, NUL or COM1.txt, so Windows can open the copies
+    #[arg(long)]
+    rename_reserved_names: bool,
+    /// What to do with symlinks, which ExFAT can't store
+    #[arg(long, value_enum, default_value_t = SymlinkMode::Dereference)]
+    symlinks: SymlinkMode,
+    /// Copy a file with several hard links once and list the other links in the report,
+    /// ExFAT can't link them so they would each take up space again
+    #[arg(long)]
+    dedup_hardlinks: bool,
+    /// Stop on a file or directory that can't be read for its permissions, instead of reporting it and going on
+    #[arg(long)]
+    fail_on_permission_denied: bool,
+    /// Order the entries of every directory are walked in
+    #[arg(long, value_enum, default_value_t = WalkOrder::Name)]
+    order: WalkOrder,
+    /// Go into subdirectories before or after their siblings
+    #[arg(long, value_enum, default_value_t = Traversal::DepthFirst)]
+    traversal: Traversal,
+}
+
+#[derive(Args)]
+struct CopyArgs {
+    #[command(flatten)]
+    mount: MountArgs,
+    #[command(flatten)]
+    walk: WalkArgs,
+    #[command(flatten)]
+    paths: Paths,
+    /// Walk the source and report what would be copied without writing anything
+    #[arg(long)]
+    dry_run: bool,
+    /// Stop once this many files were copied, or would be with --dry-run, to try the setup out on a sample.
+    /// Skipped files don't count.
+    #[arg(long, value_name = "N")]
+    max_files: Option<u64>,
+    /// Create all the directories first, then walk the source again to copy the files
+    #[arg(long)]
+    structure_first: bool,
+    /// Record directories whose whole subtree was copied in this file and skip them on the next run,
+    /// resume with the same source, filters and destination
+    #[arg(long)]
+    checkpoint: Option<PathBuf>,
+    /// Append the source-relative path, size and modification time of every copied file to this file as JSON lines
+    #[arg(long)]
+    manifest: Option<PathBuf>,
+    /// Put the SHA-256 of every copied file in the manifest too
+    #[arg(long, requires = "manifest")]
+    manifest_sha256: bool,
+    /// Only copy files that aren't in this manifest of an earlier run with the size and modification time they
+    /// have now, the rest count as copied without checking the destination, which may not even be the same drive
+    #[arg(long, value_name = "MANIFEST")]
+    since_manifest: Option<PathBuf>,
+    /// Append a JSON line to this file for every file copied or skipped, every remount and every failure
+    /// as they happen, each with "schema_version", "time" in Unix seconds and "event": "copied" (path,
+    /// destination, size, duration_secs), "skipped" (path, reason), "remount" (remounts) or "error"
+    /// (path, errno when there is one, reason)
+    #[arg(long)]
+    events_jsonl: Option<PathBuf>,
+    /// Delete whatever in the destination isn't in the source any more, preview it with --dry-run first
+    #[arg(long, conflicts_with_all = ["flatten", "route_by_extension"])]
+    mirror: bool,
+    /// Let --mirror actually delete files
+    #[arg(long, requires = "mirror")]
+    allow_delete: bool,
+    /// Leave the destination modification time at the time of copying
+    #[arg(long)]
+    no_preserve_mtime: bool,
+    /// Copy Unix permissions too, best effort: ExFAT itself has none, a FUSE mount of it keeps only some bits
+    #[arg(long)]
+    preserve_permissions: bool,
+    /// Copy extended attributes like Finder tags too, best effort: ExFAT itself has none, a FUSE mount of it
+    /// may keep user. ones, whatever the destination rejects is left out
+    #[arg(long)]
+    preserve_xattrs: bool,
+    /// What to do with a file whose destination already exists
+    #[arg(long, value_enum, default_value_t = Overwrite::Skip)]
+    overwrite: Overwrite,
+    /// Same as --overwrite if-different
+    #[arg(long, conflicts_with = "overwrite")]
+    update: bool,
+    /// Write a JSON report of the run to this file
+    #[arg(long)]
+    report_json: Option<PathBuf>,
+    /// Append an `original<TAB>renamed` line to this file for every name changed to fit ExFAT
+    #[arg(long)]
+    rename_log: Option<PathBuf>,
+    /// How every copy is checked against its source, a mismatch is copied once more before it's reported
+    #[arg(long, value_enum, default_value_t = Verify::None)]
+    verify: Verify,
+    /// Copy files on this many worker threads, directories are still created in walk order
+    #[arg(long, default_value_t = 1, value_parser = clap::value_parser!(u16).range(1..))]
+    jobs: u16,
+    /// Add up the time spent per directory this many levels below the source, for the slowest directories
+    /// at the end and in the report
+    #[arg(long, default_value_t = 1, value_name = "DEPTH")]
+    throughput_depth: u16,
+    /// Count the source first, then show a progress bar with an ETA while copying,
+    /// and warn up front if the destination looks too small
+    #[arg(long)]
+    progress: bool,
+    /// Log how far the copy got every this many seconds, without a pre-scan, for runs nobody watches
+    #[arg(long, conflicts_with = "progress", value_parser = clap::value_parser!(u64).range(1..))]
+    progress_interval_secs: Option<u64>,
+    /// Skip files bigger than the free space left on the destination instead of stopping the copy
+    #[arg(long)]
+    skip_oversize: bool,
+    /// Copy a file again this many times after an I/O error before it's given up on
+    #[arg(long, default_value_t = 2)]
+    io_retries: u32,
+    /// Delay before the first retry after an I/O error, doubled after every further one
+    #[arg(long, default_value_t = 500)]
+    io_retry_delay_ms: u64,
+    /// Chunk size in bytes that files over 16 MiB are streamed in, Ctrl-C stops such a file between chunks
+    #[arg(long, default_value_t = 1024 * 1024, value_parser = clap::value_parser!(u32).range(1..))]
+    buffer_size: u32,
+    /// Copy at most this many bytes a second, like 20M, on average over all --jobs. Every file is streamed then.
+    #[arg(long, value_parser = parse_rate)]
+    rate_limit: Option<u64>,
+    /// Refuse to copy to a destination that isn't on ExFAT or FAT instead of only warning about it
+    #[arg(long)]
+    strict_fs: bool,
+    /// Unmount the volume when Ctrl-C stops the copy
+    #[arg(long)]
+    umount_on_interrupt: bool,
+    /// Unmount the volume once everything was gone through, even if this run didn't mount it
+    #[arg(long)]
+    umount_when_done: bool,
+    /// Once everything was gone through, remount and try every failed path once more
+    #[arg(long)]
+    retry_failures_at_end: bool,
+}
+
+#[derive(Args)]
+struct VerifyArgs {
+    #[command(flatten)]
+    mount: MountArgs,
+    #[command(flatten)]
+    walk: WalkArgs,
+    #[command(flatten)]
+    paths: Paths,
+    /// Compare SHA-256 hashes as well as sizes, which reads every file on both sides
+    #[arg(long)]
+    checksum: bool,
+    /// Write a JSON report of what was found to this file
+    #[arg(long)]
+    report_json: Option<PathBuf>,
+}
+
+#[derive(Args)]
+struct ListArgs {
+    #[command(flatten)]
+    mount: MountArgs,
+    #[command(flatten)]
+    walk: WalkArgs,
+    /// A directory whose contents are listed, or files and directories that are each listed under their own name
+    #[arg(required = true, num_args = 1..)]
+    sources: Vec<PathBuf>,
+}
+
+impl Command {
+    fn mount(&self) -> &MountArgs {
+        match self {
+            Command::Copy(args) => &args.mount,
+            Command::Verify(args) => &args.mount,
+            Command::List(args) => &args.mount,
+        }
+    }
+}
+
+impl MountArgs {
+    /// The settings every command shares, with `walk` from `sources` to `dest`
+    fn to_config(&self, walk: &WalkArgs, sources: &[PathBuf], dest: &Path) -> Result<CopyConfig> {
+        let mut mounter = SystemMounter::new(&self.mount_command, &self.umount_command, !self.no_sudo);
+        if let Some(volume) = self.volume {
+            mounter = mounter.with_volume(volume);
+        }
+        if !self.no_read_only && !self.mount_options.iter().any(|option| option == "ro") {
+            mounter = mounter.with_mount_option("ro");
+        }
+        for option in &self.mount_options {
+            mounter = mounter.with_mount_option(option);
+        }
+        if let Some(password) = self.read_password()? {
+            mounter = mounter.with_password(password);
+        }
+        Ok(CopyConfig {
+            mount_timeout: Duration::from_secs(self.mount_timeout_secs),
+            mount_poll_interval: Duration::from_millis(self.mount_poll_interval_ms),
+            mount_settle: Duration::from_secs(self.mount_settle_secs),
+            max_mount_attempts: self.max_mount_attempts,
+            mount_retry_delay: Duration::from_millis(self.mount_retry_delay_ms),
+            state_file: self.state_file.clone(),
+            max_path_aborts: self.max_path_aborts,
+            max_total_remounts: self.max_total_remounts,
+            allow_lazy_umount: self.allow_lazy_umount,
+            include: walk.include.clone(),
+            exclude: walk.exclude.clone(),
+            skip_apple_metadata: walk.skip_apple_metadata,
+            skip_hidden: walk.skip_hidden,
+            flatten: walk.flatten,
+            route_by_extension: walk.route_by_extension,
+            skip_dirs_blocked_by_files: walk.skip_dirs_blocked_by_files,
+            extension_routes: walk
+                .routes
+                .iter()
+                .flat_map(|(folder, extensions)| extensions.iter().map(move |extension| (extension.clone(), folder.clone())))
+                .collect(),
+            exclude_from: walk.exclude_from.clone(),
+            min_size: walk.min_size,
+            max_size: walk.max_size,
+            newer_than: walk.newer_than,
+            max_depth: walk.max_depth.map(usize::from),
+            normalize: walk.normalize,
+            target_fs: walk.target_fs,
+            forbidden_chars: walk.forbidden_chars.clone(),
+            replacement_char: walk.replacement_char,
+            on_collision: walk.on_collision,
+            rename_reserved_names: walk.rename_reserved_names,
+            symlinks: walk.symlinks,
+            dedup_hardlinks: walk.dedup_hardlinks,
+            fail_on_permission_denied: walk.fail_on_permission_denied,
+            order: walk.order,
+            traversal: walk.traversal,
+            extra_sources: sources[1..].to_vec(),
+            mounter: Arc::new(mounter),
+            ..CopyConfig::new(&self.device, &self.mount_point, sources[0].clone(), dest.to_path_buf())
+        })
+    }
+
+    fn read_password(&self) -> Result<Option<String>> {
+        if let Some(password_file) = &self.password_file {
+            let content = fs::read_to_string(password_file)
+                .with_context(|| format!("can't read password file '{}'", password_file.display()))?;
+            return Ok(Some(content.lines().next().unwrap_or_default().to_string()));
+        }
+        if self.ask_password {
+            let password = rpassword::prompt_password("volume password: ").context("can't read password")?;
+            return Ok(Some(password));
+        }
+        Ok(self.password.clone())
+    }
+}
+
+impl CopyArgs {
+    fn to_config(&self) -> Result<CopyConfig> {
+        if self.mirror && !self.dry_run && !self.allow_delete {
+            bail!("--mirror deletes from the destination, see what it would delete with --dry-run and pass --allow-delete to go ahead");
+        }
+        Ok(CopyConfig {
+            dry_run: self.dry_run,
+            max_files: self.max_files,
+            structure_first: self.structure_first,
+            checkpoint: self.checkpoint.clone(),
+            manifest: self.manifest.clone(),
+            manifest_sha256: self.manifest_sha256,
+            since_manifest: self.since_manifest.clone(),
+            events_jsonl: self.events_jsonl.clone(),
+            mirror: self.mirror,
+            mirror_keeps: [&self.report_json, &self.rename_log].into_iter().flatten().cloned().collect(),
+            preserve_mtime: !self.no_preserve_mtime,
+            preserve_permissions: self.preserve_permissions,
+            preserve_xattrs: self.preserve_xattrs,
+            overwrite: if self.update { Overwrite::IfDifferent } else { self.overwrite },
+            jobs: usize::from(self.jobs),
+            throughput_depth: usize::from(self.throughput_depth),
+            verify: self.verify,
+            progress: self.progress,
+            progress_interval: self.progress_interval_secs.map(Duration::from_secs),
+            skip_oversize: self.skip_oversize,
+            io_retries: self.io_retries,
+            io_retry_delay: Duration::from_millis(self.io_retry_delay_ms),
+            buffer_size: self.buffer_size as usize,
+            rate_limit: self.rate_limit,
+            strict_fs: self.strict_fs,
+            umount_on_interrupt: self.umount_on_interrupt,
+            umount_when_done: self.umount_when_done,
+            retry_failures_at_end: self.retry_failures_at_end,
+            ..self.mount.to_config(&self.walk, &self.paths.sources, &self.paths.dest)?
+        })
+    }
+}
+
+impl VerifyArgs {
+    fn to_config(&self) -> Result<CopyConfig> {
+        Ok(CopyConfig {
+            verify: if self.checksum { Verify::Sha256 } else { Verify::Size },
+            ..self.mount.to_config(&self.walk, &self.paths.sources, &self.paths.dest)?
+        })
+    }
 }
 
 fn main() {
-    let args = Cli::parse();
-    initial_mount_check(&args);
-    copy_tree(&args);
-    println!("done!");
-}
-
-fn initial_mount_check(args: &Cli) {
-    match fs::read_dir(args.source.as_path()) {
-        Ok(dir_content) => {
-            println!("{:#?}", dir_content);
-        }
-        Err(e) => match e.raw_os_error() {
-            Some(107) => {
-                // Transport endpoint is not connected
-                println!("Transport endpoint is not connected, mounting at start");
-                mount(args.device.as_str(), args.mount_point.as_str());
+    let args = match parse_args(env::args_os().collect()) {
+        Ok(args) => args,
+        Err(e) => match e.downcast::<clap::Error>() {
+            // --help and --version are printed the same way, but they're no failure
+            Ok(e) => {
+                let _ = e.print();
+                process::exit(if e.use_stderr() { EXIT_USAGE } else { EXIT_OK });
             }
-            _ => panic!("Error: {}", e),
+            Err(e) => fail(EXIT_USAGE, e),
         },
     };
-    println!("passed initial mount check");
+    if let Err(e) = init_logging(&args) {
+        fail(EXIT_USAGE, e);
+    }
+    if let Err(e) = preflight(args.command.mount()) {
+        fail(EXIT_USAGE, e);
+    }
+    let run = match &args.command {
+        Command::Copy(args) => args.to_config().map(|config| copy(args, &config)),
+        Command::Verify(args) => args.to_config().map(|config| verify(args, &config)),
+        Command::List(args) => args.to_config().map(|config| list(&config)),
+    };
+    match run {
+        Ok(Ok(code)) => process::exit(code),
+        Ok(Err(e)) if e.is::<SourceMissing>() => fail(EXIT_USAGE, e),
+        Ok(Err(e)) => fail(EXIT_ABORTED, e),
+        Err(e) => fail(EXIT_USAGE, e),
+    }
 }
 
-fn copy_tree(args: &Cli) {
-    let mut stack = vec![];
-    stack.push(PathBuf::from(&args.source));
-    while let Some(path) = stack.pop() {
-        if is_failure(&path) {
-            continue;
-        }
-        // every component of dest path must be escaped properly because it's created underscored at the destination
-        let dest_path: PathBuf = args
-            .dest
-            .join(path.strip_prefix(args.source.as_path()).unwrap()).iter()
-            .map(|p| underscore_non_windows_chars(p.to_str().unwrap().to_string()))
-            .collect();
-        if path.is_dir() {
+/// Prints `e` the way returning it from `main` would, but exits with `code`
+fn fail(code: i32, e: anyhow::Error) -> ! {
+    eprintln!("Error: {:?}", e);
+    process::exit(code)
+}
 
-            match fs::create_dir_all(&dest_path) {
-                Ok(_) => (),
-                Err(e) => match e.raw_os_error() {
-                    Some(22) => {
-                        fs::create_dir_all(replace_forbidden_characters(&dest_path)).unwrap();
+/// The command line, on top of the settings of its `--config` file
+fn parse_args(argv: Vec<OsString>) -> Result<Cli> {
+    let mut command = Cli::command();
+    // only to find the file, the settings in it may well be what this is missing
+    let partial = Cli::command().ignore_errors(true).get_matches_from(argv.clone());
+    if let Some((name, subcommand)) = partial.subcommand() {
+        if let Some(path) = subcommand.get_one::<PathBuf>("config") {
+            let settings = read_config_file(path)?;
+            let (mut defaults, mut global_defaults) = (vec![], vec![]);
+            let known = command.find_subcommand(name).unwrap();
+            for (key, value) in settings {
+                let id = key.replace('-', "_");
+                let is = |a: &&clap::Arg| a.get_id() == id.as_str() && id != "config";
+                // the options every command takes, like `quiet`, are only handed down to the subcommands later
+                let (arg, global) = match known.get_arguments().find(is) {
+                    Some(arg) => (Some(arg), false),
+                    None => (command.get_arguments().find(is), true),
+                };
+                let Some(arg) = arg else {
+                    // one file can serve every command, say `dest` is only for `copy` and `verify`
+                    if command.get_subcommands().any(|s| s.get_arguments().any(|a| a.get_id() == id.as_str())) {
+                        continue;
                     }
-                    _ => panic!("Error: {:#?} From: '{:#?}' To: '{:#?}'", e, &path, &dest_path),
-                },
-            }
-            let mut need_remount = false;
-
-            for entry in fs::read_dir(&path).unwrap() {
-                match entry {
-                    Ok(entry) => stack.push(entry.path()),
-                    Err(e) => match e.raw_os_error() {
-                        Some(103) => {
-                            // can't remount here because the file we failed to open is still in use preventing umount
-                            need_remount = true;
-                            break;
-                        } // Software caused connection abort -- this is we're here, need to remount, remember not to try this path again, and continue
-                        _ => panic!("Error: {}", e),
-                    },
+                    bail!("unknown setting '{}' in '{}'", key, path.display());
                 };
+                let values = match value {
+                    toml::Value::Array(items) => items.into_iter().map(config_value).collect(),
+                    value => config_value(value).map(|value| vec![value]),
+                }
+                .with_context(|| format!("setting '{}' in '{}'", key, path.display()))?;
+                let default = (id, key, values, arg.is_positional(), matches!(arg.get_action(), ArgAction::SetTrue));
+                if global {
+                    global_defaults.push(default);
+                } else {
+                    defaults.push(default);
+                }
             }
-
-            if need_remount {
-                handle_software_caused_connection_abort(args, &path).unwrap();
+            for (id, key, values, positional, flag) in global_defaults {
+                command = command.mut_arg(id, |arg| config_default(arg, key, values, positional, flag));
             }
+            command = command.mut_subcommand(name, |mut subcommand| {
+                for (id, key, values, positional, flag) in defaults {
+                    subcommand = subcommand.mut_arg(id, |arg| config_default(arg, key, values, positional, flag));
+                }
+                subcommand
+            });
+        }
+    }
+    let mut matches = command.try_get_matches_from(argv)?;
+    // `quiet` from the file gives way to `--verbose` on the command line, they'd conflict given both there
+    let verbose_given = matches.value_source("verbose") == Some(ValueSource::CommandLine);
+    let quiet_defaulted = matches.value_source("quiet") == Some(ValueSource::DefaultValue);
+    let mut args = Cli::from_arg_matches_mut(&mut matches)?;
+    if verbose_given && quiet_defaulted {
+        args.quiet = false;
+    }
+    Ok(args)
+}
+
+/// `arg` with the values a config file sets for it as its default
+fn config_default(mut arg: clap::Arg, key: String, values: Vec<String>, positional: bool, flag: bool) -> clap::Arg {
+    // a positional the file sets is taken out of the positionals, the ones after it move up
+    if positional {
+        arg = arg.long(key);
+    }
+    // so the command line can still turn it off with `--flag=false`
+    if flag {
+        arg = arg.action(ArgAction::Set).num_args(0..=1).require_equals(true).default_missing_value("true");
+    }
+    arg.required(false).default_values(values)
+}
+
+fn read_config_file(path: &Path) -> Result<toml::Table> {
+    let text = fs::read_to_string(path).with_context(|| format!("can't read config file '{}'", path.display()))?;
+    text.parse().with_context(|| format!("can't parse config file '{}'", path.display()))
+}
+
+/// A setting as it would be typed on the command line
+fn config_value(value: toml::Value) -> Result<String> {
+    match value {
+        toml::Value::String(s) => Ok(s),
+        toml::Value::Integer(i) => Ok(i.to_string()),
+        toml::Value::Float(f) => Ok(f.to_string()),
+        toml::Value::Boolean(b) => Ok(b.to_string()),
+        _ => bail!("has to be a string, a number, a boolean or a list of them"),
+    }
+}
+
+/// A second Ctrl-C quits right away
+fn handle_ctrlc(config: &CopyConfig, stopping: &'static str) -> Result<()> {
+    let handler_config = config.clone();
+    ctrlc::set_handler(move || {
+        if handler_config.is_interrupted() {
+            process::exit(EXIT_INTERRUPTED);
+        }
+        warn!("interrupted, {}, press Ctrl-C again to quit right away", stopping);
+        handler_config.interrupt();
+    })
+    .context("can't install the Ctrl-C handler")
+}
+
+/// The exit code of the run
+fn copy(args: &CopyArgs, config: &CopyConfig) -> Result<i32> {
+    handle_ctrlc(config, "finishing the current file or stopping a big one half-way")?;
+    let report = aspf_copier::copy_tree(config)?;
+    if let Some(report_json) = &args.report_json {
+        report.write_json(report_json)?;
+    }
+    // nothing was actually renamed in a dry run
+    if let Some(rename_log) = args.rename_log.as_ref().filter(|_| !args.dry_run) {
+        report.append_rename_log(rename_log)?;
+    }
+    if args.dry_run {
+        info!(
+            "dry run: would create {} directories and copy {} files, {} bytes total",
+            report.directories_created, report.files_copied, report.bytes_copied
+        );
+        if args.mirror {
+            info!("dry run: would delete {} paths from the destination", report.deleted.len());
+        }
+    } else {
+        print_summary(&report);
+    }
+    if report.interrupted {
+        warn!("stopped early on Ctrl-C, run again to copy the rest");
+        return Ok(EXIT_INTERRUPTED);
+    }
+    if report.destination_full {
+        error!("stopped early, the destination is full");
+        return Ok(EXIT_ABORTED);
+    }
+    if report.remounts_exhausted {
+        error!("stopped early, the volume kept dropping past --max-total-remounts");
+        return Ok(EXIT_ABORTED);
+    }
+    if report.max_files_reached {
+        info!("stopped at --max-files, run again without it to copy the rest");
+        return Ok(EXIT_MAX_FILES);
+    }
+    if !report.failures.is_empty() {
+        print_failures(&report.failures);
+        warn!("done, but {} paths failed, they're all in the report", report.failures.len());
+        return Ok(EXIT_FAILURES);
+    }
+    info!("done!");
+    Ok(EXIT_OK)
+}
+
+/// The exit code of the run
+fn verify(args: &VerifyArgs, config: &CopyConfig) -> Result<i32> {
+    handle_ctrlc(config, "finishing the current file")?;
+    let report = aspf_copier::verify_tree(config)?;
+    if let Some(report_json) = &args.report_json {
+        report.write_json(report_json)?;
+    }
+    info!("files matching:             {}", report.files_matching);
+    info!("bytes matching:             {}", report.bytes_matching);
+    info!("paths missing:              {}", report.missing.len());
+    info!("files differing:            {}", report.differing.len());
+    info!("paths not readable:         {}", report.failures.len());
+    if report.interrupted {
+        warn!("stopped early on Ctrl-C, only part of the destination was checked");
+        return Ok(EXIT_INTERRUPTED);
+    }
+    if report.remounts_exhausted {
+        error!("stopped early, the volume kept dropping past --max-total-remounts");
+        return Ok(EXIT_ABORTED);
+    }
+    if !report.is_intact() {
+        error!("the destination doesn't match the source");
+        return Ok(EXIT_FAILURES);
+    }
+    info!("the destination matches the source");
+    Ok(EXIT_OK)
+}
+
+impl ListArgs {
+    fn to_config(&self) -> Result<CopyConfig> {
+        self.mount.to_config(&self.walk, &self.sources, Path::new(""))
+    }
+}
+
+/// Prints a `source<TAB>destination` line for every path, with a third `renamed` column on those whose name
+/// doesn't make it to the destination as it is. The exit code of the run.
+fn list(config: &CopyConfig) -> Result<i32> {
+    handle_ctrlc(config, "stopping the listing")?;
+    let (mut listed, mut renamed) = (0, 0);
+    let report = aspf_copier::list_tree(config, &mut |path, dest| {
+        listed += 1;
+        if path.file_name() == dest.file_name() {
+            println!("{}\t{}", path.display(), dest.display());
         } else {
-            copy_file(args, path.as_path(), dest_path.as_path()).unwrap();
+            renamed += 1;
+            println!("{}\t{}\trenamed", path.display(), dest.display());
         }
+    })?;
+    info!("paths listed:               {}", listed);
+    info!("paths renamed:              {}", renamed);
+    info!("paths not readable:         {}", report.failures.len());
+    if report.interrupted {
+        warn!("stopped early on Ctrl-C, only part of the source was listed");
+        return Ok(EXIT_INTERRUPTED);
     }
+    if report.remounts_exhausted {
+        error!("stopped early, the volume kept dropping past --max-total-remounts");
+        return Ok(EXIT_ABORTED);
+    }
+    if !report.failures.is_empty() {
+        return Ok(EXIT_FAILURES);
+    }
+    Ok(EXIT_OK)
 }
 
-fn copy_file(args: &Cli, from: &Path, to: &Path) -> Result<(), std::io::Error> {
-    if to.exists() {
-        return Ok(());
+/// `RUST_LOG` still overrides the level picked by `--verbose`/`--quiet`
+fn init_logging(args: &Cli) -> Result<()> {
+    let level = match (args.quiet, args.verbose) {
+        (true, _) => LevelFilter::Warn,
+        (false, 0) => LevelFilter::Info,
+        (false, 1) => LevelFilter::Debug,
+        (false, _) => LevelFilter::Trace,
+    };
+    // `requires` doesn't hold a config file to it
+    if args.log_file_only && args.log_file.is_none() {
+        bail!("--log-file-only needs a --log-file");
     }
-    match fs::copy(from, to) {
-        Ok(_) => Ok(()),
-        Err(e) => match e.raw_os_error() {
-            Some(5) => Ok(()), //  input-output error, can't get source data, just continue
-            Some(103) => handle_software_caused_connection_abort(args, from), // Software caused connection abort -- this is we're here, need to remount, remember not to try this path again, and continue
-            Some(22) => copy_file(args, from, replace_forbidden_characters(to).as_path()),
-            Some(2) => Ok(()), // broken link, just continue - TODO: stat to confirm it was a link
-            _ => panic!("Error: {:#?} From: '{:#?}' To: '{:#?}'", e, from, to),
-        },
+    let mut builder = env_logger::Builder::new();
+    builder.filter_level(level).parse_default_env();
+    if let Some(log_file) = &args.log_file {
+        let file = fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(log_file)
+            .with_context(|| format!("can't open the log file '{}'", log_file.display()))?;
+        let log = LogFile {
+            file,
+            console: !args.log_file_only,
+        };
+        builder
+            .format(|buf, record| {
+                writeln!(buf, "[{} {:<5} {}] {}", buf.timestamp_millis(), record.level(), record.target(), record.args())
+            })
+            .target(env_logger::Target::Pipe(Box::new(log)));
     }
+    builder.init();
+    Ok(())
 }
 
-fn handle_software_caused_connection_abort(args: &Cli, path: &Path) -> Result<(), std::io::Error> {
-    println!(
-        "Software caused connection abort, remounting and continuing: {}",
-        &path.to_str().unwrap().to_string()
-    );
-    remember_failure(path);
-    remount(args);
-    println!("remounted, continuing");
+/// Where log lines go with `--log-file`, the console too unless `--log-file-only`
+struct LogFile {
+    file: fs::File,
+    console: bool,
+}
+
+impl Write for LogFile {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.console {
+            // the file still gets the line when stderr is gone
+            let _ = io::stderr().write_all(buf);
+        }
+        self.file.write_all(buf)?;
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.file.flush()
+    }
+}
+
+/// Exit code of a run that copied everything, or a verify run that found it all at the destination
+const EXIT_OK: i32 = 0;
+/// Exit code of a run that went through with failures in its report,
+/// or a verify run that found files missing, differing or unreadable
+const EXIT_FAILURES: i32 = 1;
+/// Exit code of a run that stopped early because the destination ran out of space, the volume needed more than
+/// `--max-total-remounts` remounts, or on an error it couldn't go on after
+const EXIT_ABORTED: i32 = 2;
+/// Exit code of a command line, config file or installation nothing could be run with
+const EXIT_USAGE: i32 = 3;
+/// Exit code of a run that went as far as `--max-files`, with more left to copy
+const EXIT_MAX_FILES: i32 = 4;
+/// Exit code of a run stopped by Ctrl-C, the shell convention for SIGINT
+const EXIT_INTERRUPTED: i32 = 130;
+
+/// Fails before anything is mounted or copied if the mount or umount command is empty,
+/// or a program they need isn't installed
+fn preflight(args: &MountArgs) -> Result<()> {
+    for (option, template) in [("--mount-command", &args.mount_command), ("--umount-command", &args.umount_command)] {
+        if template.trim().is_empty() {
+            bail!("{} is empty, it needs at least the program to run", option);
+        }
+    }
+    let mut programs = vec![];
+    if !args.no_sudo {
+        programs.push("sudo");
+    }
+    programs.extend(args.mount_command.split_whitespace().next());
+    programs.extend(args.umount_command.split_whitespace().next());
+    for program in programs {
+        if !is_installed(program) {
+            bail!("'{}' is not installed{}", program, install_hint(program));
+        }
+    }
     Ok(())
 }
 
-fn umount(mount_point: &str) {
-    let output = std::process::Command::new("sudo")
-        .arg("umount")
-        .arg(mount_point)
-        .output()
-        .expect("failed to execute umount");
-    println!("status: {}", output.status);
-    println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-    println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
-    if output.status.success() {
-        println!("umounted");
-    } else {
-        println!("failed to umount");
-    }
-    thread::sleep(time::Duration::from_secs(10));
-}
-
-fn mount(device: &str, mount_point: &str) {
-    let output = std::process::Command::new("sudo")
-        .arg("apfs-fuse")
-        .arg(device)
-        .arg(mount_point)
-        .output()
-        .expect("failed to execute mount");
-    println!("status: {}", output.status);
-    println!("stdout: {}", String::from_utf8_lossy(&output.stdout));
-    println!("stderr: {}", String::from_utf8_lossy(&output.stderr));
-    if output.status.success() {
-        println!("mounted");
-    } else {
-        umount(mount_point);
-        println!("failed to mount, retrying");
-        mount(device, mount_point);
+fn is_installed(program: &str) -> bool {
+    if program.contains('/') {
+        return Path::new(program).is_file();
     }
-    thread::sleep(time::Duration::from_secs(10));
+    env::var_os("PATH").is_some_and(|path| env::split_paths(&path).any(|dir| dir.join(program).is_file()))
 }
 
-fn remount(args: &Cli) {
-    println!("remounting");
-    umount(args.mount_point.as_str());
-    mount(args.device.as_str(), args.mount_point.as_str());
+fn install_hint(program: &str) -> &'static str {
+    match program {
+        "apfs-fuse" => ", build it from https://github.com/sgan81/apfs-fuse or install your distribution's apfs-fuse package",
+        "sudo" => ", install it or pass --no-sudo when running as root",
+        "umount" => ", it comes with util-linux",
+        _ => "",
+    }
+}
+
+/// A size like `700`, `1M` or `4G`, with suffixes in powers of 1024 and an optional `B` after them
+fn parse_size(size: &str) -> std::result::Result<u64, String> {
+    let upper = size.trim().to_ascii_uppercase();
+    let digits = upper.strip_suffix('B').unwrap_or(&upper);
+    let (digits, shift) = match digits.chars().last() {
+        Some(unit @ ('K' | 'M' | 'G' | 'T')) => (&digits[..digits.len() - 1], 10 * (1 + "KMGT".find(unit).unwrap())),
+        _ => (digits, 0),
+    };
+    let number: u64 = digits.trim().parse().map_err(|_| format!("'{}' isn't a size like 700, 1M or 4G", size))?;
+    number.checked_mul(1 << shift).ok_or_else(|| format!("'{}' is too big", size))
+}
+
+/// Bytes a second, written as a `parse_size`
+fn parse_rate(rate: &str) -> std::result::Result<u64, String> {
+    match parse_size(rate)? {
+        0 => Err("a rate of 0 would never copy anything".to_string()),
+        rate => Ok(rate),
+    }
+}
+
+/// `folder=ext,ext`, extensions with or without the dot and in any case
+fn parse_route(route: &str) -> std::result::Result<(String, Vec<String>), String> {
+    let invalid = || format!("'{}' isn't a folder and extensions like raw=cr2,nef", route);
+    let (folder, extensions) = route.split_once('=').ok_or_else(invalid)?;
+    let extensions: Vec<_> = extensions
+        .split(',')
+        .map(|extension| extension.trim().trim_start_matches('.').to_lowercase())
+        .filter(|extension| !extension.is_empty())
+        .collect();
+    if folder.is_empty() || extensions.is_empty() {
+        return Err(invalid());
+    }
+    Ok((folder.to_string(), extensions))
+}
+
+/// A `YYYY-MM-DD` date, midnight UTC, or an age counted back from now in `s`, `m`, `h`, `d` or `w`
+fn parse_newer_than(when: &str) -> std::result::Result<SystemTime, String> {
+    let invalid = || format!("'{}' isn't a date like 2023-01-01 or an age like 30d", when);
+    if let [year, month, day] = when.split('-').collect::<Vec<_>>()[..] {
+        let (year, month, day) = (
+            year.parse::<i64>().map_err(|_| invalid())?,
+            month.parse::<i64>().ok().filter(|m| (1..=12).contains(m)).ok_or_else(invalid)?,
+            day.parse::<i64>().ok().filter(|d| (1..=31).contains(d)).ok_or_else(invalid)?,
+        );
+        let secs = days_from_civil(year, month, day) * 86_400;
+        return u64::try_from(secs).map(|secs| UNIX_EPOCH + Duration::from_secs(secs)).map_err(|_| invalid());
+    }
+    let unit = match when.chars().last() {
+        Some('s') => 1,
+        Some('m') => 60,
+        Some('h') => 3_600,
+        Some('d') => 86_400,
+        Some('w') => 7 * 86_400,
+        _ => return Err(invalid()),
+    };
+    let count: u64 = when[..when.len() - 1].parse().map_err(|_| invalid())?;
+    count
+        .checked_mul(unit)
+        .and_then(|secs| SystemTime::now().checked_sub(Duration::from_secs(secs)))
+        .ok_or_else(invalid)
+}
+
+/// Days from 1970-01-01 to a date of the proleptic Gregorian calendar
+fn days_from_civil(year: i64, month: i64, day: i64) -> i64 {
+    let year = if month <= 2 { year - 1 } else { year };
+    let era = year.div_euclid(400);
+    let year_of_era = year - era * 400;
+    let day_of_year = (153 * ((month + 9) % 12) + 2) / 5 + day - 1;
+    let day_of_era = year_of_era * 365 + year_of_era / 4 - year_of_era / 100 + day_of_year;
+    era * 146_097 + day_of_era - 719_468
+}
+
+fn print_summary(report: &CopyReport) {
+    info!("directories created:        {}", report.directories_created);
+    info!("files copied:               {}", report.files_copied);
+    info!("bytes copied:               {}", report.bytes_copied);
+    info!("files already copied:       {}", report.files_already_copied);
+    info!("files skipped on I/O error: {}", report.files_skipped_io_error);
+    info!("files skipped for space:    {}", report.files_skipped_no_space);
+    info!("hidden paths skipped:       {}", report.paths_skipped_hidden);
+    info!("files skipped by size:      {}", report.files_skipped_by_size);
+    info!("files skipped by age:       {}", report.files_skipped_by_age);
+    info!("directories past max depth: {}", report.directories_skipped_by_depth);
+    info!("files failing verification: {}", report.files_failed_verification);
+    info!("symlinks skipped:           {}", report.skipped_symlinks.len());
+    info!("hard links deduplicated:    {}", report.deduplicated_hardlinks.len());
+    info!("paths abandoned on abort:   {}", report.paths_abandoned);
+    info!("failures recovered at end:  {}", report.failures_recovered);
+    info!("paths done by a checkpoint: {}", report.paths_skipped_by_checkpoint);
+    info!("files same as in manifest:  {}", report.files_unchanged_since_manifest);
+    info!("files skipped by collision: {}", report.files_skipped_by_collision);
+    info!("paths deleted by mirroring: {}", report.deleted.len());
+    print_slowest_directories(report);
+    if !report.unreadable.is_empty() {
+        warn!("these {} files couldn't be read and have no copy:", report.unreadable.len());
+        for path in &report.unreadable {
+            warn!("  {}", path);
+        }
+    }
 }
 
-fn remember_failure(path: &Path) {
-    let mut set = FAILED_CONNECTION_ABORTS.lock().unwrap();
-    set.insert(path.to_str().unwrap().to_string());
+/// What went wrong with a failed path, in the order they're listed
+const FAILURE_KINDS: &[&str] = &["connection aborts", "I/O errors", "permission denied", "names the destination rejected", "disk full", "other"];
+
+fn failure_kind(failure: &Failure) -> &'static str {
+    match failure.errno {
+        // Software caused connection abort
+        Some(103) => FAILURE_KINDS[0],
+        // Input/output error
+        Some(5) => FAILURE_KINDS[1],
+        // Operation not permitted, Permission denied
+        Some(1 | 13) => FAILURE_KINDS[2],
+        // File exists, Invalid argument, File name too long
+        Some(17 | 22 | 36) => FAILURE_KINDS[3],
+        // No space left on device
+        Some(28) => FAILURE_KINDS[4],
+        _ => FAILURE_KINDS[5],
+    }
 }
 
-fn is_failure(path: &Path) -> bool {
-    FAILED_CONNECTION_ABORTS
-        .lock()
-        .unwrap()
-        .contains(&path.to_str().unwrap().to_string())
+/// Directories shown as the slowest, the report has the time of every one
+const SLOWEST_SHOWN: usize = 5;
+
+/// Where the time went, a folder of tiny files or one that keeps aborting the connection shows up on top
+fn print_slowest_directories(report: &CopyReport) {
+    let slowest = report.slowest_directories(SLOWEST_SHOWN);
+    if slowest.is_empty() {
+        return;
+    }
+    info!("slowest directories:");
+    for (dir, throughput) in slowest {
+        let rate = throughput.bytes_copied as f64 / throughput.secs.max(0.001);
+        info!(
+            "  {}: {:.1}s for {} files, {}/s, {} remounts",
+            dir,
+            throughput.secs,
+            throughput.files,
+            indicatif::HumanBytes(rate as u64),
+            throughput.remounts
+        );
+    }
 }
 
-fn replace_forbidden_characters(path: &Path) -> PathBuf {
-    let mut new_path = PathBuf::from(path);
-    new_path.set_file_name(underscore_non_windows_chars(
-        path.file_name().unwrap().to_str().unwrap().to_string(),
-    ));
-    new_path
+/// Failures shown per kind, the report has them all
+const FAILURES_SHOWN: usize = 10;
+
+/// Failures grouped by kind on stderr, colored when it's a terminal and `NO_COLOR` isn't set
+fn print_failures(failures: &[Failure]) {
+    let heading = Style::new().bold().fg_color(Some(AnsiColor::Red.into()));
+    let kind = Style::new().bold().fg_color(Some(AnsiColor::Yellow.into()));
+    let reason = Style::new().dimmed();
+    anstream::eprintln!("{heading}{} paths failed:{heading:#}", failures.len());
+    for (name, group) in group_failures(failures) {
+        anstream::eprintln!("  {kind}{} ({}){kind:#}", name, group.len());
+        for failure in group.iter().take(FAILURES_SHOWN) {
+            anstream::eprintln!("    {}  {reason}{}{reason:#}", failure.path, failure.reason);
+        }
+        if group.len() > FAILURES_SHOWN {
+            anstream::eprintln!("    {reason}and {} more{reason:#}", group.len() - FAILURES_SHOWN);
+        }
+    }
 }
 
-fn underscore_non_windows_chars(filename: String) -> String {
-    // " * / : < > ? \ |
-    filename
-        .replace("\"", "_")
-        .replace("*", "_")
-        // when iterate through path components, first component is root, so we don't want to replace root
-        // todo: bypas firs component from escaping and used unchanged
-        //.replace("/", "_")
-        .replace(":", "_")
-        .replace("<", "_")
-        .replace(">", "_")
-        .replace("?", "_")
-        .replace("\\", "_")
-        .replace("|", "_")
+/// Kinds without failures are left out
+fn group_failures(failures: &[Failure]) -> Vec<(&'static str, Vec<&Failure>)> {
+    FAILURE_KINDS
+        .iter()
+        .map(|name| (*name, failures.iter().filter(|f| failure_kind(f) == *name).collect::<Vec<_>>()))
+        .filter(|(_, group)| !group.is_empty())
+        .collect()
 }
 
 #[cfg(test)]
 mod tests {
     #[test]
-    fn it_underscore_non_windows_chars() {
-        assert_eq!(
-            super::underscore_non_windows_chars("foo\"bar".to_string()),
-            "foo_bar".to_string()
-        );
-        assert_eq!(
-            super::underscore_non_windows_chars("foo*bar".to_string()),
-            "foo_bar".to_string()
-        );
-        /*assert_eq!(
-            super::underscore_non_windows_chars("foo/bar".to_string()),
-            "foo_bar".to_string()
-        );*/
-        assert_eq!(
-            super::underscore_non_windows_chars("foo:bar".to_string()),
-            "foo_bar".to_string()
-        );
-        assert_eq!(
-            super::underscore_non_windows_chars("foo<bar".to_string()),
-            "foo_bar".to_string()
-        );
-        assert_eq!(
-            super::underscore_non_windows_chars("foo>bar".to_string()),
-            "foo_bar".to_string()
-        );
-        assert_eq!(
-            super::underscore_non_windows_chars("foo\\bar".to_string()),
-            "foo_bar".to_string()
-        );
+    fn it_groups_failures_by_kind() {
+        let failure = |path: &str, errno| aspf_copier::Failure {
+            path: path.to_string(),
+            errno,
+            reason: String::new(),
+        };
+        let failures = [failure("a", Some(5)), failure("b", None), failure("c", Some(103)), failure("d", Some(5)), failure("e", Some(28))];
+        let groups: Vec<_> = super::group_failures(&failures)
+            .into_iter()
+            .map(|(kind, group)| (kind, group.iter().map(|f| f.path.as_str()).collect::<Vec<_>>()))
+            .collect();
         assert_eq!(
-            super::underscore_non_windows_chars("foo|bar".to_string()),
-            "foo_bar".to_string()
+            groups,
+            [
+                ("connection aborts", vec!["c"]),
+                ("I/O errors", vec!["a", "d"]),
+                ("disk full", vec!["e"]),
+                ("other", vec!["b"])
+            ]
         );
     }
+
+    #[test]
+    fn it_looks_up_programs_in_path() {
+        assert!(super::is_installed("sh"));
+        assert!(super::is_installed("/bin/sh"));
+        assert!(!super::is_installed("apfs-copier-no-such-program"));
+        assert!(!super::is_installed("/nonexistent/apfs-fuse"));
+
+        let preflight = |args: &[&str]| {
+            let argv = ["aspf-copier", "list", "--no-sudo"].iter().chain(args).chain(&["dev", "mnt", "src"]);
+            let args = super::parse_args(argv.map(std::ffi::OsString::from).collect()).unwrap();
+            super::preflight(args.command.mount())
+        };
+        assert!(preflight(&["--mount-command", "sh -c true", "--umount-command", "sh"]).is_ok());
+        let error = preflight(&["--mount-command", "  ", "--umount-command", "sh"]).unwrap_err();
+        assert!(error.to_string().contains("--mount-command is empty"), "{}", error);
+        assert!(preflight(&["--mount-command", "sh", "--umount-command", ""]).is_err());
+    }
+
+    #[test]
+    fn it_takes_settings_from_a_config_file_under_the_command_line() {
+        let path = std::env::temp_dir().join(format!("apfs-copier-config-{}.toml", std::process::id()));
+        std::fs::write(
+            &path,
+            "# the external disk\ndevice = \"/dev/sdb2\"\nmount-point = \"/mnt/apfs\"\nno-sudo = true\n\
+             exclude = [\"*.tmp\", \"Caches\"]\nmax-mount-attempts = 2\nquiet = true\n",
+        )
+        .unwrap();
+        let parse = |args: &[&str]| {
+            let argv = ["aspf-copier", "copy", "--config", path.to_str().unwrap()].into_iter().chain(args.iter().copied());
+            super::parse_args(argv.map(std::ffi::OsString::from).collect())
+        };
+        let args = parse(&["/mnt/apfs/Users", "/media/exfat"]).unwrap();
+        assert!(args.quiet);
+        let super::Command::Copy(copy) = args.command else { panic!("not a copy") };
+        assert_eq!(copy.mount.device, "/dev/sdb2");
+        assert_eq!(copy.mount.mount_point, "/mnt/apfs");
+        assert!(copy.mount.no_sudo);
+        assert_eq!(copy.mount.max_mount_attempts, 2);
+        assert_eq!(copy.mount.mount_timeout_secs, 30);
+        assert_eq!(copy.walk.exclude, ["*.tmp", "Caches"]);
+        assert_eq!(copy.paths.sources, [std::path::PathBuf::from("/mnt/apfs/Users")]);
+
+        let args = parse(&["--device", "/dev/sdc2", "--no-sudo=false", "--exclude", "*.log", "-v", "src", "dest"]).unwrap();
+        assert_eq!((args.quiet, args.verbose), (false, 1));
+        let super::Command::Copy(copy) = args.command else { panic!("not a copy") };
+        assert_eq!(copy.mount.device, "/dev/sdc2");
+        assert!(!copy.mount.no_sudo);
+        assert_eq!(copy.walk.exclude, ["*.log"]);
+        let config = copy.mount.to_config(&copy.walk, &copy.paths.sources, &copy.paths.sources[0]).unwrap();
+        assert!(format!("{:?}", config.mounter).contains(r#"options: ["ro"]"#));
+
+        let args = parse(&["--mount-option", "uid=1000", "--no-read-only", "src", "dest"]).unwrap();
+        let super::Command::Copy(copy) = args.command else { panic!("not a copy") };
+        let config = copy.mount.to_config(&copy.walk, &copy.paths.sources, &copy.paths.sources[0]).unwrap();
+        assert!(format!("{:?}", config.mounter).contains(r#"options: ["uid=1000"]"#));
+
+        std::fs::write(&path, "no-such-option = 1\n").unwrap();
+        assert!(parse(&["dev", "mnt", "src", "dest"]).is_err());
+        std::fs::remove_file(&path).unwrap();
+    }
+
+    #[test]
+    fn it_parses_routes() {
+        let route = |folder: &str, extensions: &[&str]| (folder.to_string(), extensions.iter().map(|e| e.to_string()).collect());
+        assert_eq!(super::parse_route("raw=cr2,NEF"), Ok(route("raw", &["cr2", "nef"])));
+        assert_eq!(super::parse_route("Scans=.tif, .pdf"), Ok(route("Scans", &["tif", "pdf"])));
+        assert!(super::parse_route("raw").is_err());
+        assert!(super::parse_route("=cr2").is_err());
+        assert!(super::parse_route("raw=,").is_err());
+    }
+
+    #[test]
+    fn it_parses_dates_and_ages() {
+        use std::time::{Duration, SystemTime, UNIX_EPOCH};
+        assert_eq!(super::parse_newer_than("1970-01-01"), Ok(UNIX_EPOCH));
+        assert_eq!(super::parse_newer_than("2023-01-01"), Ok(UNIX_EPOCH + Duration::from_secs(1_672_531_200)));
+        assert_eq!(super::parse_newer_than("2024-03-01"), Ok(UNIX_EPOCH + Duration::from_secs(1_709_251_200)));
+        let month_ago = super::parse_newer_than("30d").unwrap();
+        let age = SystemTime::now().duration_since(month_ago).unwrap();
+        assert!(age >= Duration::from_secs(30 * 86_400) && age < Duration::from_secs(30 * 86_400 + 60));
+        assert!(super::parse_newer_than("2023-13-01").is_err());
+        assert!(super::parse_newer_than("30").is_err());
+        assert!(super::parse_newer_than("d").is_err());
+    }
+
+    #[test]
+    fn it_parses_human_readable_sizes() {
+        assert_eq!(super::parse_size("700"), Ok(700));
+        assert_eq!(super::parse_size("1K"), Ok(1024));
+        assert_eq!(super::parse_size("1M"), Ok(1024 * 1024));
+        assert_eq!(super::parse_size("4g"), Ok(4 << 30));
+        assert_eq!(super::parse_size("2TB"), Ok(2 << 40));
+        assert!(super::parse_size("1.5G").is_err());
+        assert!(super::parse_size("M").is_err());
+        assert!(super::parse_size("99999999999T").is_err());
+    }
 }
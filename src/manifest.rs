@@ -0,0 +1,173 @@
+use anyhow::{Context, Result};
+use log::info;
+use serde::{Deserialize, Serialize};
+use std::{
+    collections::HashMap,
+    fs::{self, File, OpenOptions},
+    io::Write,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::Mutex,
+};
+
+use crate::{copy::Copied, names::replace_forbidden_characters, CopyConfig};
+
+/// The `manifest` file: one JSON line per copied file, appended right after the copy so an
+/// interrupted run keeps what it got through. Files skipped as already copied aren't listed again.
+pub(crate) struct Manifest {
+    path: PathBuf,
+    file: Mutex<File>,
+    /// Paths start with the name of their source when it isn't a single directory copied into `dest`
+    named_sources: bool,
+    dest: PathBuf,
+}
+
+#[derive(Serialize)]
+struct Entry<'a> {
+    path: String,
+    destination: String,
+    size: u64,
+    /// Of the source, in seconds since the Unix epoch
+    #[serde(skip_serializing_if = "Option::is_none")]
+    mtime: Option<i64>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    sha256: Option<&'a str>,
+}
+
+impl Manifest {
+    /// A dry run copies nothing, so there's nothing to write down
+    pub(crate) fn open(config: &CopyConfig) -> Result<Option<Manifest>> {
+        let Some(path) = config.manifest.as_ref().filter(|_| !config.dry_run) else {
+            return Ok(None);
+        };
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .with_context(|| format!("can't open manifest '{}'", path.display()))?;
+        Ok(Some(Manifest {
+            path: path.clone(),
+            file: Mutex::new(file),
+            named_sources: !config.extra_sources.is_empty() || config.source.is_file(),
+            dest: replace_forbidden_characters(config, &config.dest),
+        }))
+    }
+
+    /// `from` was copied from under `source`
+    pub(crate) fn record(&self, source: &Path, from: &Path, copied: &Copied) -> Result<()> {
+        let source = if self.named_sources { source.parent().unwrap_or(source) } else { source };
+        let entry = Entry {
+            path: relative(from, source),
+            destination: relative(&copied.to, &self.dest),
+            size: copied.bytes,
+            mtime: fs::metadata(from).map(|m| m.mtime()).ok(),
+            sha256: copied.sha256.as_deref(),
+        };
+        let mut line = serde_json::to_vec(&entry)?;
+        line.push(b'\n');
+        // a single write per line keeps lines from workers apart
+        self.file
+            .lock()
+            .unwrap()
+            .write_all(&line)
+            .with_context(|| format!("can't write manifest '{}'", self.path.display()))
+    }
+}
+
+fn relative(path: &Path, root: &Path) -> String {
+    path.strip_prefix(root).unwrap_or(path).display().to_string()
+}
+
+/// The files in `since_manifest`, with the size and modification time they had when they were copied
+pub(crate) struct SinceManifest {
+    /// By full source path
+    files: HashMap<PathBuf, (u64, i64)>,
+}
+
+/// The part of an `Entry` that tells whether the file changed
+#[derive(Deserialize)]
+struct Listed {
+    path: String,
+    size: u64,
+    mtime: Option<i64>,
+}
+
+impl SinceManifest {
+    /// Reads `since_manifest` against the sources in `roots`, if there is one. Paths went in relative to the source,
+    /// or to its parent when it had to be named, so the sources can be mounted elsewhere this time. Later lines win,
+    /// the same file may have been copied again by another run appending to the same manifest. Files listed without
+    /// a modification time are left for the copy to look at.
+    pub(crate) fn load(config: &CopyConfig, roots: &[CopyConfig]) -> Result<Option<SinceManifest>> {
+        let Some(file) = &config.since_manifest else {
+            return Ok(None);
+        };
+        let content = fs::read_to_string(file).with_context(|| format!("can't read manifest '{}'", file.display()))?;
+        let named_sources = !config.extra_sources.is_empty() || config.source.is_file();
+        let mut files = HashMap::new();
+        for (n, line) in content.lines().enumerate().filter(|(_, l)| !l.is_empty()) {
+            let listed: Listed = serde_json::from_str(line)
+                .with_context(|| format!("can't read line {} of manifest '{}'", n + 1, file.display()))?;
+            let Some(mtime) = listed.mtime else {
+                continue;
+            };
+            let path = Path::new(&listed.path);
+            let source = if named_sources {
+                let name = path.components().next().map(|c| c.as_os_str());
+                roots.iter().find(|root| root.source.file_name() == name).and_then(|root| root.source.parent())
+            } else {
+                Some(config.source.as_path())
+            };
+            // one of the sources of that run that isn't being copied now
+            let Some(source) = source else {
+                continue;
+            };
+            files.insert(source.join(path), (listed.size, mtime));
+        }
+        info!("loaded {} files copied before from '{}'", files.len(), file.display());
+        Ok(Some(SinceManifest { files }))
+    }
+
+    /// `path` is listed with the size and modification time it has now
+    pub(crate) fn is_unchanged(&self, path: &Path) -> bool {
+        let Some(&(size, mtime)) = self.files.get(path) else {
+            return false;
+        };
+        fs::metadata(path).is_ok_and(|m| m.len() == size && m.mtime() == mtime)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig};
+    use std::os::unix::fs::MetadataExt;
+
+    #[test]
+    fn it_lists_copied_files_with_their_hashes() {
+        let (source, dest) = test_dirs("manifest");
+        let manifest = source.parent().unwrap().join("manifest.jsonl");
+        std::fs::create_dir(source.join("sub")).unwrap();
+        std::fs::write(source.join("sub").join("a:b.txt"), "abc").unwrap();
+        let config = CopyConfig {
+            manifest: Some(manifest.clone()),
+            manifest_sha256: true,
+            ..CopyConfig::new("", "", source.clone(), dest)
+        };
+        let manifest_file = super::Manifest::open(&config).unwrap();
+        crate::copy::walk_and_copy(&config, manifest_file.as_ref(), None, None).unwrap();
+        // copied again, nothing new is listed
+        crate::copy::walk_and_copy(&config, manifest_file.as_ref(), None, None).unwrap();
+        let mtime = std::fs::metadata(source.join("sub").join("a:b.txt")).unwrap().mtime();
+        assert_eq!(
+            std::fs::read_to_string(&manifest).unwrap(),
+            format!(
+                concat!(
+                    r#"{{"path":"sub/a:b.txt","destination":"sub/a_b.txt","size":3,"mtime":{},"#,
+                    r#""sha256":"ba7816bf8f01cfea414140de5dae2223b00361a396177a9cb410ff61f20015ad"}}"#,
+                    "\n"
+                ),
+                mtime
+            )
+        );
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,110 @@
+use log::{info, warn};
+use std::{
+    collections::HashSet,
+    fs,
+    path::{Path, PathBuf},
+};
+
+use crate::{CopyConfig, CopyReport};
+
+/// The `mirror` pass over one destination directory: removes whatever in `dest_dir` isn't in `expected`,
+/// the destinations of every entry its source directory listed. Names are only ever mapped from the source
+/// to the destination, so an entry that was left out by filters or failed to copy still keeps its copy.
+pub(crate) fn remove_stale(config: &CopyConfig, report: &mut CopyReport, dest_dir: &Path, expected: &HashSet<PathBuf>) {
+    let entries = match fs::read_dir(dest_dir) {
+        Ok(entries) => entries,
+        // nothing was created yet in a dry run
+        Err(_) if config.dry_run => return,
+        Err(e) => {
+            warn!("can't list '{}' to mirror it: {}", dest_dir.display(), e);
+            return;
+        }
+    };
+    let own_files = own_files(config);
+    for entry in entries {
+        let path = match entry {
+            Ok(entry) => entry.path(),
+            Err(e) => {
+                warn!("can't list '{}' to mirror it: {}", dest_dir.display(), e);
+                return;
+            }
+        };
+        if expected.contains(&path) || fs::canonicalize(&path).is_ok_and(|p| own_files.contains(&p)) {
+            continue;
+        }
+        if config.dry_run {
+            info!("would delete '{}'", path.display());
+            report.record_deletion(&path);
+            continue;
+        }
+        let removed = match fs::symlink_metadata(&path) {
+            Ok(m) if m.is_dir() => fs::remove_dir_all(&path),
+            _ => fs::remove_file(&path),
+        };
+        match removed {
+            Ok(()) => {
+                info!("deleted '{}', it's not in the source", path.display());
+                report.record_deletion(&path);
+            }
+            Err(e) => warn!("can't delete '{}': {}", path.display(), e),
+        }
+    }
+}
+
+/// The files the run reads and writes may well sit on the destination too
+fn own_files(config: &CopyConfig) -> HashSet<PathBuf> {
+    [&config.state_file, &config.checkpoint, &config.manifest, &config.since_manifest, &config.events_jsonl]
+        .into_iter()
+        .flatten()
+        .chain(&config.mirror_keeps)
+        .filter_map(|file| fs::canonicalize(file).ok())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig};
+
+    #[test]
+    fn it_deletes_what_the_source_no_longer_has() {
+        let (source, dest) = test_dirs("mirror");
+        std::fs::create_dir(source.join("kept")).unwrap();
+        std::fs::write(source.join("kept").join("a:b.txt"), "a").unwrap();
+        std::fs::write(source.join("skipped.tmp"), "tmp").unwrap();
+        std::fs::create_dir_all(dest.join("gone").join("deeper")).unwrap();
+        std::fs::create_dir(dest.join("kept")).unwrap();
+        std::fs::write(dest.join("kept").join("old.txt"), "old").unwrap();
+        std::fs::write(dest.join("skipped.tmp"), "tmp").unwrap();
+        let config = CopyConfig {
+            mirror: true,
+            dry_run: true,
+            exclude: vec!["*.tmp".to_string()],
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let preview = crate::copy::walk_and_copy(&config, None, None, None).unwrap();
+        assert_eq!(preview.deleted.len(), 2);
+        assert!(dest.join("gone").exists());
+
+        let config = CopyConfig {
+            dry_run: false,
+            checkpoint: Some(dest.join("checkpoint")),
+            events_jsonl: Some(dest.join("events.jsonl")),
+            mirror_keeps: vec![dest.join("report.json")],
+            ..config
+        };
+        std::fs::write(dest.join("checkpoint"), "").unwrap();
+        std::fs::write(dest.join("events.jsonl"), "").unwrap();
+        std::fs::write(dest.join("report.json"), "{}").unwrap();
+        let report = crate::copy::walk_and_copy(&config, None, None, None).unwrap();
+        let mut deleted: Vec<_> = report.deleted.iter().map(|p| p.strip_prefix(dest.to_str().unwrap()).unwrap()).collect();
+        deleted.sort();
+        assert_eq!(deleted, ["/gone", "/kept/old.txt"]);
+        assert!(dest.join("kept").join("a_b.txt").exists());
+        // left out by the filters, not gone from the source
+        assert!(dest.join("skipped.tmp").exists());
+        assert!(dest.join("checkpoint").exists());
+        assert!(dest.join("events.jsonl").exists());
+        assert!(dest.join("report.json").exists());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,738 @@
+use anyhow::{bail, Context, Result};
+use log::{debug, error, info, warn};
+use std::{
+    fmt, fs, io,
+    path::{Path, PathBuf},
+    process::{Command, Output},
+    sync::{atomic::Ordering, RwLock},
+    thread, time,
+};
+
+use crate::{
+    config::absolute_path,
+    dest_fs::unescape,
+    events::{self, Event},
+    CopyConfig,
+};
+
+/// Counts remounts of every run so workers can tell whether one already remounted, `CopyConfig::remounts`
+/// counts them for a run. Anything reading the source holds a read lock, a remount holds the write lock,
+/// so it waits for in-flight copies and holds off new ones until the volume is back
+pub(crate) static MOUNT_GENERATION: RwLock<u64> = RwLock::new(0);
+
+/// Mounts at start unless the source is already readable from a mount at `mount_point`, and replaces a stale
+/// mount that's there but can't be read. Without `/proc/mounts` only a disconnected source gets mounted.
+pub(crate) fn initial_mount_check(config: &CopyConfig) -> Result<()> {
+    // no mount makes a source outside the mount point appear, so there's nothing to wait for
+    let outside = config.mount_point.is_empty() || !config.source.starts_with(absolute_path(Path::new(&config.mount_point))?);
+    if outside && config.source.symlink_metadata().is_err_and(|e| e.kind() == io::ErrorKind::NotFound) {
+        return check_source(config);
+    }
+    let readable = read_source(config.source.as_path());
+    let mount_now = match (mounted_at(config), readable) {
+        (Some(Some(mounted)), Ok(())) => {
+            info!("'{}' is already mounted at '{}' ({}), not mounting", mounted.device, config.mount_point, mounted.fs_type);
+            false
+        }
+        // No such file or directory, Not a directory -- the mount is fine, the source path isn't
+        (Some(Some(mounted)), Err(e)) if matches!(e.raw_os_error(), Some(2 | 20)) => {
+            info!("'{}' is already mounted at '{}' ({}), not mounting", mounted.device, config.mount_point, mounted.fs_type);
+            false
+        }
+        (Some(Some(mounted)), Err(e)) => {
+            warn!(
+                "'{}' is mounted at '{}' ({}) but the source can't be read: {}, mounting it again",
+                mounted.device, config.mount_point, mounted.fs_type, e
+            );
+            umount(config)?;
+            true
+        }
+        (Some(None), Ok(())) => {
+            info!("nothing is mounted at '{}' but the source is readable, not mounting", config.mount_point);
+            false
+        }
+        (Some(None), Err(e)) => {
+            info!("nothing is mounted at '{}' ({}), mounting at start", config.mount_point, e);
+            true
+        }
+        (None, Ok(())) => false,
+        // Transport endpoint is not connected
+        (None, Err(e)) => e.raw_os_error() == Some(107),
+    };
+    // a source that still isn't readable once mounted is for check_source to explain
+    if mount_now {
+        mount(config)?;
+    }
+    check_source(config)?;
+    info!("passed initial mount check");
+    Ok(())
+}
+
+/// Says what's wrong with a source that still can't be read, and warns about an empty one
+fn check_source(config: &CopyConfig) -> Result<()> {
+    let source = config.source.display();
+    match read_source(config.source.as_path()) {
+        Ok(()) => (),
+        Err(e) if e.raw_os_error() == Some(2) => return Err(SourceMissing(config.source.clone()).into()),
+        Err(e) if e.raw_os_error() == Some(20) => bail!("source '{}' is not a directory or a regular file", source),
+        Err(e) => return Err(e).with_context(|| format!("can't read source '{}'", source)),
+    }
+    if fs::read_dir(&config.source).is_ok_and(|mut entries| entries.next().is_none()) {
+        warn!("source '{}' is empty, there's nothing to copy", source);
+    }
+    Ok(())
+}
+
+/// The source isn't there, mounted or not
+#[derive(Debug)]
+pub struct SourceMissing(pub PathBuf);
+
+impl fmt::Display for SourceMissing {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "source '{}' does not exist, check the path", self.0.display())
+    }
+}
+
+impl std::error::Error for SourceMissing {}
+
+/// An entry of `/proc/mounts`
+#[derive(Debug)]
+struct Mounted {
+    device: String,
+    fs_type: String,
+}
+
+/// What's mounted at `mount_point`, or None when that can't be told
+fn mounted_at(config: &CopyConfig) -> Option<Option<Mounted>> {
+    if config.mount_point.is_empty() {
+        return None;
+    }
+    let mounts = fs::read_to_string("/proc/mounts").ok()?;
+    // a stale FUSE mount point can't be resolved
+    let mount_point = fs::canonicalize(&config.mount_point).unwrap_or_else(|_| Path::new(&config.mount_point).to_path_buf());
+    Some(find_mount(&mounts, &mount_point))
+}
+
+/// The last mount at `mount_point` in `/proc/mounts`-style `mounts` is the one on top
+fn find_mount(mounts: &str, mount_point: &Path) -> Option<Mounted> {
+    mounts.lines().rev().find_map(|line| {
+        let mut fields = line.split(' ');
+        let (device, point, fs_type) = (fields.next()?, fields.next()?, fields.next()?);
+        (unescape(point) == mount_point).then(|| Mounted {
+            device: unescape(device).display().to_string(),
+            fs_type: fs_type.to_string(),
+        })
+    })
+}
+
+/// Remounts unless another worker already did since `generation` was read, since their in-flight
+/// copies all abort together when the volume goes away. Fails with `RemountsExhausted` instead
+/// once the run used up `max_total_remounts`.
+pub(crate) fn remount_since(config: &CopyConfig, generation: u64) -> Result<()> {
+    let mut current = MOUNT_GENERATION.write().unwrap();
+    if *current != generation {
+        debug!("already remounted by another worker");
+        return Ok(());
+    }
+    let remounts = config.remounts.load(Ordering::Relaxed);
+    if let Some(max) = config.max_total_remounts.filter(|max| remounts >= u64::from(*max)) {
+        error!("the volume needs remounting again after {} remounts, stopping", max);
+        return Err(RemountsExhausted.into());
+    }
+    remount(config)?;
+    *current += 1;
+    let remounts = config.remounts.fetch_add(1, Ordering::Relaxed) + 1;
+    events::emit(config, Event::Remount { remounts });
+    match config.max_total_remounts {
+        Some(max) => info!("remount {} of at most {}", remounts, max),
+        None => info!("remount {} so far", remounts),
+    }
+    Ok(())
+}
+
+/// The volume needed another remount after `max_total_remounts`, the run stops there and keeps what it has done so far
+#[derive(Debug)]
+pub(crate) struct RemountsExhausted;
+
+impl fmt::Display for RemountsExhausted {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(f, "ran out of remounts, the volume keeps dropping")
+    }
+}
+
+impl std::error::Error for RemountsExhausted {}
+
+/// Runs the external mount/umount commands, a test can put a fake in `CopyConfig::mounter`
+pub trait Mounter: fmt::Debug + Send + Sync {
+    fn mount(&self, device: &str, mount_point: &str) -> std::io::Result<Output>;
+    fn umount(&self, mount_point: &str) -> std::io::Result<Output>;
+
+    /// Detaches a busy mount point right away and cleans up once nothing uses it, a plain umount unless overridden
+    fn lazy_umount(&self, mount_point: &str) -> std::io::Result<Output> {
+        self.umount(mount_point)
+    }
+}
+
+pub const DEFAULT_MOUNT_COMMAND: &str = "apfs-fuse {device} {mountpoint}";
+pub const DEFAULT_UMOUNT_COMMAND: &str = "umount {mountpoint}";
+
+/// Runs `mount_command` and `umount_command`, with `sudo` in front unless told otherwise.
+/// `{device}` and `{mountpoint}` in the commands are replaced after splitting them on whitespace,
+/// so paths with spaces stay single arguments.
+pub struct SystemMounter {
+    mount_command: String,
+    umount_command: String,
+    sudo: bool,
+    password: Option<String>,
+    volume: Option<u32>,
+    options: Vec<String>,
+}
+
+impl SystemMounter {
+    pub fn new(mount_command: impl Into<String>, umount_command: impl Into<String>, sudo: bool) -> SystemMounter {
+        SystemMounter {
+            mount_command: mount_command.into(),
+            umount_command: umount_command.into(),
+            sudo,
+            password: None,
+            volume: None,
+            options: vec![],
+        }
+    }
+
+    /// Unlocks an encrypted volume, passed to the mount command as `-r <password>` right after the program.
+    /// It still shows up in the process list while the mount command runs, that's how `apfs-fuse` takes it.
+    pub fn with_password(mut self, password: impl Into<String>) -> SystemMounter {
+        self.password = Some(password.into());
+        self
+    }
+
+    /// Picks a volume of the container by index, passed to the mount command as `-v <index>` right after the program
+    pub fn with_volume(mut self, volume: u32) -> SystemMounter {
+        self.volume = Some(volume);
+        self
+    }
+
+    /// Passed to the mount command as `-o <option>` right after the program, after the volume and the password.
+    /// Every call adds one more, like `ro` to leave a damaged volume alone or `uid=1000` to read everything.
+    pub fn with_mount_option(mut self, option: impl Into<String>) -> SystemMounter {
+        self.options.push(option.into());
+        self
+    }
+
+    fn mount_args(&self, device: &str, mount_point: &str) -> Vec<String> {
+        let mut args = fill_in(&self.mount_command, device, mount_point);
+        let mut options = vec![];
+        if let Some(volume) = self.volume {
+            options.extend(["-v".to_string(), volume.to_string()]);
+        }
+        if let Some(password) = &self.password {
+            options.extend(["-r".to_string(), password.clone()]);
+        }
+        for option in &self.options {
+            options.extend(["-o".to_string(), option.clone()]);
+        }
+        let after_program = 1.min(args.len());
+        args.splice(after_program..after_program, options);
+        args
+    }
+
+    fn umount_args(&self, mount_point: &str) -> Vec<String> {
+        fill_in(&self.umount_command, "", mount_point)
+    }
+
+    /// `-l` right after the program, the way `umount` takes it
+    fn lazy_umount_args(&self, mount_point: &str) -> Vec<String> {
+        let mut args = self.umount_args(mount_point);
+        args.insert(1.min(args.len()), "-l".to_string());
+        args
+    }
+
+    fn command(&self, args: &[String]) -> Command {
+        let (program, args) = if self.sudo {
+            ("sudo", args)
+        } else {
+            (args.first().map(String::as_str).unwrap_or_default(), args.get(1..).unwrap_or_default())
+        };
+        let mut command = Command::new(program);
+        command.args(args);
+        command
+    }
+}
+
+fn fill_in(template: &str, device: &str, mount_point: &str) -> Vec<String> {
+    template
+        .split_whitespace()
+        .map(|arg| arg.replace("{device}", device).replace("{mountpoint}", mount_point))
+        .collect()
+}
+
+impl fmt::Debug for SystemMounter {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        f.debug_struct("SystemMounter")
+            .field("mount_command", &self.mount_command)
+            .field("umount_command", &self.umount_command)
+            .field("sudo", &self.sudo)
+            .field("password", &self.password.as_ref().map(|_| "<redacted>"))
+            .field("volume", &self.volume)
+            .field("options", &self.options)
+            .finish()
+    }
+}
+
+impl Default for SystemMounter {
+    fn default() -> SystemMounter {
+        SystemMounter::new(DEFAULT_MOUNT_COMMAND, DEFAULT_UMOUNT_COMMAND, true)
+    }
+}
+
+impl Mounter for SystemMounter {
+    fn mount(&self, device: &str, mount_point: &str) -> std::io::Result<Output> {
+        self.command(&self.mount_args(device, mount_point)).output()
+    }
+
+    fn umount(&self, mount_point: &str) -> std::io::Result<Output> {
+        self.command(&self.umount_args(mount_point)).output()
+    }
+
+    fn lazy_umount(&self, mount_point: &str) -> std::io::Result<Output> {
+        self.command(&self.lazy_umount_args(mount_point)).output()
+    }
+}
+
+/// A busy mount point gets this many plain umounts before giving up or trying a lazy one
+const UMOUNT_ATTEMPTS: u32 = 2;
+
+/// A file handle that's still open keeps the mount point busy for a moment, so that's tried again,
+/// and with `allow_lazy_umount` the mount point is detached lazily if it stays busy. A failed umount that leaves
+/// something mounted at `mount_point` counts as busy, whatever language umount said it in.
+pub(crate) fn umount(config: &CopyConfig) -> Result<()> {
+    let mut busy = false;
+    for attempt in 1..=UMOUNT_ATTEMPTS {
+        let output = config.mounter.umount(&config.mount_point).context("failed to execute umount")?;
+        debug!("status: {}", output.status);
+        debug!("stdout: {}", String::from_utf8_lossy(&output.stdout));
+        debug!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+        if output.status.success() {
+            info!("umounted");
+            busy = false;
+            break;
+        }
+        busy = String::from_utf8_lossy(&output.stderr).contains("busy") || mounted_at(config).is_some_and(|m| m.is_some());
+        if !busy {
+            warn!("failed to umount");
+            break;
+        }
+        warn!("mount point is busy, umount attempt {} of {}", attempt, UMOUNT_ATTEMPTS);
+        if attempt < UMOUNT_ATTEMPTS {
+            thread::sleep(config.mount_retry_delay);
+        }
+    }
+    if busy && config.allow_lazy_umount {
+        let output = config.mounter.lazy_umount(&config.mount_point).context("failed to execute umount")?;
+        debug!("status: {}", output.status);
+        debug!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+        if output.status.success() {
+            info!("umounted lazily, the old mount goes away once nothing uses it");
+        } else {
+            warn!("failed to umount lazily");
+        }
+    } else if busy {
+        warn!("mount point stays busy, --allow-lazy-umount would detach it anyway");
+    }
+    // a disconnected FUSE mount point can't be listed, a plain directory can
+    if !wait_until_readable(config, Path::new(&config.mount_point)) {
+        warn!("mount point is still not readable, continuing anyway");
+    }
+    Ok(())
+}
+
+/// Tries to mount up to `max_mount_attempts` times, doubling the delay between attempts, and tells whether
+/// the source became readable within `mount_timeout` once it's mounted
+fn mount(config: &CopyConfig) -> Result<bool> {
+    let mut delay = config.mount_retry_delay;
+    for attempt in 1..=config.max_mount_attempts {
+        let output = config
+            .mounter
+            .mount(&config.device, &config.mount_point)
+            .context("failed to execute mount")?;
+        debug!("status: {}", output.status);
+        debug!("stdout: {}", String::from_utf8_lossy(&output.stdout));
+        debug!("stderr: {}", String::from_utf8_lossy(&output.stderr));
+        if output.status.success() {
+            info!("mounted");
+            let readable = wait_until_readable(config, config.source.as_path());
+            if !config.mount_settle.is_zero() {
+                debug!("letting the mount settle for {:?}", config.mount_settle);
+                thread::sleep(config.mount_settle);
+            }
+            return Ok(readable);
+        }
+        warn!("failed to mount, attempt {} of {}", attempt, config.max_mount_attempts);
+        umount(config)?;
+        if attempt < config.max_mount_attempts {
+            info!("retrying in {:?}", delay);
+            thread::sleep(delay);
+            delay *= 2;
+        }
+    }
+    bail!(
+        "failed to mount '{}' at '{}' after {} attempts",
+        config.device,
+        config.mount_point,
+        config.max_mount_attempts
+    )
+}
+
+/// apfs-fuse sometimes comes back mounted but broken, so the source has to be readable again before anything
+/// goes on, or the next read aborts right away. Tries up to `max_mount_attempts` times, a mount that keeps
+/// failing counts as one failed attempt.
+fn remount(config: &CopyConfig) -> Result<()> {
+    for attempt in 1..=config.max_mount_attempts {
+        info!("remounting");
+        umount(config)?;
+        match mount(config) {
+            Ok(true) => return Ok(()),
+            Ok(false) => {
+                warn!("source still isn't readable after remount attempt {} of {}", attempt, config.max_mount_attempts)
+            }
+            Err(e) => warn!("remount attempt {} of {} failed: {:#}", attempt, config.max_mount_attempts, e),
+        }
+    }
+    bail!(
+        "source '{}' is still not readable after {} remounts",
+        config.source.display(),
+        config.max_mount_attempts
+    )
+}
+
+/// A directory source has to be listable, a file one only has to be there
+fn read_source(path: &Path) -> io::Result<()> {
+    if path.is_file() {
+        return fs::metadata(path).map(drop);
+    }
+    fs::read_dir(path).map(drop)
+}
+
+/// Polls `path` until it can be listed or `mount_timeout` runs out
+fn wait_until_readable(config: &CopyConfig, path: &Path) -> bool {
+    let deadline = time::Instant::now() + config.mount_timeout;
+    loop {
+        if read_source(path).is_ok() {
+            return true;
+        }
+        if time::Instant::now() >= deadline {
+            return false;
+        }
+        thread::sleep(config.mount_poll_interval);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{Mounter, SystemMounter, MOUNT_GENERATION};
+    use crate::CopyConfig;
+    use std::{
+        os::unix::process::ExitStatusExt,
+        path::{Path, PathBuf},
+        process::{ExitStatus, Output},
+        sync::{atomic::Ordering, Arc, Mutex},
+        time::Duration,
+    };
+
+    /// Fails the first `failing_mounts` mounts and records every call
+    #[derive(Debug, Default)]
+    struct FakeMounter {
+        failing_mounts: u32,
+        /// Fails the first plain umounts with "target is busy"
+        busy_umounts: u32,
+        /// Said by a busy umount instead of "target is busy"
+        busy_message: Option<&'static str>,
+        calls: Mutex<Vec<&'static str>>,
+    }
+
+    impl FakeMounter {
+        fn output(&self, call: &'static str, success: bool, stderr: &str) -> std::io::Result<Output> {
+            self.calls.lock().unwrap().push(call);
+            Ok(Output {
+                status: ExitStatus::from_raw(if success { 0 } else { 1 << 8 }),
+                stdout: vec![],
+                stderr: if success { vec![] } else { stderr.as_bytes().to_vec() },
+            })
+        }
+
+        fn count(&self, call: &str) -> u32 {
+            self.calls.lock().unwrap().iter().filter(|c| **c == call).count() as u32
+        }
+    }
+
+    impl Mounter for FakeMounter {
+        fn mount(&self, _device: &str, _mount_point: &str) -> std::io::Result<Output> {
+            self.output("mount", self.count("mount") >= self.failing_mounts, "mount failed")
+        }
+
+        fn umount(&self, _mount_point: &str) -> std::io::Result<Output> {
+            let busy = self.busy_message.unwrap_or("umount: /mnt/mac: target is busy.");
+            self.output("umount", self.count("umount") >= self.busy_umounts, busy)
+        }
+
+        fn lazy_umount(&self, _mount_point: &str) -> std::io::Result<Output> {
+            self.output("umount -l", true, "")
+        }
+    }
+
+    /// Held by the tests that remount, every test in the process moves the same `MOUNT_GENERATION`
+    static REMOUNTING: Mutex<()> = Mutex::new(());
+
+    fn fake_config(mounter: &Arc<FakeMounter>) -> CopyConfig {
+        let tmp = std::env::temp_dir();
+        CopyConfig {
+            max_mount_attempts: 3,
+            mount_retry_delay: Duration::from_millis(1),
+            mounter: mounter.clone(),
+            ..CopyConfig::new("/dev/null", tmp.to_str().unwrap(), tmp.clone(), tmp.clone())
+        }
+    }
+
+    fn program_and_args(command: &std::process::Command) -> Vec<String> {
+        std::iter::once(command.get_program())
+            .chain(command.get_args())
+            .map(|a| a.to_string_lossy().into_owned())
+            .collect()
+    }
+
+    #[test]
+    fn it_builds_mount_commands_from_templates() {
+        let default = SystemMounter::default();
+        assert_eq!(
+            program_and_args(&default.command(&default.mount_args("/dev/sdb2", "/mnt/my mac"))),
+            ["sudo", "apfs-fuse", "/dev/sdb2", "/mnt/my mac"]
+        );
+        let custom = SystemMounter::new(
+            "/opt/apfs/bin/apfs-fuse -o allow_other {device} {mountpoint}",
+            "fusermount -u {mountpoint}",
+            false,
+        );
+        assert_eq!(
+            program_and_args(&custom.command(&custom.mount_args("/dev/sdb2", "/mnt/mac"))),
+            ["/opt/apfs/bin/apfs-fuse", "-o", "allow_other", "/dev/sdb2", "/mnt/mac"]
+        );
+        assert_eq!(
+            program_and_args(&custom.command(&custom.umount_args("/mnt/mac"))),
+            ["fusermount", "-u", "/mnt/mac"]
+        );
+    }
+
+    #[test]
+    fn it_passes_password_without_showing_it() {
+        let mounter = SystemMounter::default().with_password("hunter2");
+        assert_eq!(
+            program_and_args(&mounter.command(&mounter.mount_args("/dev/sdb2", "/mnt/mac"))),
+            ["sudo", "apfs-fuse", "-r", "hunter2", "/dev/sdb2", "/mnt/mac"]
+        );
+        assert_eq!(
+            program_and_args(&mounter.command(&mounter.umount_args("/mnt/mac"))),
+            ["sudo", "umount", "/mnt/mac"]
+        );
+        assert!(!format!("{:?}", mounter).contains("hunter2"));
+    }
+
+    #[test]
+    fn it_selects_volume_before_the_password() {
+        let mounter = SystemMounter::new(super::DEFAULT_MOUNT_COMMAND, super::DEFAULT_UMOUNT_COMMAND, false)
+            .with_volume(1)
+            .with_password("hunter2");
+        assert_eq!(
+            program_and_args(&mounter.command(&mounter.mount_args("/dev/sdb2", "/mnt/mac"))),
+            ["apfs-fuse", "-v", "1", "-r", "hunter2", "/dev/sdb2", "/mnt/mac"]
+        );
+    }
+
+    #[test]
+    fn it_passes_every_mount_option() {
+        let mounter = SystemMounter::default().with_volume(1).with_mount_option("ro").with_mount_option("uid=1000");
+        assert_eq!(
+            program_and_args(&mounter.command(&mounter.mount_args("/dev/sdb2", "/mnt/mac"))),
+            ["sudo", "apfs-fuse", "-v", "1", "-o", "ro", "-o", "uid=1000", "/dev/sdb2", "/mnt/mac"]
+        );
+        assert_eq!(program_and_args(&mounter.command(&mounter.umount_args("/mnt/mac"))), ["sudo", "umount", "/mnt/mac"]);
+    }
+
+    #[test]
+    fn it_lets_a_new_mount_settle_only_when_asked() {
+        let mounter = Arc::new(FakeMounter::default());
+        let started = std::time::Instant::now();
+        super::mount(&fake_config(&mounter)).unwrap();
+        assert!(started.elapsed() < Duration::from_millis(100));
+        let config = CopyConfig {
+            mount_settle: Duration::from_millis(100),
+            ..fake_config(&mounter)
+        };
+        let started = std::time::Instant::now();
+        super::mount(&config).unwrap();
+        assert!(started.elapsed() >= Duration::from_millis(100));
+        assert_eq!(mounter.count("mount"), 2);
+    }
+
+    #[test]
+    fn it_says_when_the_source_does_not_exist() {
+        let config = CopyConfig::new("", "", std::env::temp_dir().join("apfs-copier-typo"), std::env::temp_dir());
+        let error = super::initial_mount_check(&config).unwrap_err();
+        assert!(error.to_string().contains("does not exist"), "{}", error);
+
+        // outside the mount point it's gone without a mount, and without waiting for one
+        let mounter = Arc::new(FakeMounter::default());
+        let config = CopyConfig {
+            source: PathBuf::from("/nonexistent/apfs-copier-typo"),
+            mount_timeout: Duration::from_secs(30),
+            ..fake_config(&mounter)
+        };
+        let started = std::time::Instant::now();
+        let error = super::initial_mount_check(&config).unwrap_err();
+        assert!(error.is::<super::SourceMissing>(), "{}", error);
+        assert!(started.elapsed() < Duration::from_secs(1));
+        assert_eq!(mounter.count("mount"), 0);
+    }
+
+    #[test]
+    fn it_finds_what_is_mounted_at_the_mount_point() {
+        let mounts = "/dev/sda1 / ext4 rw 0 0\n\
+                      /dev/sdb2 /mnt/mac fuse.apfs-fuse rw 0 0\n\
+                      /dev/sdc2 /mnt/mac fuse.apfs-fuse rw 0 0\n\
+                      /dev/sdd1 /mnt/My\\040Mac fuseblk rw 0 0\n";
+        let found = |mount_point: &str| super::find_mount(mounts, Path::new(mount_point)).map(|m| m.device);
+        assert_eq!(found("/mnt/mac").as_deref(), Some("/dev/sdc2"));
+        assert_eq!(found("/mnt/mac/").as_deref(), Some("/dev/sdc2"));
+        assert_eq!(found("/mnt/My Mac").as_deref(), Some("/dev/sdd1"));
+        assert_eq!(found("/mnt"), None);
+    }
+
+    #[test]
+    fn it_unmounts_lazily_only_when_allowed() {
+        let mounter = Arc::new(FakeMounter {
+            busy_umounts: u32::MAX,
+            ..Default::default()
+        });
+        super::umount(&fake_config(&mounter)).unwrap();
+        assert_eq!(*mounter.calls.lock().unwrap(), ["umount", "umount"]);
+
+        let mounter = Arc::new(FakeMounter {
+            busy_umounts: u32::MAX,
+            ..Default::default()
+        });
+        let config = CopyConfig {
+            allow_lazy_umount: true,
+            ..fake_config(&mounter)
+        };
+        super::umount(&config).unwrap();
+        assert_eq!(*mounter.calls.lock().unwrap(), ["umount", "umount", "umount -l"]);
+
+        // still mounted after a plain umount, told in another language
+        let mounter = Arc::new(FakeMounter {
+            busy_umounts: u32::MAX,
+            busy_message: Some("umount: /proc: Das Ziel ist beschäftigt."),
+            ..Default::default()
+        });
+        let config = CopyConfig {
+            mount_point: "/proc".to_string(),
+            allow_lazy_umount: true,
+            ..fake_config(&mounter)
+        };
+        super::umount(&config).unwrap();
+        assert_eq!(*mounter.calls.lock().unwrap(), ["umount", "umount", "umount -l"]);
+    }
+
+    #[test]
+    fn it_adds_the_lazy_flag_after_the_program() {
+        let mounter = SystemMounter::new(super::DEFAULT_MOUNT_COMMAND, super::DEFAULT_UMOUNT_COMMAND, true);
+        assert_eq!(
+            program_and_args(&mounter.command(&mounter.lazy_umount_args("/mnt/mac"))),
+            ["sudo", "umount", "-l", "/mnt/mac"]
+        );
+    }
+
+    #[test]
+    fn it_gives_up_mounting_after_max_attempts() {
+        let mounter = Arc::new(FakeMounter {
+            failing_mounts: u32::MAX,
+            ..Default::default()
+        });
+        assert!(super::mount(&fake_config(&mounter)).is_err());
+        let calls = mounter.calls.lock().unwrap();
+        assert_eq!(*calls, ["mount", "umount", "mount", "umount", "mount", "umount"]);
+    }
+
+    #[test]
+    fn it_retries_mounting_until_it_succeeds() {
+        let mounter = Arc::new(FakeMounter {
+            failing_mounts: 2,
+            ..Default::default()
+        });
+        super::mount(&fake_config(&mounter)).unwrap();
+        let calls = mounter.calls.lock().unwrap();
+        assert_eq!(*calls, ["mount", "umount", "mount", "umount", "mount"]);
+    }
+
+    #[test]
+    fn it_remounts_once_per_generation() {
+        let mounter = Arc::new(FakeMounter::default());
+        let config = fake_config(&mounter);
+        let _remounting = REMOUNTING.lock().unwrap_or_else(|e| e.into_inner());
+        let generation = *MOUNT_GENERATION.read().unwrap();
+        super::remount_since(&config, generation).unwrap();
+        // a second worker aborted on the same mount, the volume is already back
+        super::remount_since(&config, generation).unwrap();
+        assert_eq!(*mounter.calls.lock().unwrap(), ["umount", "mount"]);
+        assert_eq!(*MOUNT_GENERATION.read().unwrap(), generation + 1);
+        assert_eq!(config.remounts.load(Ordering::Relaxed), 1);
+    }
+
+    #[test]
+    fn it_remounts_again_while_the_source_is_unreadable() {
+        let mounter = Arc::new(FakeMounter::default());
+        let config = CopyConfig {
+            source: std::env::temp_dir().join("apfs-copier-no-such-source"),
+            mount_timeout: Duration::from_millis(1),
+            ..fake_config(&mounter)
+        };
+        assert!(super::remount(&config).is_err());
+        assert_eq!(*mounter.calls.lock().unwrap(), ["umount", "mount", "umount", "mount", "umount", "mount"]);
+    }
+
+    #[test]
+    fn it_remounts_again_after_a_mount_that_kept_failing() {
+        let mounter = Arc::new(FakeMounter {
+            failing_mounts: 3,
+            ..Default::default()
+        });
+        super::remount(&fake_config(&mounter)).unwrap();
+        assert_eq!(mounter.count("mount"), 4);
+        assert_eq!(mounter.calls.lock().unwrap().last(), Some(&"mount"));
+    }
+
+    #[test]
+    fn it_stops_remounting_once_the_budget_is_used_up() {
+        let mounter = Arc::new(FakeMounter::default());
+        let config = CopyConfig {
+            max_total_remounts: Some(0),
+            ..fake_config(&mounter)
+        };
+        let _remounting = REMOUNTING.lock().unwrap_or_else(|e| e.into_inner());
+        let generation = *MOUNT_GENERATION.read().unwrap();
+        let error = super::remount_since(&config, generation).unwrap_err();
+        assert!(error.is::<super::RemountsExhausted>());
+        assert!(mounter.calls.lock().unwrap().is_empty());
+
+        // what an earlier run used up doesn't count against the next one
+        let config = CopyConfig {
+            max_total_remounts: Some(1),
+            ..fake_config(&mounter)
+        };
+        config.remounts.store(1, Ordering::Relaxed);
+        let run = config.for_run().unwrap();
+        let generation = *MOUNT_GENERATION.read().unwrap();
+        super::remount_since(&run, generation).unwrap();
+        assert_eq!(run.remounts.load(Ordering::Relaxed), 1);
+        assert_eq!(config.remounts.load(Ordering::Relaxed), 1);
+    }
+}
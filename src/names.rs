@@ -0,0 +1,548 @@
+use anyhow::{bail, Result};
+use log::warn;
+use sha2::{Digest, Sha256};
+use unicode_normalization::UnicodeNormalization;
+use std::{
+    cmp::Reverse,
+    collections::HashMap,
+    ffi::{OsStr, OsString},
+    fs,
+    path::{Component, Path, PathBuf},
+};
+
+use crate::{CopyConfig, Normalize, OnCollision, TargetFs};
+
+/// Rewrites every component, since a parent directory name can be just as illegal as the file name
+pub(crate) fn replace_forbidden_characters(config: &CopyConfig, path: &Path) -> PathBuf {
+    path.components()
+        .map(|c| match c {
+            Component::Normal(name) => OsString::from(sanitize_name(config, name)),
+            // root, `.` and `..` are not names at the destination
+            other => other.as_os_str().to_os_string(),
+        })
+        .collect()
+}
+
+/// Characters every Windows filesystem rejects in names, `/` can't be in a source name anyway
+const WINDOWS_FORBIDDEN: &str = "\"*:<>?\\|";
+
+/// What `config.target_fs` takes in a name
+struct NameRules<'a> {
+    /// Printable characters it rejects
+    forbidden: &'a str,
+    /// 0x00-0x1F, and DEL too with `forbids_del`
+    forbids_controls: bool,
+    forbids_del: bool,
+    /// Trailing dots and spaces are dropped, names are cut to 255 UTF-16 units and case is ignored
+    windows: bool,
+}
+
+impl NameRules<'_> {
+    fn of(config: &CopyConfig) -> NameRules<'_> {
+        let windows = config.target_fs != TargetFs::None;
+        NameRules {
+            forbidden: config.forbidden_chars.as_deref().unwrap_or(if windows { WINDOWS_FORBIDDEN } else { "" }),
+            forbids_controls: windows,
+            forbids_del: config.target_fs == TargetFs::Exfat,
+            windows,
+        }
+    }
+
+    fn forbids(&self, c: char) -> bool {
+        self.forbidden.contains(c) || (self.forbids_controls && c.is_ascii_control() && (c != '\u{7f}' || self.forbids_del))
+    }
+}
+
+/// Turns a single source name into one the destination can store
+pub(crate) fn sanitize_name(config: &CopyConfig, name: &OsStr) -> String {
+    let rules = NameRules::of(config);
+    let original = name;
+    let name = normalize(config.normalize, lossy_name(name));
+    let unnameable = has_nothing_to_keep(&rules, &name);
+    let name = underscore_forbidden_chars(&rules, name, config.replacement_char);
+    let name = if rules.windows { trim_trailing_dots_and_spaces(name) } else { name };
+    let name = if config.rename_reserved_names { prefix_reserved_name(name) } else { name };
+    let name = if rules.windows { truncate_to_exfat_length(name) } else { name };
+    if unnameable || name.is_empty() {
+        return unnamed(original);
+    }
+    name
+}
+
+/// Nothing but characters that get replaced or trimmed, so all that would be left of the name is underscores or nothing
+fn has_nothing_to_keep(rules: &NameRules, filename: &str) -> bool {
+    filename.chars().all(|c| rules.forbids(c) || (rules.windows && matches!(c, '.' | ' ')))
+}
+
+/// `_unnamed_` and the start of the SHA-256 of the original name, the same on every run,
+/// different for different names as far as a directory's worth of them goes
+fn unnamed(name: &OsStr) -> String {
+    let hash = format!("{:x}", Sha256::digest(name.as_encoded_bytes()));
+    let unnamed = format!("_unnamed_{}", &hash[..8]);
+    warn!("{:?} has nothing the destination can keep in a name, copying it as '{}'", name, unnamed);
+    unnamed
+}
+
+/// Two sources whose names sanitize to the same destination must not share it, so every one after the first
+/// gets a numeric suffix. Windows filesystems ignore case, so `File.txt` and `file.txt` from a case-sensitive volume
+/// are the same destination there too. A destination left on disk by an earlier run is assumed to belong to the same source.
+pub(crate) fn claim_destination(
+    config: &CopyConfig,
+    claimed: &mut HashMap<PathBuf, PathBuf>,
+    source: &Path,
+    dest: PathBuf,
+) -> PathBuf {
+    let mut candidate = dest.clone();
+    let mut n = 0;
+    while let Some(owner) = claimed.get(&claim_key(config, &candidate)) {
+        if owner == source {
+            return candidate;
+        }
+        n += 1;
+        candidate = with_numeric_suffix(&dest, n);
+    }
+    if n > 0 {
+        warn!("'{}' would be copied over another file, copying it as '{}'", source.display(), candidate.display());
+    }
+    claimed.insert(claim_key(config, &candidate), source.to_path_buf());
+    candidate
+}
+
+/// With `OnCollision::KeepNewest` and `NewestOnly`, files listed from one directory that would get the same
+/// destination are put in order of their modification time, newest first, so the newest one claims the name.
+/// The others keep their places in the listing among themselves and everything else.
+pub(crate) fn newest_first(config: &CopyConfig, children: &mut [(PathBuf, PathBuf)]) {
+    if config.on_collision == OnCollision::Suffix {
+        return;
+    }
+    let mut collisions: HashMap<PathBuf, Vec<usize>> = HashMap::new();
+    for (i, (path, dest_path)) in children.iter().enumerate() {
+        if !path.is_dir() {
+            collisions.entry(claim_key(config, dest_path)).or_default().push(i);
+        }
+    }
+    for places in collisions.into_values().filter(|places| places.len() > 1) {
+        let mut colliding: Vec<_> = places.iter().map(|&i| children[i].clone()).collect();
+        // one whose time can't be read goes last, the same times stay in the listed order
+        colliding.sort_by_key(|(path, _)| Reverse(fs::metadata(path).and_then(|m| m.modified()).ok()));
+        for (&i, child) in places.iter().zip(colliding) {
+            children[i] = child;
+        }
+    }
+}
+
+/// The newer file of the same directory that claimed `dest` before `source`, as `newest_first` ordered them
+pub(crate) fn newer_collision(config: &CopyConfig, claimed: &HashMap<PathBuf, PathBuf>, source: &Path, dest: &Path) -> Option<PathBuf> {
+    if config.on_collision == OnCollision::Suffix {
+        return None;
+    }
+    claimed
+        .get(&claim_key(config, dest))
+        .filter(|owner| *owner != source && owner.parent() == source.parent() && !owner.is_dir())
+        .cloned()
+}
+
+/// `claim_destination` for a directory, which can't go where a file already is at the destination,
+/// say one a flattened run left there. It gets a numeric suffix instead, the file keeps its name.
+pub(crate) fn claim_directory_destination(
+    config: &CopyConfig,
+    claimed: &mut HashMap<PathBuf, PathBuf>,
+    source: &Path,
+    dest: PathBuf,
+) -> PathBuf {
+    let mut candidate = claim_destination(config, claimed, source, dest.clone());
+    while is_file_in_the_way(&candidate) {
+        claimed.insert(claim_key(config, &candidate), candidate.clone());
+        candidate = claim_destination(config, claimed, source, dest.clone());
+    }
+    candidate
+}
+
+/// Something that isn't a directory is at `dest`, a symlink to one too
+pub(crate) fn is_file_in_the_way(dest: &Path) -> bool {
+    dest.symlink_metadata().is_ok_and(|m| !m.is_dir())
+}
+
+/// The same for every path the destination takes for the same one
+fn claim_key(config: &CopyConfig, path: &Path) -> PathBuf {
+    if NameRules::of(config).windows {
+        fold_case(path)
+    } else {
+        path.to_path_buf()
+    }
+}
+
+/// The same for every path a Windows filesystem takes for the same one
+fn fold_case(path: &Path) -> PathBuf {
+    PathBuf::from(path.to_string_lossy().to_uppercase())
+}
+
+/// Appended to the name of a copy until it's complete
+pub(crate) const PART_SUFFIX: &str = ".apfs-copier-part";
+
+/// Where a copy to `to` is written before it's renamed into place, still within ExFAT length
+pub(crate) fn part_path(to: &Path) -> PathBuf {
+    let name = to.file_name().map(|n| n.to_string_lossy().into_owned()).unwrap_or_default();
+    to.with_file_name(fit_name(&name, PART_SUFFIX))
+}
+
+/// `a_b.txt` becomes `a_b (1).txt`, a name already as long as ExFAT allows loses the end of its stem to the suffix
+fn with_numeric_suffix(path: &Path, n: u32) -> PathBuf {
+    let stem = path.file_stem().map(|s| s.to_string_lossy().into_owned()).unwrap_or_default();
+    let suffix = match path.extension() {
+        Some(extension) => format!(" ({}).{}", n, extension.to_string_lossy()),
+        None => format!(" ({})", n),
+    };
+    path.with_file_name(fit_name(&stem, &suffix))
+}
+
+/// ExFAT stores names as UTF-16, so a name that isn't valid UTF-8 can only be copied in lossy form
+fn lossy_name(name: &OsStr) -> String {
+    match name.to_str() {
+        Some(name) => name.to_string(),
+        None => {
+            let lossy = name.to_string_lossy().into_owned();
+            warn!("{:?} is not valid UTF-8, copying it as '{}'", name, lossy);
+            lossy
+        }
+    }
+}
+
+/// Done first, so every later rule sees the characters that end up at the destination
+fn normalize(normalize: Normalize, filename: String) -> String {
+    match normalize {
+        Normalize::None => filename,
+        Normalize::Nfc => filename.nfc().collect(),
+        Normalize::Nfd => filename.nfd().collect(),
+    }
+}
+
+/// Windows filesystems reject these with EINVAL, ExFAT rejects 0x7F too
+fn underscore_forbidden_chars(rules: &NameRules, filename: String, replacement: char) -> String {
+    filename.chars().map(|c| if rules.forbids(c) { replacement } else { c }).collect()
+}
+
+/// A `replacement_char` has to be one the destination stores, or every name it's put in would still fail
+pub(crate) fn check_replacement_char(config: &CopyConfig) -> Result<()> {
+    let replacement = config.replacement_char;
+    if matches!(replacement, '/' | '\0') || NameRules::of(config).forbids(replacement) {
+        bail!("'{}' can't be stored in a name on the destination, so it can't replace other characters", replacement.escape_default());
+    }
+    Ok(())
+}
+
+/// ExFAT and Windows drop or reject trailing dots and spaces, a name made only of them is empty after
+fn trim_trailing_dots_and_spaces(filename: String) -> String {
+    filename.trim_end_matches(['.', ' ']).to_string()
+}
+
+/// Device names Windows won't open as files, whatever the extension
+const RESERVED_NAMES: &[&str] = &[
+    "CON", "PRN", "AUX", "NUL", "COM1", "COM2", "COM3", "COM4", "COM5", "COM6", "COM7", "COM8", "COM9", "LPT1", "LPT2",
+    "LPT3", "LPT4", "LPT5", "LPT6", "LPT7", "LPT8", "LPT9",
+];
+
+/// ExFAT itself stores `CON` or `nul.txt` fine, but Windows can't open them later, so they get a `_` in front
+fn prefix_reserved_name(filename: String) -> String {
+    let stem = filename.split('.').next().unwrap_or_default().trim_end();
+    if !RESERVED_NAMES.iter().any(|reserved| stem.eq_ignore_ascii_case(reserved)) {
+        return filename;
+    }
+    let prefixed = format!("_{}", filename);
+    warn!("'{}' is reserved on Windows, copying it as '{}'", filename, prefixed);
+    prefixed
+}
+
+/// ExFAT names are at most this many UTF-16 code units, longer ones fail with EINVAL
+const MAX_NAME_UTF16: usize = 255;
+
+/// Shortens the stem of a name too long for ExFAT and keeps the extension, unless the extension alone is most of it
+fn truncate_to_exfat_length(filename: String) -> String {
+    if filename.encode_utf16().count() <= MAX_NAME_UTF16 {
+        return filename;
+    }
+    let truncated = match filename.rsplit_once('.') {
+        Some((stem, extension)) if !stem.is_empty() && extension.encode_utf16().count() < MAX_NAME_UTF16 / 2 => {
+            fit_name(stem, &format!(".{}", extension))
+        }
+        _ => trim_trailing_dots_and_spaces(fit_name(&filename, "")),
+    };
+    warn!("'{}' is too long for the destination, copying it as '{}'", filename, truncated);
+    truncated
+}
+
+/// `stem` cut short enough to fit in one name with `suffix` after it, never splitting a surrogate pair
+fn fit_name(stem: &str, suffix: &str) -> String {
+    let mut room = MAX_NAME_UTF16.saturating_sub(suffix.encode_utf16().count());
+    let mut name = String::new();
+    for c in stem.chars() {
+        if c.len_utf16() > room {
+            break;
+        }
+        room -= c.len_utf16();
+        name.push(c);
+    }
+    name.push_str(suffix);
+    name
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{CopyConfig, Normalize, TargetFs};
+    use std::{
+        collections::HashMap,
+        ffi::OsStr,
+        os::unix::ffi::OsStrExt,
+        path::{Path, PathBuf},
+    };
+
+    #[test]
+    fn it_replaces_forbidden_characters_in_every_component() {
+        assert_eq!(
+            super::replace_forbidden_characters(&CopyConfig::default(), Path::new("/foo:bar/baz?.txt")),
+            PathBuf::from("/foo_bar/baz_.txt")
+        );
+        assert_eq!(
+            super::replace_forbidden_characters(&CopyConfig::default(), Path::new("relative/a|b/c")),
+            PathBuf::from("relative/a_b/c")
+        );
+    }
+
+    #[test]
+    fn it_disambiguates_colliding_destinations() {
+        let mut claimed = HashMap::new();
+        let dest = PathBuf::from("/dest/a_b.txt");
+        let first = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/a:b.txt"), dest.clone());
+        let second = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/a?b.txt"), dest.clone());
+        let third = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/a|b.txt"), dest.clone());
+        let again = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/a?b.txt"), dest);
+        assert_eq!(first, PathBuf::from("/dest/a_b.txt"));
+        assert_eq!(second, PathBuf::from("/dest/a_b (1).txt"));
+        assert_eq!(third, PathBuf::from("/dest/a_b (2).txt"));
+        assert_eq!(again, second);
+
+        let dir = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/x:y"), PathBuf::from("/dest/x_y"));
+        let other = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/x?y"), PathBuf::from("/dest/x_y"));
+        assert_eq!(dir, PathBuf::from("/dest/x_y"));
+        assert_eq!(other, PathBuf::from("/dest/x_y (1)"));
+
+        let upper = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/File.txt"), PathBuf::from("/dest/File.txt"));
+        let lower = super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/file.txt"), PathBuf::from("/dest/file.txt"));
+        assert_eq!(upper, PathBuf::from("/dest/File.txt"));
+        assert_eq!(lower, PathBuf::from("/dest/file (1).txt"));
+    }
+
+    #[test]
+    fn it_underscores_control_chars() {
+        let config = CopyConfig::default();
+        let exfat = super::NameRules::of(&config);
+        assert_eq!(super::underscore_forbidden_chars(&exfat, "a\u{0}b\tc\u{1f}d\u{7f}".to_string(), '_'), "a_b_c_d_");
+        assert_eq!(super::underscore_forbidden_chars(&exfat, "caf\u{e9} \u{80}".to_string(), '_'), "caf\u{e9} \u{80}");
+        assert_eq!(
+            super::replace_forbidden_characters(&CopyConfig::default(), Path::new("/dest/bad\rdir/file\n.txt")),
+            PathBuf::from("/dest/bad_dir/file_.txt")
+        );
+    }
+
+    #[test]
+    fn it_replaces_with_a_configured_char() {
+        let config = CopyConfig {
+            replacement_char: '-',
+            ..Default::default()
+        };
+        assert_eq!(super::sanitize_name(&config, OsStr::new("12:30|a\tb.txt")), "12-30-a-b.txt");
+        let check = |replacement_char| super::check_replacement_char(&CopyConfig { replacement_char, ..Default::default() });
+        assert!(check('-').is_ok());
+        assert!(check('\u{2236}').is_ok());
+        assert!(check(':').is_err());
+        assert!(check('\n').is_err());
+    }
+
+    #[test]
+    fn it_prefixes_names_reserved_on_windows() {
+        let config = CopyConfig {
+            rename_reserved_names: true,
+            ..Default::default()
+        };
+        let sanitize = |name: &str| super::sanitize_name(&config, OsStr::new(name));
+        assert_eq!(sanitize("CON"), "_CON");
+        assert_eq!(sanitize("con.txt"), "_con.txt");
+        assert_eq!(sanitize("COM1"), "_COM1");
+        assert_eq!(sanitize("nul.tar.gz"), "_nul.tar.gz");
+        assert_eq!(sanitize("CON."), "_CON");
+        assert_eq!(sanitize("COM10"), "COM10");
+        assert_eq!(sanitize("icon.png"), "icon.png");
+        assert_eq!(sanitize("CONSOLE"), "CONSOLE");
+        assert_eq!(super::sanitize_name(&CopyConfig::default(), OsStr::new("CON")), "CON");
+    }
+
+    #[test]
+    fn it_trims_trailing_dots_and_spaces() {
+        assert_eq!(super::trim_trailing_dots_and_spaces("name. ".to_string()), "name");
+        assert_eq!(super::trim_trailing_dots_and_spaces("name...".to_string()), "name");
+        assert_eq!(super::trim_trailing_dots_and_spaces(" ".to_string()), "");
+        assert_eq!(super::trim_trailing_dots_and_spaces(".hidden".to_string()), ".hidden");
+        assert_eq!(super::trim_trailing_dots_and_spaces("a.b c".to_string()), "a.b c");
+        assert_eq!(
+            super::replace_forbidden_characters(&CopyConfig::default(), Path::new("../dest/dir. /file ")),
+            PathBuf::from("../dest/dir/file")
+        );
+    }
+
+    #[test]
+    fn it_truncates_names_to_exfat_length() {
+        let utf16_len = |name: &str| name.encode_utf16().count();
+        let short = "photo.jpg".to_string();
+        assert_eq!(super::truncate_to_exfat_length(short.clone()), short);
+        let exact = "a".repeat(251) + ".jpg";
+        assert_eq!(super::truncate_to_exfat_length(exact.clone()), exact);
+
+        let long = "\u{e9}".repeat(300) + ".jpeg";
+        let truncated = super::truncate_to_exfat_length(long);
+        assert_eq!(utf16_len(&truncated), 255);
+        assert!(truncated.ends_with("\u{e9}.jpeg"));
+
+        // every emoji is a surrogate pair, one that would only half fit is left out
+        let emoji = "x".repeat(2) + &"\u{1f600}".repeat(200) + ".txt";
+        let truncated = super::truncate_to_exfat_length(emoji);
+        assert_eq!(utf16_len(&truncated), 254);
+        assert!(truncated.ends_with("\u{1f600}.txt"));
+        assert_eq!(truncated.chars().filter(|c| *c == '\u{1f600}').count(), 124);
+
+        // no extension to keep, or one too long to be one
+        let no_extension = "b".repeat(254) + " " + &"c".repeat(10);
+        assert_eq!(super::truncate_to_exfat_length(no_extension), "b".repeat(254));
+        let dotted = "d.".to_string() + &"e".repeat(300);
+        assert_eq!(utf16_len(&super::truncate_to_exfat_length(dotted)), 255);
+    }
+
+    #[test]
+    fn it_keeps_disambiguated_long_names_within_exfat_length() {
+        let mut claimed = HashMap::new();
+        let first = super::sanitize_name(&CopyConfig::default(), OsStr::new(&format!("{}.mov", "\u{1f600}".repeat(300))));
+        let dest = PathBuf::from("/dest").join(&first);
+        let name_of = |path: PathBuf| path.file_name().unwrap().to_str().unwrap().to_string();
+        let a = name_of(super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/a"), dest.clone()));
+        let b = name_of(super::claim_destination(&CopyConfig::default(), &mut claimed, Path::new("/src/b"), dest));
+        assert_eq!(a, first);
+        assert!(b.ends_with("\u{1f600} (1).mov"));
+        assert!(b.encode_utf16().count() <= 255);
+        assert_ne!(a, b);
+    }
+
+    #[test]
+    fn it_normalizes_names_before_sanitizing_them() {
+        let decomposed = OsStr::new("Cafe\u{301}:Ba\u{308}r.jpg");
+        let nfc = CopyConfig {
+            normalize: Normalize::Nfc,
+            ..Default::default()
+        };
+        let nfd = CopyConfig {
+            normalize: Normalize::Nfd,
+            ..Default::default()
+        };
+        assert_eq!(super::sanitize_name(&nfc, decomposed), "Caf\u{e9}_B\u{e4}r.jpg");
+        assert_eq!(super::sanitize_name(&nfd, OsStr::new("Caf\u{e9}.jpg")), "Cafe\u{301}.jpg");
+        assert_eq!(super::sanitize_name(&CopyConfig::default(), decomposed), "Cafe\u{301}_Ba\u{308}r.jpg");
+    }
+
+    #[test]
+    fn it_names_what_sanitizes_to_nothing_after_the_original() {
+        let config = CopyConfig::default();
+        let colon = super::sanitize_name(&config, OsStr::new(":"));
+        assert!(colon.starts_with("_unnamed_") && colon.len() == "_unnamed_".len() + 8);
+        assert_eq!(super::sanitize_name(&config, OsStr::new(":")), colon);
+        let controls = super::sanitize_name(&config, OsStr::from_bytes(b"\x01\x02\x7f"));
+        assert!(controls.starts_with("_unnamed_"));
+        assert_ne!(controls, colon);
+        let dots = super::sanitize_name(&config, OsStr::new(". ."));
+        assert!(dots.starts_with("_unnamed_"));
+        assert_ne!(dots, super::sanitize_name(&config, OsStr::new("...")));
+        let long_spaces = format!("{}x", " ".repeat(300));
+        assert!(super::sanitize_name(&config, OsStr::new(&long_spaces)).starts_with("_unnamed_"));
+        // a name that's really an underscore is kept
+        assert_eq!(super::sanitize_name(&config, OsStr::new("_")), "_");
+        assert_eq!(super::sanitize_name(&config, OsStr::new(":a")), "_a");
+        assert_eq!(
+            super::replace_forbidden_characters(&config, Path::new("/dest/?/a.txt")),
+            Path::new("/dest").join(super::sanitize_name(&config, OsStr::new("?"))).join("a.txt")
+        );
+    }
+
+    #[test]
+    fn it_handles_non_utf8_names() {
+        let name = OsStr::from_bytes(b"caf\xe9:menu.txt");
+        assert_eq!(super::lossy_name(name), "caf\u{fffd}:menu.txt");
+        assert_eq!(
+            super::replace_forbidden_characters(&CopyConfig::default(), &Path::new("/dest").join(name)),
+            PathBuf::from("/dest/caf\u{fffd}_menu.txt")
+        );
+    }
+
+    #[test]
+    fn it_rewrites_names_for_the_target_fs() {
+        let config = |target_fs, forbidden_chars: Option<&str>| CopyConfig {
+            target_fs,
+            forbidden_chars: forbidden_chars.map(str::to_string),
+            ..Default::default()
+        };
+        let sanitize = |config: &CopyConfig, name: &str| super::sanitize_name(config, OsStr::new(name));
+        let (ntfs, fat32, none) = (config(TargetFs::Ntfs, None), config(TargetFs::Fat32, None), config(TargetFs::None, None));
+        assert_eq!(sanitize(&ntfs, "a:b\u{7f}. "), "a_b\u{7f}");
+        assert_eq!(sanitize(&fat32, "a?b\u{7f}"), "a_b\u{7f}");
+        assert_eq!(sanitize(&CopyConfig::default(), "a?b\u{7f}"), "a_b_");
+        assert_eq!(sanitize(&none, "a:b?\t. "), "a:b?\t. ");
+        assert_eq!(sanitize(&none, &"x".repeat(300)), "x".repeat(300));
+
+        // only these are replaced, the rest of the rules still go by the target
+        let custom = config(TargetFs::Exfat, Some("#%"));
+        assert_eq!(sanitize(&custom, "a#b:c%\t."), "a_b:c__");
+        assert_eq!(sanitize(&config(TargetFs::None, Some(":")), "a:b\t. "), "a_b\t. ");
+        assert!(super::check_replacement_char(&CopyConfig { replacement_char: '#', ..custom }).is_err());
+        assert!(super::check_replacement_char(&CopyConfig { replacement_char: '\t', ..none.clone() }).is_ok());
+
+        // a case-sensitive target keeps names that differ only in case apart
+        let mut claimed = HashMap::new();
+        super::claim_destination(&none, &mut claimed, Path::new("/src/File.txt"), PathBuf::from("/dest/File.txt"));
+        let lower = super::claim_destination(&none, &mut claimed, Path::new("/src/file.txt"), PathBuf::from("/dest/file.txt"));
+        assert_eq!(lower, PathBuf::from("/dest/file.txt"));
+    }
+
+    #[test]
+    fn it_underscore_non_windows_chars() {
+        let config = CopyConfig::default();
+        let rules = super::NameRules::of(&config);
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo\"bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo*bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+        /*assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo/bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );*/
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo:bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo<bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo>bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo\\bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+        assert_eq!(
+            super::underscore_forbidden_chars(&rules, "foo|bar".to_string(), '_'),
+            "foo_bar".to_string()
+        );
+    }
+}
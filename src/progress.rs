@@ -0,0 +1,275 @@
+use anyhow::Result;
+use indicatif::{style::ProgressTracker, HumanDuration, ProgressBar, ProgressState, ProgressStyle};
+use log::{info, warn};
+use std::{
+    collections::{HashSet, VecDeque},
+    fmt, fs,
+    os::unix::fs::MetadataExt,
+    path::{Path, PathBuf},
+    sync::{
+        atomic::{AtomicU64, Ordering},
+        Mutex,
+    },
+    time::{Duration, Instant},
+};
+
+use crate::{
+    copy::{is_symlink, skips_symlink},
+    failures::is_failure,
+    filters::Filters,
+    manifest::SinceManifest,
+    mount::{remount_since, MOUNT_GENERATION},
+    CopyConfig, SymlinkMode,
+};
+
+/// Files and bytes done, on a bar out of the totals found by the `progress` pre-scan,
+/// or only counted for the `progress_interval` status lines
+pub(crate) struct Progress {
+    bar: Option<ProgressBar>,
+    files_done: AtomicU64,
+    bytes_done: AtomicU64,
+    files_total: u64,
+    /// Directory of the file done last
+    current_dir: Mutex<PathBuf>,
+}
+
+impl Progress {
+    pub(crate) fn new(files_total: u64, bytes_total: u64) -> Progress {
+        let bar = ProgressBar::new(bytes_total);
+        bar.set_style(
+            ProgressStyle::with_template("[{elapsed_precise}] [{wide_bar}] {bytes}/{total_bytes} {msg} (ETA {rolling_eta})")
+                .unwrap()
+                .with_key("rolling_eta", RollingEta::default())
+                .progress_chars("=> "),
+        );
+        bar.set_message(format!("0/{} files", files_total));
+        Progress {
+            bar: Some(bar),
+            ..Progress::without_bar()
+        }
+    }
+
+    /// Counts without a pre-scan, there's nothing to show a bar out of
+    pub(crate) fn without_bar() -> Progress {
+        Progress {
+            bar: None,
+            files_done: AtomicU64::new(0),
+            bytes_done: AtomicU64::new(0),
+            files_total: 0,
+            current_dir: Mutex::new(PathBuf::new()),
+        }
+    }
+
+    /// Part of a file that's still being copied
+    pub(crate) fn advance(&self, bytes: u64) {
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+        }
+    }
+
+    pub(crate) fn file_done(&self, path: &Path, bytes: u64) {
+        let done = self.files_done.fetch_add(1, Ordering::Relaxed) + 1;
+        self.bytes_done.fetch_add(bytes, Ordering::Relaxed);
+        *self.current_dir.lock().unwrap() = path.parent().unwrap_or(path).to_path_buf();
+        if let Some(bar) = &self.bar {
+            bar.inc(bytes);
+            bar.set_message(format!("{}/{} files", done, self.files_total));
+        }
+    }
+
+    /// A `progress_interval` status line
+    pub(crate) fn log_status(&self, config: &CopyConfig, elapsed: Duration) {
+        // a remount in progress holds the lock
+        let remounts = match MOUNT_GENERATION.try_read() {
+            Ok(_) => format!("{} remounts", config.remounts.load(Ordering::Relaxed)),
+            Err(_) => "remounting".to_string(),
+        };
+        info!(
+            "{} files done, {} bytes, in '{}', {}, {}s elapsed",
+            self.files_done.load(Ordering::Relaxed),
+            self.bytes_done.load(Ordering::Relaxed),
+            self.current_dir.lock().unwrap().display(),
+            remounts,
+            elapsed.as_secs()
+        );
+    }
+
+    pub(crate) fn finish(&self) {
+        if let Some(bar) = &self.bar {
+            bar.finish();
+        }
+    }
+}
+
+/// How far back `RollingEta` looks for the copy speed, long enough to even out bursts
+/// and short enough to forget a remount pause soon after
+const RATE_WINDOW: Duration = Duration::from_secs(30);
+
+/// Bytes done every now and then over the last `RATE_WINDOW`, so the ETA goes by how fast the copy is going now.
+/// FUSE reads come in bursts and stall on every remount, an average since the start is way off after one.
+#[derive(Clone, Default)]
+struct RollingEta {
+    samples: VecDeque<(Instant, u64)>,
+}
+
+impl RollingEta {
+    fn record(&mut self, now: Instant, bytes_done: u64) {
+        if self.samples.back().is_some_and(|(at, _)| now.saturating_duration_since(*at) < Duration::from_millis(250)) {
+            return;
+        }
+        self.samples.push_back((now, bytes_done));
+        while self.samples.len() > 1 && self.samples.front().is_some_and(|(at, _)| now.saturating_duration_since(*at) > RATE_WINDOW) {
+            self.samples.pop_front();
+        }
+    }
+
+    /// None until there's a second to go by, or while nothing moves
+    fn eta(&self, bytes_left: u64) -> Option<Duration> {
+        let ((first_at, first), (last_at, last)) = (self.samples.front()?, self.samples.back()?);
+        let secs = last_at.saturating_duration_since(*first_at).as_secs_f64();
+        let rate = last.saturating_sub(*first) as f64 / secs;
+        (secs >= 1.0 && rate > 0.0).then(|| Duration::from_secs_f64(bytes_left as f64 / rate))
+    }
+}
+
+impl ProgressTracker for RollingEta {
+    fn clone_box(&self) -> Box<dyn ProgressTracker> {
+        Box::new(self.clone())
+    }
+
+    fn tick(&mut self, state: &ProgressState, now: Instant) {
+        self.record(now, state.pos());
+    }
+
+    fn reset(&mut self, _state: &ProgressState, _now: Instant) {
+        self.samples.clear();
+    }
+
+    fn write(&self, state: &ProgressState, w: &mut dyn fmt::Write) {
+        let bytes_left = state.len().unwrap_or(0).saturating_sub(state.pos());
+        let _ = match self.eta(bytes_left) {
+            Some(eta) => write!(w, "{:#}", HumanDuration(eta)),
+            None => write!(w, "?"),
+        };
+    }
+}
+
+/// The `progress` pass: counts the files and bytes the copy will go through, walking the source the same way.
+/// Directories that can't be listed are left out of the totals, the copy pass reports them properly.
+pub(crate) fn prescan(config: &CopyConfig, since: Option<&SinceManifest>) -> Result<(u64, u64)> {
+    let filters = Filters::from_config(config)?;
+    let (mut files, mut bytes) = (0, 0);
+    if config.source.is_file() {
+        return Ok((1, fs::metadata(&config.source)?.len()));
+    }
+    // only directories wait, like in the copy pass
+    let mut dirs = vec![config.source.clone()];
+    let mut walked = HashSet::new();
+    let mut hardlinks = HashSet::new();
+    while let Some(dir) = dirs.pop() {
+        // the copy pass notices too and stops before doing anything
+        if config.is_interrupted() {
+            break;
+        }
+        if is_failure(config, &dir) {
+            continue;
+        }
+        if config.symlinks == SymlinkMode::Follow && !fs::metadata(&dir).is_ok_and(|m| walked.insert((m.dev(), m.ino()))) {
+            continue;
+        }
+        let mount = MOUNT_GENERATION.read().unwrap();
+        let mut aborted = false;
+        let mut children = vec![];
+        if let Ok(entries) = fs::read_dir(&dir) {
+            for entry in entries {
+                match entry {
+                    Ok(entry) => children.push(entry.path()),
+                    Err(e) => {
+                        aborted = e.raw_os_error() == Some(103);
+                        break;
+                    }
+                }
+            }
+        }
+        let generation = *mount;
+        drop(mount);
+        if aborted {
+            warn!("Software caused connection abort while counting, remounting: {}", dir.display());
+            remount_since(config, generation)?;
+        }
+        for path in children {
+            if is_failure(config, &path) || filters.skips_hidden(&path) {
+                continue;
+            }
+            let relative = path.strip_prefix(config.source.as_path()).unwrap();
+            let is_dir = path.is_dir();
+            if !filters.accepts(relative, is_dir) {
+                continue;
+            }
+            if is_symlink(&path) && (skips_symlink(config, is_dir) || !path.exists()) {
+                continue;
+            }
+            if !is_dir && (filters.skips_size(&path) || filters.skips_age(&path)) {
+                continue;
+            }
+            if is_dir && config.max_depth.is_some_and(|max| relative.components().count() >= max) {
+                continue;
+            }
+            if is_dir {
+                dirs.push(path);
+            } else {
+                if since.is_some_and(|since| since.is_unchanged(&path)) {
+                    continue;
+                }
+                let metadata = fs::metadata(&path).ok();
+                // the copy pass leaves these out of the progress bar too
+                if let Some(m) = metadata.as_ref().filter(|m| config.dedup_hardlinks && m.nlink() > 1) {
+                    if !hardlinks.insert((m.dev(), m.ino())) {
+                        continue;
+                    }
+                }
+                files += 1;
+                bytes += metadata.map(|m| m.len()).unwrap_or(0);
+            }
+        }
+    }
+    Ok((files, bytes))
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig};
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn it_prescans_files_and_bytes() {
+        let (source, dest) = test_dirs("prescan");
+        std::fs::create_dir(source.join("sub")).unwrap();
+        std::fs::write(source.join("a.jpg"), "12345").unwrap();
+        std::fs::write(source.join("sub").join("b.jpg"), "123").unwrap();
+        std::fs::write(source.join("sub").join("c.txt"), "1").unwrap();
+        let config = CopyConfig {
+            include: vec!["*.jpg".to_string()],
+            ..CopyConfig::new("", "", source.clone(), dest)
+        };
+        assert_eq!(super::prescan(&config, None).unwrap(), (2, 8));
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+
+    #[test]
+    fn it_estimates_from_the_recent_rate_only() {
+        let start = Instant::now();
+        let at = |secs| start + Duration::from_secs(secs);
+        let mut eta = super::RollingEta::default();
+        eta.record(at(0), 0);
+        assert_eq!(eta.eta(1000), None);
+        eta.record(at(10), 1000);
+        assert_eq!(eta.eta(1000), Some(Duration::from_secs(10)));
+        // a remount pause, then 100 bytes a second again
+        for secs in 300..=310 {
+            eta.record(at(secs), 1000 + (secs - 300) * 100);
+        }
+        assert_eq!(eta.eta(1000), Some(Duration::from_secs(10)));
+    }
+}
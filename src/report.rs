@@ -0,0 +1,465 @@
+use anyhow::{Context, Result};
+use serde::Serialize;
+use std::{
+    collections::{BTreeMap, HashSet},
+    fs,
+    io::Write,
+    path::{Path, PathBuf},
+};
+
+use crate::{
+    events::{self, Event},
+    CopyConfig,
+};
+
+/// What a run has done, or would have done in dry-run mode
+#[derive(Debug, Default, Serialize)]
+pub struct CopyReport {
+    pub dry_run: bool,
+    pub elapsed_secs: f64,
+    pub directories_created: u64,
+    pub files_copied: u64,
+    pub bytes_copied: u64,
+    pub files_already_copied: u64,
+    pub files_skipped_io_error: u64,
+    pub files_skipped_no_space: u64,
+    /// Dotfiles and dot-directories left out by `CopyConfig::skip_hidden`
+    pub paths_skipped_hidden: u64,
+    /// Outside of `CopyConfig::min_size` and `CopyConfig::max_size`
+    pub files_skipped_by_size: u64,
+    /// Last modified before `CopyConfig::newer_than`
+    pub files_skipped_by_age: u64,
+    /// Not descended into because of `CopyConfig::max_depth`
+    pub directories_skipped_by_depth: u64,
+    /// Copied twice and still not matching the source, the copy was removed
+    pub files_failed_verification: u64,
+    pub paths_abandoned: u64,
+    /// Failed paths that copied fine on `CopyConfig::retry_failures_at_end`, the counts above still have their failures
+    pub failures_recovered: u64,
+    /// Completed by an earlier run according to the checkpoint, and not looked into again
+    pub paths_skipped_by_checkpoint: u64,
+    /// Listed in `CopyConfig::since_manifest` as they are now, and not copied again
+    pub files_unchanged_since_manifest: u64,
+    /// Older than a file of the same directory that took their destination, with `OnCollision::NewestOnly`
+    pub files_skipped_by_collision: u64,
+    /// The copy stopped early because the destination ran out of space
+    pub destination_full: bool,
+    /// The copy stopped early because the volume needed more than `CopyConfig::max_total_remounts` remounts
+    pub remounts_exhausted: bool,
+    /// The copy stopped early after `CopyConfig::max_files` files
+    pub max_files_reached: bool,
+    /// The copy stopped early on `CopyConfig::interrupt`, what it didn't get to is left for the next run
+    pub interrupted: bool,
+    pub failures: Vec<Failure>,
+    pub renames: Vec<Rename>,
+    /// Left out by `CopyConfig::symlinks`, with where they point so they can be recreated elsewhere
+    pub skipped_symlinks: Vec<Symlink>,
+    /// Hard links left out by `CopyConfig::dedup_hardlinks`, with where their content was copied to
+    pub deduplicated_hardlinks: Vec<Hardlink>,
+    /// Destination paths removed by `CopyConfig::mirror`, or that would be in a dry run
+    pub deleted: Vec<String>,
+    /// Source files given up on after I/O errors and `CopyConfig::io_retries` retries, they have no copy
+    pub unreadable: Vec<String>,
+    /// Time spent on the files under every source directory at `CopyConfig::throughput_depth`, by its path
+    pub directory_throughput: BTreeMap<String, Throughput>,
+}
+
+/// Files gone through under one directory, copied or not, and how long that took in all
+#[derive(Debug, Default, Clone, Serialize)]
+pub struct Throughput {
+    pub files: u64,
+    pub bytes_copied: u64,
+    /// With several `CopyConfig::jobs` the files overlap, the times of all of them are added up
+    pub secs: f64,
+    /// Remounts while one of its files was being copied, one that aborted stands out here.
+    /// With several jobs every file in flight gets it.
+    pub remounts: u64,
+}
+
+impl Throughput {
+    fn add(&mut self, other: &Throughput) {
+        self.files += other.files;
+        self.bytes_copied += other.bytes_copied;
+        self.secs += other.secs;
+        self.remounts += other.remounts;
+    }
+}
+
+#[derive(Debug, Clone, Serialize)]
+pub struct Failure {
+    pub path: String,
+    pub errno: Option<i32>,
+    pub reason: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Rename {
+    pub source: String,
+    pub destination: String,
+    /// Why it lost the name it would have had to another file, with `CopyConfig::on_collision`
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub collision: Option<String>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Symlink {
+    pub path: String,
+    pub target: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Hardlink {
+    pub path: String,
+    pub copied_as: String,
+}
+
+/// What a `verify_tree` run found at the destination
+#[derive(Debug, Default, Serialize)]
+pub struct VerifyReport {
+    pub elapsed_secs: f64,
+    pub files_matching: u64,
+    pub bytes_matching: u64,
+    /// The walk stopped early on `CopyConfig::interrupt`, what it didn't get to wasn't checked
+    pub interrupted: bool,
+    /// The walk stopped early because the volume needed more than `CopyConfig::max_total_remounts` remounts
+    pub remounts_exhausted: bool,
+    /// Nothing at the destination, a missing directory stands for everything under it
+    pub missing: Vec<Missing>,
+    pub differing: Vec<Difference>,
+    /// Source paths that couldn't be checked
+    pub failures: Vec<Failure>,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Missing {
+    pub path: String,
+    pub destination: String,
+}
+
+#[derive(Debug, Serialize)]
+pub struct Difference {
+    pub path: String,
+    pub destination: String,
+    pub reason: String,
+}
+
+/// Layout of `--report-json`; bump `schema_version` on any incompatible change
+#[derive(Serialize)]
+struct JsonReport<'a, T> {
+    schema_version: u32,
+    #[serde(flatten)]
+    report: &'a T,
+}
+
+impl<'a, T: Serialize> JsonReport<'a, T> {
+    fn new(report: &'a T) -> JsonReport<'a, T> {
+        JsonReport {
+            schema_version: 1,
+            report,
+        }
+    }
+
+    fn write(&self, path: &Path) -> Result<()> {
+        let file = fs::File::create(path).with_context(|| format!("can't create report '{}'", path.display()))?;
+        serde_json::to_writer_pretty(file, self).with_context(|| format!("can't write report '{}'", path.display()))?;
+        Ok(())
+    }
+}
+
+impl CopyReport {
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        JsonReport::new(self).write(path)
+    }
+
+    /// Appends a `source<TAB>destination` line for every rename, so the log of several runs finds
+    /// the original of every rewritten name. A file that lost its name on a collision gets a third column saying why.
+    /// A run that starts from a checkpoint only has what it walked.
+    pub fn append_rename_log(&self, path: &Path) -> Result<()> {
+        let line = |r: &Rename| match &r.collision {
+            Some(collision) => format!("{}\t{}\t{}\n", r.source, r.destination, collision),
+            None => format!("{}\t{}\n", r.source, r.destination),
+        };
+        let lines: String = self.renames.iter().map(line).collect();
+        fs::OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .and_then(|mut file| file.write_all(lines.as_bytes()))
+            .with_context(|| format!("can't write rename log '{}'", path.display()))
+    }
+
+    /// Also an `error` event with `config.events_jsonl`
+    pub(crate) fn record_failure(&mut self, config: &CopyConfig, path: &Path, errno: Option<i32>, reason: impl Into<String>) {
+        let reason = reason.into();
+        events::emit(config, Event::error(path, errno, &reason));
+        self.failures.push(Failure {
+            path: path.to_string_lossy().into_owned(),
+            errno,
+            reason,
+        });
+    }
+
+    pub(crate) fn record_rename(&mut self, source: &Path, destination: &Path) {
+        self.renames.push(Rename {
+            source: source.to_string_lossy().into_owned(),
+            destination: destination.to_string_lossy().into_owned(),
+            collision: None,
+        });
+    }
+
+    /// `record_rename` of a file that lost its name to `newer`, or a skipped one that would have gone to `destination`
+    pub(crate) fn record_collision(&mut self, source: &Path, destination: &Path, newer: &Path, skipped: bool) {
+        let collision = if skipped {
+            format!("skipped, older than '{}'", newer.display())
+        } else {
+            format!("older than '{}'", newer.display())
+        };
+        self.renames.push(Rename {
+            source: source.to_string_lossy().into_owned(),
+            destination: destination.to_string_lossy().into_owned(),
+            collision: Some(collision),
+        });
+    }
+
+    pub(crate) fn record_symlink(&mut self, path: &Path) {
+        self.skipped_symlinks.push(Symlink {
+            path: path.to_string_lossy().into_owned(),
+            target: fs::read_link(path).map(|t| t.to_string_lossy().into_owned()).unwrap_or_default(),
+        });
+    }
+
+    pub(crate) fn record_hardlink(&mut self, path: &Path, copied_as: &Path) {
+        self.deduplicated_hardlinks.push(Hardlink {
+            path: path.to_string_lossy().into_owned(),
+            copied_as: copied_as.to_string_lossy().into_owned(),
+        });
+    }
+
+    pub(crate) fn record_deletion(&mut self, path: &Path) {
+        self.deleted.push(path.to_string_lossy().into_owned());
+    }
+
+    pub(crate) fn record_unreadable(&mut self, path: &Path) {
+        self.files_skipped_io_error += 1;
+        self.unreadable.push(path.to_string_lossy().into_owned());
+    }
+
+    /// Left something undone on the interrupt, a full destination, the remounts used up or `max_files`
+    pub(crate) fn stopped_early(&self) -> bool {
+        self.interrupted || self.destination_full || self.remounts_exhausted || self.max_files_reached
+    }
+
+    pub(crate) fn merge(&mut self, other: CopyReport) {
+        self.directories_created += other.directories_created;
+        self.files_copied += other.files_copied;
+        self.bytes_copied += other.bytes_copied;
+        self.files_already_copied += other.files_already_copied;
+        self.files_skipped_io_error += other.files_skipped_io_error;
+        self.files_skipped_no_space += other.files_skipped_no_space;
+        self.paths_skipped_hidden += other.paths_skipped_hidden;
+        self.files_skipped_by_size += other.files_skipped_by_size;
+        self.files_skipped_by_age += other.files_skipped_by_age;
+        self.directories_skipped_by_depth += other.directories_skipped_by_depth;
+        self.files_failed_verification += other.files_failed_verification;
+        self.paths_abandoned += other.paths_abandoned;
+        self.failures_recovered += other.failures_recovered;
+        self.paths_skipped_by_checkpoint += other.paths_skipped_by_checkpoint;
+        self.files_unchanged_since_manifest += other.files_unchanged_since_manifest;
+        self.files_skipped_by_collision += other.files_skipped_by_collision;
+        self.destination_full |= other.destination_full;
+        self.remounts_exhausted |= other.remounts_exhausted;
+        self.max_files_reached |= other.max_files_reached;
+        self.interrupted |= other.interrupted;
+        self.failures.extend(other.failures);
+        self.renames.extend(other.renames);
+        self.skipped_symlinks.extend(other.skipped_symlinks);
+        self.deduplicated_hardlinks.extend(other.deduplicated_hardlinks);
+        self.deleted.extend(other.deleted);
+        self.unreadable.extend(other.unreadable);
+        self.merge_throughput(&other.directory_throughput);
+    }
+
+    fn merge_throughput(&mut self, other: &BTreeMap<String, Throughput>) {
+        for (dir, throughput) in other {
+            self.directory_throughput.entry(dir.clone()).or_default().add(throughput);
+        }
+    }
+
+    /// Adds a file's time to the directory it's under at `config.throughput_depth`
+    pub(crate) fn record_throughput(&mut self, config: &CopyConfig, from: &Path, throughput: Throughput) {
+        let relative = from.strip_prefix(&config.source).unwrap_or(Path::new(""));
+        // a file right in it counts for its directory, not one of its own
+        let dirs = relative.parent().unwrap_or(Path::new(""));
+        let mut dir = config.source.clone();
+        dir.extend(dirs.components().take(config.throughput_depth));
+        self.directory_throughput.entry(dir.to_string_lossy().into_owned()).or_default().add(&throughput);
+    }
+
+    /// The `n` directories the most time went to, the most first
+    pub fn slowest_directories(&self, n: usize) -> Vec<(&str, &Throughput)> {
+        let mut slowest: Vec<_> = self.directory_throughput.iter().map(|(dir, t)| (dir.as_str(), t)).collect();
+        slowest.sort_by(|a, b| b.1.secs.total_cmp(&a.1.secs));
+        slowest.truncate(n);
+        slowest
+    }
+
+    /// Takes in the `retry_failures_at_end` pass, which copied the failed paths in `retried` this time.
+    /// Those are recovered, one that failed again gets its new failure instead of the old one, and one the pass
+    /// didn't get to keeps its failure. Only what was copied counts, the rest of the walk went over what the first
+    /// pass already counted.
+    pub(crate) fn merge_retry(&mut self, other: CopyReport, retried: &HashSet<PathBuf>) {
+        let failed_again: HashSet<&str> = other.failures.iter().map(|f| f.path.as_str()).collect();
+        let is_replaced = |path: &str| retried.contains(Path::new(path)) || failed_again.contains(path);
+        let before = self.failures.len();
+        self.failures.retain(|f| !retried.contains(Path::new(&f.path)));
+        self.failures_recovered += (before - self.failures.len()) as u64;
+        self.failures.retain(|f| !is_replaced(&f.path));
+        self.unreadable.retain(|path| !retried.contains(Path::new(path)) && !other.unreadable.contains(path));
+        self.directories_created += other.directories_created;
+        self.files_copied += other.files_copied;
+        self.bytes_copied += other.bytes_copied;
+        self.destination_full |= other.destination_full;
+        self.remounts_exhausted |= other.remounts_exhausted;
+        self.interrupted |= other.interrupted;
+        self.failures.extend(other.failures);
+        self.unreadable.extend(other.unreadable);
+        self.merge_throughput(&other.directory_throughput);
+        let renamed: HashSet<String> = self.renames.iter().map(|r| r.source.clone()).collect();
+        self.renames.extend(other.renames.into_iter().filter(|r| !renamed.contains(&r.source)));
+    }
+}
+
+impl VerifyReport {
+    pub fn write_json(&self, path: &Path) -> Result<()> {
+        JsonReport::new(self).write(path)
+    }
+
+    /// Everything under the source was found at the destination as it should be
+    pub fn is_intact(&self) -> bool {
+        self.missing.is_empty() && self.differing.is_empty() && self.failures.is_empty()
+    }
+
+    pub(crate) fn merge(&mut self, other: VerifyReport) {
+        self.files_matching += other.files_matching;
+        self.bytes_matching += other.bytes_matching;
+        self.interrupted |= other.interrupted;
+        self.remounts_exhausted |= other.remounts_exhausted;
+        self.missing.extend(other.missing);
+        self.differing.extend(other.differing);
+        self.failures.extend(other.failures);
+    }
+
+    pub(crate) fn record_missing(&mut self, path: &Path, destination: &Path) {
+        self.missing.push(Missing {
+            path: path.to_string_lossy().into_owned(),
+            destination: destination.to_string_lossy().into_owned(),
+        });
+    }
+
+    pub(crate) fn record_difference(&mut self, path: &Path, destination: &Path, reason: impl Into<String>) {
+        self.differing.push(Difference {
+            path: path.to_string_lossy().into_owned(),
+            destination: destination.to_string_lossy().into_owned(),
+            reason: reason.into(),
+        });
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::CopyReport;
+    use std::path::Path;
+
+    #[test]
+    fn it_writes_report_with_stable_keys() {
+        let mut report = CopyReport {
+            files_copied: 2,
+            bytes_copied: 10,
+            elapsed_secs: 1.5,
+            ..Default::default()
+        };
+        report.record_failure(&crate::CopyConfig::default(), Path::new("/src/broken"), Some(5), "Input/output error");
+        report.record_rename(Path::new("/src/a:b"), Path::new("/dest/a_b"));
+        let json = serde_json::to_value(super::JsonReport::new(&report)).unwrap();
+        assert_eq!(json["schema_version"], 1);
+        assert_eq!(json["dry_run"], false);
+        assert_eq!(json["elapsed_secs"], 1.5);
+        assert_eq!(json["files_copied"], 2);
+        assert_eq!(json["bytes_copied"], 10);
+        assert_eq!(json["directories_created"], 0);
+        assert_eq!(json["failures"][0]["path"], "/src/broken");
+        assert_eq!(json["failures"][0]["errno"], 5);
+        assert_eq!(json["renames"][0]["source"], "/src/a:b");
+        assert_eq!(json["renames"][0]["destination"], "/dest/a_b");
+    }
+
+    #[test]
+    fn it_adds_up_throughput_per_directory() {
+        let config = crate::CopyConfig::new("", "", "/src".into(), "/dest".into());
+        let mut report = CopyReport::default();
+        let file = |secs, remounts| super::Throughput {
+            files: 1,
+            bytes_copied: 100,
+            secs,
+            remounts,
+        };
+        report.record_throughput(&config, Path::new("/src/Photos/2020/a.jpg"), file(1.0, 0));
+        report.record_throughput(&config, Path::new("/src/Photos/b.jpg"), file(2.0, 1));
+        report.record_throughput(&config, Path::new("/src/Music/c.mp3"), file(0.5, 0));
+        report.record_throughput(&config, Path::new("/src/d.txt"), file(0.25, 0));
+        let mut other = CopyReport::default();
+        other.record_throughput(&config, Path::new("/src/Music/e.mp3"), file(0.5, 2));
+        report.merge(other);
+        let slowest: Vec<_> = report.slowest_directories(2).into_iter().map(|(dir, t)| (dir, t.files, t.secs, t.remounts)).collect();
+        assert_eq!(slowest, [("/src/Photos", 2, 3.0, 1), ("/src/Music", 2, 1.0, 2)]);
+        assert_eq!(report.directory_throughput["/src"].bytes_copied, 100);
+
+        let deeper = crate::CopyConfig {
+            throughput_depth: 2,
+            ..config
+        };
+        let mut report = CopyReport::default();
+        report.record_throughput(&deeper, Path::new("/src/Photos/2020/x/a.jpg"), file(1.0, 0));
+        report.record_throughput(&deeper, Path::new("/src/Photos/b.jpg"), file(1.0, 0));
+        assert_eq!(report.directory_throughput.keys().collect::<Vec<_>>(), ["/src/Photos", "/src/Photos/2020"]);
+    }
+
+    #[test]
+    fn it_only_recovers_failures_the_retry_copied() {
+        let config = crate::CopyConfig::default();
+        let mut report = CopyReport::default();
+        for path in ["/src/copied", "/src/failed-again", "/src/not-reached"] {
+            report.record_failure(&config, Path::new(path), Some(5), "I/O error");
+        }
+        let mut retry = CopyReport {
+            interrupted: true,
+            ..Default::default()
+        };
+        retry.record_failure(&config, Path::new("/src/failed-again"), Some(5), "I/O error again");
+        report.merge_retry(retry, &[Path::new("/src/copied").to_path_buf()].into());
+        assert_eq!(report.failures_recovered, 1);
+        let failures: Vec<_> = report.failures.iter().map(|f| (f.path.as_str(), f.reason.as_str())).collect();
+        assert_eq!(failures, [("/src/not-reached", "I/O error"), ("/src/failed-again", "I/O error again")]);
+        assert!(report.interrupted);
+    }
+
+    #[test]
+    fn it_appends_renames_to_the_log() {
+        let log = std::env::temp_dir().join(format!("apfs-copier-rename-log-{}", std::process::id()));
+        let mut report = CopyReport::default();
+        report.record_rename(Path::new("/src/a:b"), Path::new("/dest/a_b"));
+        report.append_rename_log(&log).unwrap();
+        report.record_rename(Path::new("/src/CON"), Path::new("/dest/_CON"));
+        report.append_rename_log(&log).unwrap();
+        assert_eq!(
+            std::fs::read_to_string(&log).unwrap(),
+            "/src/a:b\t/dest/a_b\n/src/a:b\t/dest/a_b\n/src/CON\t/dest/_CON\n"
+        );
+
+        let mut report = CopyReport::default();
+        report.record_collision(Path::new("/src/a?b"), Path::new("/dest/a_b"), Path::new("/src/a:b"), true);
+        report.append_rename_log(&log).unwrap();
+        assert!(std::fs::read_to_string(&log).unwrap().ends_with("/src/a?b\t/dest/a_b\tskipped, older than '/src/a:b'\n"));
+        std::fs::remove_file(&log).unwrap();
+    }
+}
@@ -0,0 +1,62 @@
+use std::path::{Path, PathBuf};
+
+use crate::{names::sanitize_name, CopyConfig};
+
+/// The folders `route_by_extension` sorts files into and the extensions that go into each,
+/// `extension_routes` can send any of them elsewhere
+pub const DEFAULT_ROUTES: &[(&str, &[&str])] = &[
+    (
+        "images",
+        &[
+            "jpg", "jpeg", "png", "gif", "heic", "heif", "tif", "tiff", "bmp", "webp", "svg", "raw", "dng", "cr2",
+            "cr3", "nef", "arw", "orf", "rw2", "raf",
+        ],
+    ),
+    ("videos", &["mov", "mp4", "m4v", "avi", "mkv", "mts", "m2ts", "3gp", "wmv", "webm", "mpg", "mpeg"]),
+    ("audio", &["mp3", "m4a", "aac", "wav", "aif", "aiff", "flac", "ogg", "opus", "wma"]),
+    (
+        "documents",
+        &[
+            "pdf", "txt", "rtf", "md", "doc", "docx", "xls", "xlsx", "ppt", "pptx", "odt", "ods", "odp", "pages",
+            "numbers", "key", "csv", "epub",
+        ],
+    ),
+];
+
+/// Where a file with no route of its own goes, extensionless ones too
+pub const OTHER_ROUTE: &str = "other";
+
+/// `dest_path` moved into the folder of its extension, right under the directory it's in
+pub(crate) fn routed(config: &CopyConfig, dest_path: &Path) -> PathBuf {
+    let extension = dest_path.extension().map(|e| e.to_string_lossy().to_lowercase()).unwrap_or_default();
+    let folder = config.extension_routes.get(&extension).map(String::as_str).unwrap_or_else(|| {
+        DEFAULT_ROUTES
+            .iter()
+            .find(|(_, extensions)| extensions.contains(&extension.as_str()))
+            .map_or(OTHER_ROUTE, |(folder, _)| folder)
+    });
+    let folder = sanitize_name(config, folder.as_ref());
+    let dir = dest_path.parent().unwrap_or(Path::new(""));
+    dir.join(folder).join(dest_path.file_name().unwrap_or_default())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::CopyConfig;
+    use std::path::{Path, PathBuf};
+
+    #[test]
+    fn it_routes_files_by_extension() {
+        let config = CopyConfig {
+            extension_routes: [("cr2", "raw"), ("log", "logs:old")].map(|(e, f)| (e.to_string(), f.to_string())).into(),
+            ..Default::default()
+        };
+        let routed = |path: &str| super::routed(&config, Path::new(path));
+        assert_eq!(routed("/dest/IMG_1.JPG"), PathBuf::from("/dest/images/IMG_1.JPG"));
+        assert_eq!(routed("/dest/clip.mov"), PathBuf::from("/dest/videos/clip.mov"));
+        assert_eq!(routed("/dest/IMG_2.CR2"), PathBuf::from("/dest/raw/IMG_2.CR2"));
+        assert_eq!(routed("/dest/app.log"), PathBuf::from("/dest/logs_old/app.log"));
+        assert_eq!(routed("/dest/Makefile"), PathBuf::from("/dest/other/Makefile"));
+        assert_eq!(routed("/dest/archive.tar.gz"), PathBuf::from("/dest/other/archive.tar.gz"));
+    }
+}
@@ -0,0 +1,11 @@
+use std::path::PathBuf;
+
+/// A fresh `source` and `dest` pair under the temp directory
+pub(crate) fn test_dirs(name: &str) -> (PathBuf, PathBuf) {
+    let root = std::env::temp_dir().join(format!("apfs-copier-{}-{}", name, std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (source, dest) = (root.join("source"), root.join("dest"));
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&dest).unwrap();
+    (source, dest)
+}
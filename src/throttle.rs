@@ -0,0 +1,65 @@
+use std::{
+    sync::Mutex,
+    thread,
+    time::{Duration, Instant},
+};
+
+/// Bytes streamed by every worker against `CopyConfig::rate_limit`, so the limit holds for all of them together
+static BUCKET: Mutex<Option<Bucket>> = Mutex::new(None);
+
+/// A token bucket holding up to a second's worth of bytes. Taking more than it holds puts it in debt,
+/// which whoever took them sleeps off, and the next to take anything waits behind them.
+struct Bucket {
+    tokens: f64,
+    refilled: Instant,
+}
+
+impl Bucket {
+    fn new(rate: u64, now: Instant) -> Bucket {
+        Bucket {
+            tokens: rate as f64,
+            refilled: now,
+        }
+    }
+
+    /// How long to wait before `bytes` more fit under `rate` bytes a second
+    fn take(&mut self, rate: u64, bytes: u64, now: Instant) -> Duration {
+        let rate = rate as f64;
+        let elapsed = now.saturating_duration_since(self.refilled).as_secs_f64();
+        self.tokens = (self.tokens + elapsed * rate).min(rate) - bytes as f64;
+        self.refilled = now;
+        if self.tokens < 0.0 {
+            Duration::from_secs_f64(-self.tokens / rate)
+        } else {
+            Duration::ZERO
+        }
+    }
+}
+
+/// Paces a copy that has just written `bytes` to at most `rate` bytes a second on average
+pub(crate) fn throttle(rate: u64, bytes: u64) {
+    let wait = {
+        let mut bucket = BUCKET.lock().unwrap();
+        let now = Instant::now();
+        bucket.get_or_insert_with(|| Bucket::new(rate, now)).take(rate, bytes, now)
+    };
+    thread::sleep(wait);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::Bucket;
+    use std::time::{Duration, Instant};
+
+    #[test]
+    fn it_waits_off_bytes_over_the_rate() {
+        let start = Instant::now();
+        let mut bucket = Bucket::new(1000, start);
+        assert_eq!(bucket.take(1000, 1000, start), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 500, start), Duration::from_millis(500));
+        // the debt is paid off by then, and an idle bucket fills up to a second's worth only
+        let later = start + Duration::from_secs(10);
+        assert_eq!(bucket.take(1000, 1000, later), Duration::ZERO);
+        assert_eq!(bucket.take(1000, 2000, later), Duration::from_secs(2));
+    }
+}
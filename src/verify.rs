@@ -0,0 +1,130 @@
+use anyhow::Result;
+use log::{info, warn};
+use sha2::{Digest, Sha256};
+use std::{fs, io, path::Path};
+
+use crate::{
+    checkpoint::Checkpoint,
+    copy::{handle_software_caused_connection_abort, verify_copy, walk_tree},
+    mount::MOUNT_GENERATION,
+    CopyConfig, CopyReport, Verify, VerifyReport,
+};
+
+/// Walks the source the way a copy with the same `config` would, without writing anything, and checks every file
+/// against where that copy puts it. A checkpoint is ignored so nothing is left out.
+pub(crate) fn walk_and_verify(config: &CopyConfig) -> Result<VerifyReport> {
+    let config = &CopyConfig {
+        dry_run: false,
+        checkpoint: None,
+        mirror: false,
+        ..config.clone()
+    };
+    let checkpoint = Checkpoint::load(config)?;
+    let mut verified = VerifyReport::default();
+    let mut missing_dirs = vec![];
+    let walked = walk_tree(
+        config,
+        &checkpoint,
+        None,
+        &mut |_, dir, dest_dir| {
+            if dest_dir.is_dir() {
+                return Ok(true);
+            }
+            warn!("'{}' is missing, expected a copy of '{}'", dest_dir.display(), dir.display());
+            missing_dirs.push((dir.to_path_buf(), dest_dir.to_path_buf()));
+            Ok(false)
+        },
+        &mut |report, from, to| verify_file(config, report, &mut verified, &from, &to),
+    )?;
+    for (dir, dest_dir) in missing_dirs {
+        verified.record_missing(&dir, &dest_dir);
+    }
+    verified.missing.sort_by(|a, b| a.path.cmp(&b.path));
+    verified.interrupted = walked.interrupted;
+    verified.remounts_exhausted = walked.remounts_exhausted;
+    verified.failures = walked.failures;
+    Ok(verified)
+}
+
+fn verify_file(config: &CopyConfig, report: &mut CopyReport, verified: &mut VerifyReport, from: &Path, to: &Path) -> Result<()> {
+    if !to.is_file() {
+        warn!("'{}' is missing, expected a copy of '{}'", to.display(), from.display());
+        verified.record_missing(from, to);
+        return Ok(());
+    }
+    let mount = MOUNT_GENERATION.read().unwrap();
+    let source = fs::metadata(from).and_then(|m| {
+        let hash = (config.verify == Verify::Sha256).then(|| hash_file(from)).transpose()?;
+        Ok((m.len(), hash))
+    });
+    let generation = *mount;
+    drop(mount);
+    let (len, hash) = match source {
+        Ok(source) => source,
+        Err(e) if e.raw_os_error() == Some(103) => {
+            if handle_software_caused_connection_abort(config, report, from, generation)? {
+                return Ok(());
+            }
+            return verify_file(config, report, verified, from, to);
+        }
+        Err(e) => {
+            warn!("can't read '{}': {}", from.display(), e);
+            report.record_failure(config, from, e.raw_os_error(), e.to_string());
+            return Ok(());
+        }
+    };
+    match verify_copy(config, from, to, hash.as_deref()) {
+        Some(reason) => {
+            warn!("'{}' {}", to.display(), reason);
+            verified.record_difference(from, to, reason);
+        }
+        None => {
+            info!("'{}' matches '{}'", to.display(), from.display());
+            verified.files_matching += 1;
+            verified.bytes_matching += len;
+        }
+    }
+    Ok(())
+}
+
+fn hash_file(path: &Path) -> io::Result<Vec<u8>> {
+    let mut hasher = Sha256::new();
+    io::copy(&mut fs::File::open(path)?, &mut hasher)?;
+    Ok(hasher.finalize().to_vec())
+}
+
+#[cfg(test)]
+mod tests {
+    use crate::{test_support::test_dirs, CopyConfig, Verify};
+
+    #[test]
+    fn it_reports_missing_and_differing_copies() {
+        let (source, dest) = test_dirs("verify-tree");
+        for dir in ["a:b", "c"] {
+            std::fs::create_dir(source.join(dir)).unwrap();
+            std::fs::write(source.join(dir).join("1.txt"), "one").unwrap();
+            std::fs::write(source.join(dir).join("2.txt"), "two").unwrap();
+        }
+        let config = CopyConfig {
+            verify: Verify::Sha256,
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        crate::copy::walk_and_copy(&config, None, None, None).unwrap();
+        let intact = super::walk_and_verify(&config).unwrap();
+        assert!(intact.is_intact());
+        assert_eq!((intact.files_matching, intact.bytes_matching), (4, 12));
+
+        std::fs::write(dest.join("a_b").join("1.txt"), "eno").unwrap();
+        std::fs::remove_file(dest.join("a_b").join("2.txt")).unwrap();
+        std::fs::remove_dir_all(dest.join("c")).unwrap();
+        let broken = super::walk_and_verify(&config).unwrap();
+        assert_eq!(broken.files_matching, 0);
+        let missing: Vec<_> = broken.missing.iter().map(|m| m.destination.as_str()).collect();
+        assert_eq!(missing, [dest.join("a_b").join("2.txt").to_str().unwrap(), dest.join("c").to_str().unwrap()]);
+        assert_eq!(broken.differing[0].reason, "has a different SHA-256");
+        // a size check can't tell
+        let by_size = super::walk_and_verify(&CopyConfig { verify: Verify::Size, ..config }).unwrap();
+        assert_eq!(by_size.files_matching, 1);
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
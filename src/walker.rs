@@ -0,0 +1,209 @@
+use anyhow::{anyhow, Result};
+use std::{
+    cell::Cell,
+    fmt,
+    path::{Path, PathBuf},
+    sync::mpsc::{self, Receiver, SyncSender},
+    thread::{self, JoinHandle},
+};
+
+use crate::{
+    checkpoint::Checkpoint,
+    copy::walk_tree,
+    failures,
+    mount::{self, RemountsExhausted},
+    names, CopyConfig, CopyReport, Failure,
+};
+
+/// How far the walk gets ahead of whoever takes its entries
+const WALK_AHEAD: usize = 64;
+
+/// A directory or file a copy with the same config would go through
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WalkEntry {
+    pub path: PathBuf,
+    /// Where the copy puts it, under `CopyConfig::dest` and with the names it would get there
+    pub destination: PathBuf,
+    pub is_dir: bool,
+}
+
+#[derive(Debug)]
+pub enum WalkError {
+    /// A path that couldn't be walked, the walk goes on without it like a copy would
+    Path(Failure),
+    /// The walk can't go on, nothing comes after this one
+    Stopped(anyhow::Error),
+}
+
+impl fmt::Display for WalkError {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            WalkError::Path(failure) => write!(f, "can't walk '{}': {}", failure.path, failure.reason),
+            WalkError::Stopped(e) => write!(f, "{:#}", e),
+        }
+    }
+}
+
+impl std::error::Error for WalkError {}
+
+type Walked = Result<WalkEntry, WalkError>;
+
+/// The walk a copy makes of the source, as an iterator: the same filters, the same names at the destination and
+/// the same remounts when the FUSE connection aborts. It runs on a thread of its own a little ahead of the
+/// entries taken, and stops when the walker is dropped. `Ctrl-C` through `CopyConfig::interrupt` ends it early
+/// without an error, running out of `CopyConfig::max_total_remounts` with one. Nothing is written and a checkpoint
+/// is ignored.
+pub struct SourceWalker {
+    entries: Option<Receiver<Walked>>,
+    walk: Option<JoinHandle<()>>,
+}
+
+impl SourceWalker {
+    /// Mounts the source if needed and starts walking it
+    pub fn new(config: &CopyConfig) -> Result<SourceWalker> {
+        let config = config.for_run()?;
+        names::check_replacement_char(&config)?;
+        if let Some(state_file) = &config.state_file {
+            failures::load_failures(state_file)?;
+        }
+        mount::initial_mount_check(&config)?;
+        let roots = config.roots()?;
+        let (sender, entries) = mpsc::sync_channel(WALK_AHEAD);
+        let walk = thread::spawn(move || {
+            // several sources each have a directory of their own at the destination
+            let named = roots.len() > 1;
+            for root in &roots {
+                match walk_root(root, named, &sender) {
+                    Ok(true) => (),
+                    Ok(false) => break,
+                    Err(e) => {
+                        let _ = sender.send(Err(WalkError::Stopped(e)));
+                        break;
+                    }
+                }
+            }
+        });
+        Ok(SourceWalker {
+            entries: Some(entries),
+            walk: Some(walk),
+        })
+    }
+}
+
+impl Iterator for SourceWalker {
+    type Item = Walked;
+
+    fn next(&mut self) -> Option<Walked> {
+        self.entries.as_ref()?.recv().ok()
+    }
+}
+
+impl Drop for SourceWalker {
+    fn drop(&mut self) {
+        // the walk stops at the next entry nobody takes
+        drop(self.entries.take());
+        if let Some(walk) = self.walk.take() {
+            let _ = walk.join();
+        }
+    }
+}
+
+/// Walks one source, false when the walk stopped early
+fn walk_root(config: &CopyConfig, named: bool, sender: &SyncSender<Walked>) -> Result<bool> {
+    let config = &CopyConfig {
+        dry_run: false,
+        checkpoint: None,
+        mirror: false,
+        ..config.clone()
+    };
+    let checkpoint = Checkpoint::load(config)?;
+    // taken from the report before every entry, so they come in the order they happened
+    let failures_sent = Cell::new(0);
+    let send = |report: &CopyReport, entry: Option<WalkEntry>| -> Result<()> {
+        let failures = report.failures.get(failures_sent.get()..).unwrap_or_default();
+        failures_sent.set(report.failures.len());
+        let failures = failures.iter().map(|failure| Err(WalkError::Path(failure.clone())));
+        for walked in failures.chain(entry.map(Ok)) {
+            sender.send(walked).map_err(|_| anyhow!("nothing takes the walked entries anymore"))?;
+        }
+        Ok(())
+    };
+    let is_entry = |dir: &Path| dir.starts_with(&config.source) && (named || dir != config.source);
+    let report = walk_tree(
+        config,
+        &checkpoint,
+        None,
+        &mut |report, dir, dest_dir| {
+            let entry = is_entry(dir).then(|| WalkEntry {
+                path: dir.to_path_buf(),
+                destination: dest_dir.to_path_buf(),
+                is_dir: true,
+            });
+            send(report, entry).map(|_| true)
+        },
+        &mut |report, path, destination| {
+            send(
+                report,
+                Some(WalkEntry {
+                    path,
+                    destination,
+                    is_dir: false,
+                }),
+            )
+        },
+    )?;
+    send(&report, None)?;
+    if report.remounts_exhausted {
+        return Err(RemountsExhausted.into());
+    }
+    Ok(!report.interrupted)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::{SourceWalker, WalkEntry, WalkError};
+    use crate::{test_support::test_dirs, CopyConfig};
+
+    #[test]
+    fn it_walks_the_source_as_an_iterator() {
+        let (source, dest) = test_dirs("walker");
+        std::fs::create_dir(source.join("a:b")).unwrap();
+        std::fs::write(source.join("a:b").join("c.txt"), "").unwrap();
+        std::fs::write(source.join("d.tmp"), "").unwrap();
+        std::os::unix::fs::symlink(source.join("gone"), source.join("dangling")).unwrap();
+        let config = CopyConfig {
+            exclude: vec!["*.tmp".to_string()],
+            ..CopyConfig::new("", "", source.clone(), dest.clone())
+        };
+        let walked: Vec<_> = SourceWalker::new(&config).unwrap().collect();
+        let entries: Vec<_> = walked.iter().filter_map(|walked| walked.as_ref().ok()).cloned().collect();
+        assert_eq!(
+            entries,
+            [
+                WalkEntry {
+                    path: source.join("a:b"),
+                    destination: dest.join("a_b"),
+                    is_dir: true,
+                },
+                WalkEntry {
+                    path: source.join("a:b").join("c.txt"),
+                    destination: dest.join("a_b").join("c.txt"),
+                    is_dir: false,
+                },
+            ]
+        );
+        let failed: Vec<_> = walked
+            .iter()
+            .filter_map(|walked| match walked {
+                Err(WalkError::Path(failure)) => Some(failure.path.clone()),
+                _ => None,
+            })
+            .collect();
+        assert_eq!(failed, [source.join("dangling").to_str().unwrap()]);
+
+        // dropping it half-way stops the walk
+        let first = SourceWalker::new(&config).unwrap().next();
+        assert!(first.is_some());
+        std::fs::remove_dir_all(source.parent().unwrap()).unwrap();
+    }
+}
@@ -0,0 +1,204 @@
+use aspf_copier::{copy_tree, CopyConfig, Mounter};
+use std::{
+    os::unix::{fs::symlink, process::ExitStatusExt},
+    path::PathBuf,
+    process::{ExitStatus, Output},
+    sync::{
+        atomic::{AtomicU32, Ordering},
+        Arc,
+    },
+};
+
+/// Never mounts anything, only counts the umounts
+#[derive(Debug, Default)]
+struct CountingMounter {
+    umounts: AtomicU32,
+}
+
+impl Mounter for CountingMounter {
+    fn mount(&self, _device: &str, _mount_point: &str) -> std::io::Result<Output> {
+        panic!("the source is readable, there's nothing to mount")
+    }
+
+    fn umount(&self, _mount_point: &str) -> std::io::Result<Output> {
+        self.umounts.fetch_add(1, Ordering::Relaxed);
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: vec![],
+            stderr: vec![],
+        })
+    }
+}
+
+/// Puts `missing` in place on every mount, like a file that only reads fine on a fresh one
+#[derive(Debug)]
+struct RepairingMounter {
+    missing: PathBuf,
+}
+
+impl Mounter for RepairingMounter {
+    fn mount(&self, _device: &str, _mount_point: &str) -> std::io::Result<Output> {
+        std::fs::write(&self.missing, "repaired")?;
+        self.umount("")
+    }
+
+    fn umount(&self, _mount_point: &str) -> std::io::Result<Output> {
+        Ok(Output {
+            status: ExitStatus::from_raw(0),
+            stdout: vec![],
+            stderr: vec![],
+        })
+    }
+}
+
+#[test]
+fn it_copies_a_tree_through_the_library_api() {
+    let root = std::env::temp_dir().join(format!("apfs-copier-api-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (source, dest) = (root.join("source"), root.join("dest"));
+    std::fs::create_dir_all(source.join("a?b")).unwrap();
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::write(source.join("a?b").join("c.txt"), "content").unwrap();
+
+    let report = copy_tree(&CopyConfig::new("", "", source.clone(), dest.clone())).unwrap();
+    assert!(!report.dry_run);
+    assert_eq!(report.directories_created, 1);
+    assert_eq!(report.files_copied, 1);
+    assert_eq!(report.bytes_copied, 7);
+    assert_eq!(report.renames.len(), 1);
+    assert_eq!(std::fs::read_to_string(dest.join("a_b").join("c.txt")).unwrap(), "content");
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn it_copies_several_sources_and_single_files() {
+    let root = std::env::temp_dir().join(format!("apfs-copier-api-sources-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let dest = root.join("dest");
+    std::fs::create_dir_all(root.join("photos").join("2020")).unwrap();
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::write(root.join("photos").join("2020").join("a.jpg"), "jpeg").unwrap();
+    std::fs::write(root.join("notes?.txt"), "notes").unwrap();
+
+    let single = copy_tree(&CopyConfig::new("", "", root.join("notes?.txt"), dest.clone())).unwrap();
+    assert_eq!(single.files_copied, 1);
+    assert_eq!(std::fs::read_to_string(dest.join("notes_.txt")).unwrap(), "notes");
+
+    let both = CopyConfig {
+        extra_sources: vec![root.join("notes?.txt")],
+        ..CopyConfig::new("", "", root.join("photos"), dest.clone())
+    };
+    let report = copy_tree(&both).unwrap();
+    assert_eq!((report.files_copied, report.files_already_copied), (1, 1));
+    assert_eq!(std::fs::read_to_string(dest.join("photos").join("2020").join("a.jpg")).unwrap(), "jpeg");
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn it_unmounts_once_the_copy_is_done_when_asked() {
+    let root = std::env::temp_dir().join(format!("apfs-copier-api-umount-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (source, dest) = (root.join("source"), root.join("dest"));
+    std::fs::create_dir_all(&source).unwrap();
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::write(source.join("a.txt"), "a").unwrap();
+
+    let mounter = Arc::new(CountingMounter::default());
+    let config = CopyConfig {
+        mounter: mounter.clone(),
+        ..CopyConfig::new("/dev/null", root.to_string_lossy(), source.clone(), dest.clone())
+    };
+    copy_tree(&config).unwrap();
+    assert_eq!(mounter.umounts.load(Ordering::Relaxed), 0);
+    copy_tree(&CopyConfig {
+        umount_when_done: true,
+        ..config
+    })
+    .unwrap();
+    assert_eq!(mounter.umounts.load(Ordering::Relaxed), 1);
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn it_retries_failed_paths_on_a_fresh_mount_at_the_end() {
+    let root = std::env::temp_dir().join(format!("apfs-copier-api-retry-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (source, dest) = (root.join("source"), root.join("dest"));
+    std::fs::create_dir_all(source.join("a:b")).unwrap();
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::write(source.join("a:b").join("fine.txt"), "fine").unwrap();
+    symlink("target.txt", source.join("a:b").join("link.txt")).unwrap();
+    symlink("gone.txt", source.join("dangling.txt")).unwrap();
+
+    let config = CopyConfig {
+        mounter: Arc::new(RepairingMounter {
+            missing: source.join("a:b").join("target.txt"),
+        }),
+        retry_failures_at_end: true,
+        ..CopyConfig::new("/dev/null", root.to_string_lossy(), source.clone(), dest.clone())
+    };
+    let report = copy_tree(&config).unwrap();
+    assert_eq!(report.failures_recovered, 1);
+    assert_eq!(report.failures.len(), 1);
+    assert!(report.failures[0].path.ends_with("dangling.txt"));
+    // the target turned up in the directory too, the retry only goes for what failed
+    assert_eq!(report.files_copied, 2);
+    assert_eq!(std::fs::read_to_string(dest.join("a_b").join("link.txt")).unwrap(), "repaired");
+    assert!(!dest.join("a_b").join("target.txt").exists());
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn it_copies_from_relative_sources_with_trailing_slashes_and_dot_dots() {
+    let root = std::env::temp_dir().join(format!("apfs-copier-api-relative-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (source, dest) = (root.join("source"), root.join("dest"));
+    std::fs::create_dir_all(source.join("sub")).unwrap();
+    std::fs::create_dir_all(&dest).unwrap();
+    std::fs::write(source.join("sub").join("a.txt"), "a").unwrap();
+
+    // up to the root from the working directory, then down to the source the long way round
+    let cwd = std::env::current_dir().unwrap();
+    let mut relative: PathBuf = cwd.components().skip(1).map(|_| "..").collect();
+    relative.push(root.strip_prefix("/").unwrap());
+    let relative = PathBuf::from(format!("{}/./source/sub/../", relative.display()));
+    let report = copy_tree(&CopyConfig::new("", "", relative, PathBuf::from(format!("{}/", dest.display())))).unwrap();
+    assert_eq!(report.files_copied, 1);
+    assert_eq!(std::fs::read_to_string(dest.join("sub").join("a.txt")).unwrap(), "a");
+    std::fs::remove_dir_all(&root).unwrap();
+}
+
+#[test]
+fn it_copies_only_what_changed_since_an_earlier_manifest() {
+    let root = std::env::temp_dir().join(format!("apfs-copier-api-since-{}", std::process::id()));
+    let _ = std::fs::remove_dir_all(&root);
+    let (manifest, first, second) = (root.join("manifest.jsonl"), root.join("first"), root.join("second"));
+    std::fs::create_dir_all(root.join("photos").join("2020")).unwrap();
+    std::fs::create_dir_all(&first).unwrap();
+    std::fs::create_dir_all(&second).unwrap();
+    std::fs::write(root.join("photos").join("2020").join("a.jpg"), "jpeg").unwrap();
+    std::fs::write(root.join("notes.txt"), "notes").unwrap();
+    let config = |dest: &PathBuf| CopyConfig {
+        extra_sources: vec![root.join("notes.txt")],
+        ..CopyConfig::new("", "", root.join("photos"), dest.clone())
+    };
+    copy_tree(&CopyConfig {
+        manifest: Some(manifest.clone()),
+        ..config(&first)
+    })
+    .unwrap();
+
+    std::fs::write(root.join("photos").join("2020").join("a.jpg"), "a bigger jpeg").unwrap();
+    std::fs::write(root.join("photos").join("b.jpg"), "new").unwrap();
+    // another drive this time, the manifest is all there is to go by
+    let report = copy_tree(&CopyConfig {
+        since_manifest: Some(manifest),
+        ..config(&second)
+    })
+    .unwrap();
+    assert_eq!((report.files_copied, report.files_unchanged_since_manifest), (2, 1));
+    assert_eq!(std::fs::read_to_string(second.join("photos").join("2020").join("a.jpg")).unwrap(), "a bigger jpeg");
+    assert!(second.join("photos").join("b.jpg").exists());
+    assert!(!second.join("notes.txt").exists());
+    std::fs::remove_dir_all(&root).unwrap();
+}